@@ -0,0 +1,24 @@
+// cargo bench --features frame_dedup_crc --bench frame_dedup
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+// The size of an I420 frame, which is what the frame deduplication hashes.
+fn i420_frame(width: usize, height: usize) -> Vec<u8> {
+    let len = width * height * 3 / 2;
+    // Not all zeros, to be closer to a real frame.
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+fn bench_xxh3(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_dedup_xxh3");
+    for (name, width, height) in [("1080p", 1920, 1080), ("4k", 3840, 2160)] {
+        let frame = i420_frame(width, height);
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &frame, |b, frame| {
+            b.iter(|| xxhash_rust::xxh3::xxh3_64(black_box(frame)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_xxh3);
+criterion_main!(benches);
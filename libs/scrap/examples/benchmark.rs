@@ -95,6 +95,8 @@ fn test_vpx(
         quality,
         codec: codec_id,
         keyframe_interval: None,
+        deinterlace: false,
+        display_scale: 1.0,
     });
     let mut encoder = VpxEncoder::new(config, i444).unwrap();
     let mut vpxs = vec![];
@@ -173,6 +175,7 @@ fn test_av1(
         height: height as _,
         quality,
         keyframe_interval: None,
+        display_scale: 1.0,
     });
     let mut encoder = AomEncoder::new(config, i444).unwrap();
     let start = Instant::now();
@@ -264,6 +267,7 @@ mod hw {
                 height,
                 quality,
                 keyframe_interval: None,
+                display_scale: 1.0,
             }),
             false,
         )
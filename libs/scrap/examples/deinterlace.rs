@@ -0,0 +1,26 @@
+use scrap::{convert::packed_yuvfmt, deinterlace::Deinterlacer, Pixfmt};
+use std::time::Instant;
+
+// cargo run --package scrap --example deinterlace --release [--features deinterlace_yadif]
+
+const COUNT: u32 = 100;
+
+fn main() {
+    let filter = if cfg!(feature = "deinterlace_yadif") {
+        "yadif"
+    } else {
+        "bob"
+    };
+    for (w, h) in [(1280, 720), (1920, 1080), (2560, 1440), (3840, 2160)] {
+        let fmt = packed_yuvfmt(Pixfmt::I420, w, h).unwrap();
+        let src: Vec<u8> = (0..w * h * 3 / 2).map(|i| (i * 31 % 251) as u8).collect();
+        let mut deinterlacer = Deinterlacer::default();
+        // The first frame of yadif has no previous frame.
+        deinterlacer.process(&src, &fmt).unwrap();
+        let start = Instant::now();
+        for _ in 0..COUNT {
+            deinterlacer.process(&src, &fmt).unwrap();
+        }
+        println!("{filter} {w}x{h}: {:?}", start.elapsed() / COUNT);
+    }
+}
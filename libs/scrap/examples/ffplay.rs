@@ -5,8 +5,7 @@ use scrap::{Frame, TraitPixelBuffer};
 extern crate scrap;
 
 fn main() {
-    use scrap::{Capturer, Display, TraitCapturer};
-    use std::io::ErrorKind::WouldBlock;
+    use scrap::{CaptureError, Capturer, Display, TraitCapturer};
     use std::io::Write;
     use std::process::{Command, Stdio};
 
@@ -46,7 +45,7 @@ fn main() {
                     out.write_all(row).unwrap();
                 }
             }
-            Err(ref e) if e.kind() == WouldBlock => {
+            Err(CaptureError::Timeout) => {
                 // Wait for the frame.
             }
             Err(_) => {
@@ -105,6 +105,8 @@ fn main() -> io::Result<()> {
             quality,
             codec: vpx_codec,
             keyframe_interval: None,
+            deinterlace: false,
+            display_scale: 1.0,
         }),
         false,
     )
@@ -2,11 +2,10 @@ extern crate repng;
 extern crate scrap;
 
 use std::fs::File;
-use std::io::ErrorKind::WouldBlock;
 use std::thread;
 use std::time::Duration;
 
-use scrap::{Capturer, Display, Frame, TraitCapturer, TraitPixelBuffer};
+use scrap::{CaptureError, Capturer, Display, Frame, TraitCapturer, TraitPixelBuffer};
 
 fn main() {
     let n = Display::all().unwrap().len();
@@ -37,7 +36,7 @@ fn record(i: usize) {
         let frame = match capturer.frame(Duration::from_millis(0)) {
             Ok(frame) => frame,
             Err(error) => {
-                if error.kind() == WouldBlock {
+                if let CaptureError::Timeout = error {
                     // Keep spinning.
                     thread::sleep(one_frame);
                     continue;
@@ -90,7 +89,7 @@ fn record(i: usize) {
         let frame = match capturer.frame(Duration::from_millis(0)) {
             Ok(frame) => frame,
             Err(error) => {
-                if error.kind() == WouldBlock {
+                if let CaptureError::Timeout = error {
                     // Keep spinning.
                     thread::sleep(one_frame);
                     continue;
@@ -35,7 +35,7 @@ impl Capturer {
 }
 
 impl crate::TraitCapturer for Capturer {
-    fn frame<'a>(&'a mut self, _timeout: Duration) -> io::Result<Frame<'a>> {
+    fn frame<'a>(&'a mut self, _timeout: Duration) -> Result<Frame<'a>, crate::CaptureError> {
         if get_video_raw(&mut self.rgba, &mut self.saved_raw_data).is_some() {
             Ok(Frame::PixelBuffer(PixelBuffer::new(
                 &self.rgba,
@@ -43,7 +43,7 @@ impl crate::TraitCapturer for Capturer {
                 self.height(),
             )))
         } else {
-            return Err(io::ErrorKind::WouldBlock.into());
+            return Err(crate::CaptureError::Timeout);
         }
     }
 }
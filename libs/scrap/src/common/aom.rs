@@ -7,7 +7,10 @@
 include!(concat!(env!("OUT_DIR"), "/aom_ffi.rs"));
 
 use crate::codec::{base_bitrate, codec_thread_num};
-use crate::{codec::EncoderApi, EncodeFrame, STRIDE_ALIGN};
+use crate::{
+    codec::{EncoderApi, RoiRect},
+    EncodeFrame, STRIDE_ALIGN,
+};
 use crate::{common::GoogleImage, generate_call_macro, generate_call_ptr_macro, Error, Result};
 use crate::{EncodeInput, EncodeYuvFormat, Pixfmt};
 use hbb_common::{
@@ -47,6 +50,7 @@ pub struct AomEncoderConfig {
     pub height: u32,
     pub quality: f32,
     pub keyframe_interval: Option<usize>,
+    pub display_scale: f64,
 }
 
 pub struct AomEncoder {
@@ -55,6 +59,8 @@ pub struct AomEncoder {
     height: usize,
     i444: bool,
     yuvfmt: EncodeYuvFormat,
+    force_keyframe: bool,
+    display_scale: f64,
 }
 
 // https://webrtc.googlesource.com/src/+/refs/heads/main/modules/video_coding/codecs/av1/libaom_av1_encoder.cc
@@ -113,7 +119,12 @@ mod webrtc {
         let (q_min, q_max) = AomEncoder::calc_q_values(cfg.quality);
         c.rc_min_quantizer = q_min;
         c.rc_max_quantizer = q_max;
-        c.rc_target_bitrate = AomEncoder::bitrate(cfg.width as _, cfg.height as _, cfg.quality);
+        c.rc_target_bitrate = AomEncoder::bitrate(
+            cfg.width as _,
+            cfg.height as _,
+            cfg.display_scale,
+            cfg.quality,
+        );
         c.rc_undershoot_pct = 50;
         c.rc_overshoot_pct = 50;
         c.rc_buf_initial_sz = 600;
@@ -226,6 +237,8 @@ impl EncoderApi for AomEncoder {
                     height: config.height as _,
                     i444,
                     yuvfmt: Self::get_yuvfmt(config.width, config.height, i444),
+                    force_keyframe: false,
+                    display_scale: config.display_scale,
                 })
             }
             _ => Err(anyhow!("encoder type mismatch")),
@@ -261,7 +274,8 @@ impl EncoderApi for AomEncoder {
         let (q_min, q_max) = Self::calc_q_values(ratio);
         c.rc_min_quantizer = q_min;
         c.rc_max_quantizer = q_max;
-        c.rc_target_bitrate = Self::bitrate(self.width as _, self.height as _, ratio);
+        c.rc_target_bitrate =
+            Self::bitrate(self.width as _, self.height as _, self.display_scale, ratio);
         call_aom!(aom_codec_enc_config_set(&mut self.ctx, &c));
         Ok(())
     }
@@ -284,6 +298,23 @@ impl EncoderApi for AomEncoder {
     }
 
     fn disable(&self) {}
+
+    fn request_keyframe(&mut self) -> bool {
+        self.force_keyframe = true;
+        true
+    }
+
+    fn set_roi(&mut self, _rects: &[RoiRect]) -> ResultType<bool> {
+        Ok(false)
+    }
+
+    fn set_burst(&mut self, _burst: bool) -> ResultType<bool> {
+        Ok(false)
+    }
+
+    fn set_resolution(&mut self, _width: u32, _height: u32) -> ResultType<bool> {
+        Ok(false)
+    }
 }
 
 impl AomEncoder {
@@ -309,12 +340,17 @@ impl AomEncoder {
         ));
         let pts = webrtc::kTimeBaseDen / 1000 * ms;
         let duration = webrtc::kTimeBaseDen / 1000;
+        let flags = if std::mem::take(&mut self.force_keyframe) {
+            AOM_EFLAG_FORCE_KF
+        } else {
+            0
+        };
         call_aom!(aom_codec_encode(
             &mut self.ctx,
             &image,
             pts as _,
             duration as _, // Duration
-            0,             // Flags
+            flags as _,    // Flags
         ));
 
         Ok(EncodeFrames {
@@ -344,8 +380,8 @@ impl AomEncoder {
         }
     }
 
-    fn bitrate(width: u32, height: u32, ratio: f32) -> u32 {
-        let bitrate = base_bitrate(width, height) as f32;
+    fn bitrate(width: u32, height: u32, display_scale: f64, ratio: f32) -> u32 {
+        let bitrate = base_bitrate(width, height, display_scale) as f32;
         (bitrate * ratio) as u32
     }
 
@@ -579,3 +615,71 @@ impl Drop for Image {
 }
 
 unsafe impl Send for aom_codec_ctx_t {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::EncoderCfg;
+
+    const OBU_SEQUENCE_HEADER: u8 = 1;
+
+    // https://aomediacodec.github.io/av1-spec/#obu-syntax
+    fn obu_types(mut data: &[u8]) -> Vec<u8> {
+        let mut types = Vec::new();
+        while !data.is_empty() {
+            let header = data[0];
+            let obu_type = (header >> 3) & 0x0F;
+            let has_extension = (header >> 2) & 0x01 == 1;
+            let has_size = (header >> 1) & 0x01 == 1;
+            types.push(obu_type);
+            let mut pos = if has_extension { 2 } else { 1 };
+            if !has_size {
+                break;
+            }
+            // leb128
+            let mut size = 0usize;
+            for i in 0..8 {
+                let Some(b) = data.get(pos) else {
+                    return types;
+                };
+                pos += 1;
+                size |= ((b & 0x7F) as usize) << (i * 7);
+                if b & 0x80 == 0 {
+                    break;
+                }
+            }
+            if pos + size > data.len() {
+                break;
+            }
+            data = &data[pos + size..];
+        }
+        types
+    }
+
+    #[test]
+    fn test_encode_sequence_header() {
+        let (width, height) = (320, 240);
+        let mut encoder = AomEncoder::new(
+            EncoderCfg::AOM(AomEncoderConfig {
+                width,
+                height,
+                quality: 1.0,
+                keyframe_interval: None,
+                display_scale: 1.0,
+            }),
+            false,
+        )
+        .unwrap();
+        let yuvfmt = encoder.yuvfmt();
+        let yuv = vec![0x80u8; yuvfmt.h * yuvfmt.stride[0] * 2];
+        let frames: Vec<(Vec<u8>, bool)> = encoder
+            .encode(0, &yuv, STRIDE_ALIGN)
+            .unwrap()
+            .map(|f| (f.data.to_vec(), f.key))
+            .collect();
+        assert!(!frames.is_empty());
+        let (data, key) = &frames[0];
+        assert!(key);
+        assert!(obu_types(data).contains(&OBU_SEQUENCE_HEADER));
+    }
+}
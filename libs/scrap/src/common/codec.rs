@@ -50,6 +50,17 @@ pub enum EncoderCfg {
     VRAM(VRamEncoderConfig),
 }
 
+/// A region of the frame in pixels, encoded with the quantizer offset `delta_q` (-63 - 63).
+/// A negative offset means higher quality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoiRect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+    pub delta_q: i32,
+}
+
 pub trait EncoderApi {
     fn new(cfg: EncoderCfg, i444: bool) -> ResultType<Self>
     where
@@ -73,6 +84,21 @@ pub trait EncoderApi {
     fn is_hardware(&self) -> bool;
 
     fn disable(&self);
+
+    // Make the next encoded frame a keyframe, return false if the encoder can't.
+    fn request_keyframe(&mut self) -> bool;
+
+    // Set the regions of interest of the next frames, empty to clear.
+    // Return false if the encoder doesn't support it.
+    fn set_roi(&mut self, rects: &[RoiRect]) -> ResultType<bool>;
+
+    // Allow more bits for the next frames until it is set to false, e.g. on a scene change.
+    // Return false if the encoder doesn't support it.
+    fn set_burst(&mut self, burst: bool) -> ResultType<bool>;
+
+    // Change the frame size in place, the bitrate is scaled with the frame area.
+    // Return false if the encoder doesn't support it, then it must be recreated.
+    fn set_resolution(&mut self, width: u32, height: u32) -> ResultType<bool>;
 }
 
 pub struct Encoder {
@@ -161,6 +187,14 @@ impl Encoder {
         }
     }
 
+    /// Negotiate the codec with all the connected peers.
+    ///
+    /// Every peer sends its `SupportedDecoding` in the login options, a codec is only usable if
+    /// all the peers can decode it, the most frequent preference among them wins,
+    /// otherwise h265 > h264 > av1/vp9/vp8, VP8 is used if some peers can't decode VP9,
+    /// e.g. the peers which failed to decode VP9. All the peers share one encoder per display,
+    /// so there is no per-connection codec, the video service recreates the encoder
+    /// when `negotiated_codec()` changes.
     pub fn update(update: EncodingUpdate) {
         log::info!("update:{:?}", update);
         let mut decodings = PEER_DECODINGS.lock().unwrap();
@@ -183,6 +217,9 @@ impl Encoder {
             EncodingUpdate::Check => {}
         }
 
+        // VP9 is assumed decodable if there are no peers.
+        // The peers mark VP9 unsupported after decoding errors, then VP8 is used instead.
+        let vp9_useable = decodings.iter().all(|(_, s)| s.ability_vp9 > 0);
         let vp8_useable = decodings.len() > 0 && decodings.iter().all(|(_, s)| s.ability_vp8 > 0);
         let av1_useable = decodings.len() > 0
             && decodings.iter().all(|(_, s)| s.ability_av1 > 0)
@@ -231,7 +268,7 @@ impl Encoder {
         let preferences: Vec<_> = decodings
             .iter()
             .filter(|(_, s)| {
-                s.prefer == PreferCodec::VP9.into()
+                s.prefer == PreferCodec::VP9.into() && vp9_useable
                     || s.prefer == PreferCodec::VP8.into() && vp8_useable
                     || s.prefer == PreferCodec::AV1.into() && av1_useable
                     || s.prefer == PreferCodec::H264.into() && h264_useable
@@ -265,7 +302,10 @@ impl Encoder {
         let av1_test = Config::get_option(hbb_common::config::keys::OPTION_AV1_TEST) != "N";
         let mut auto_codec = if av1_useable && av1_test {
             CodecFormat::AV1
+        } else if !vp9_useable && vp8_useable {
+            CodecFormat::VP8
         } else {
+            // VP9 is the last resort, even if some peers can't decode it.
             CodecFormat::VP9
         };
         if h264_useable {
@@ -305,7 +345,7 @@ impl Encoder {
         };
         if decodings.len() > 0 {
             log::info!(
-                "usable: vp8={vp8_useable}, av1={av1_useable}, h264={h264_useable}, h265={h265_useable}",
+                "usable: vp8={vp8_useable}, vp9={vp9_useable}, av1={av1_useable}, h264={h264_useable}, h265={h265_useable}",
             );
             log::info!(
                 "connection count: {}, used preference: {:?}, encoder: {:?}",
@@ -397,7 +437,8 @@ impl Encoder {
         let decodings = PEER_DECODINGS.lock().unwrap().clone();
         let prefer_i444 = decodings
             .iter()
-            .all(|d| d.1.prefer_chroma == Chroma::I444.into());
+            .all(|d| d.1.prefer_chroma == Chroma::I444.into())
+            || (vpxcodec::is_lossless() && matches!(config, EncoderCfg::VPX(_)));
         let i444_useable = match config {
             EncoderCfg::VPX(vpx) => match vpx.codec {
                 VpxVideoCodecId::VP8 => false,
@@ -916,7 +957,14 @@ impl Quality {
     }
 }
 
-pub fn base_bitrate(width: u32, height: u32) -> u32 {
+/// The bitrate is computed with the size divided by `display_scale`, the logical size of the
+/// display, e.g. a 4K display at 200% gets the bitrate of 1080p.
+pub fn base_bitrate(width: u32, height: u32, display_scale: f64) -> u32 {
+    let scale = display_scale.max(1.0);
+    let (width, height) = (
+        (width as f64 / scale).round() as u32,
+        (height as f64 / scale).round() as u32,
+    );
     const RESOLUTION_PRESETS: &[(u32, u32, u32)] = &[
         (640, 480, 400),     // VGA, 307k pixels
         (800, 600, 500),     // SVGA, 480k pixels
@@ -1097,6 +1145,7 @@ pub fn test_av1() {
                     height,
                     quality,
                     keyframe_interval,
+                    display_scale: 1.0,
                 }),
                 i444,
             ) else {
@@ -197,3 +197,97 @@ pub fn convert_to_yuv(
     }
     Ok(())
 }
+
+/// The tightly packed layout of a yuv frame, for the frames not passed to the encoders directly.
+pub fn packed_yuvfmt(pixfmt: crate::Pixfmt, w: usize, h: usize) -> ResultType<EncodeYuvFormat> {
+    let (cw, ch) = ((w + 1) / 2, (h + 1) / 2);
+    let (stride, u, v) = match pixfmt {
+        crate::Pixfmt::I420 => (vec![w, cw, cw], w * h, w * h + cw * ch),
+        crate::Pixfmt::NV12 => (vec![w, cw * 2], w * h, w * h),
+        crate::Pixfmt::I444 => (vec![w, w, w], w * h, w * h * 2),
+        _ => bail!("not a yuv pixfmt: {pixfmt:?}"),
+    };
+    Ok(EncodeYuvFormat {
+        pixfmt,
+        w,
+        h,
+        stride,
+        u,
+        v,
+    })
+}
+
+// (offset, stride, width, height, channels) of the planes.
+pub(crate) fn yuv_planes(fmt: &EncodeYuvFormat) -> Vec<(usize, usize, usize, usize, usize)> {
+    let (w, h) = (fmt.w, fmt.h);
+    let (cw, ch) = ((w + 1) / 2, (h + 1) / 2);
+    match fmt.pixfmt {
+        crate::Pixfmt::I420 => vec![
+            (0, fmt.stride[0], w, h, 1),
+            (fmt.u, fmt.stride[1], cw, ch, 1),
+            (fmt.v, fmt.stride[2], cw, ch, 1),
+        ],
+        crate::Pixfmt::NV12 => vec![
+            (0, fmt.stride[0], w, h, 1),
+            (fmt.u, fmt.stride[1], cw, ch, 2),
+        ],
+        crate::Pixfmt::I444 => vec![
+            (0, fmt.stride[0], w, h, 1),
+            (fmt.u, fmt.stride[1], w, h, 1),
+            (fmt.v, fmt.stride[2], w, h, 1),
+        ],
+        _ => vec![],
+    }
+}
+
+/// Down-sample a yuv frame with a box filter, the pixel formats must be the same.
+pub fn scale_yuv(
+    src: &[u8],
+    src_fmt: &EncodeYuvFormat,
+    dst: &mut Vec<u8>,
+    dst_fmt: &EncodeYuvFormat,
+) -> ResultType<()> {
+    if src_fmt.pixfmt != dst_fmt.pixfmt {
+        bail!("can't scale {:?} to {:?}", src_fmt.pixfmt, dst_fmt.pixfmt);
+    }
+    let src_planes = yuv_planes(src_fmt);
+    let dst_planes = yuv_planes(dst_fmt);
+    if src_planes.is_empty() {
+        bail!("can't scale {:?}", src_fmt.pixfmt);
+    }
+    let len = |planes: &[(usize, usize, usize, usize, usize)]| {
+        planes
+            .iter()
+            .map(|(offset, stride, _, h, _)| offset + stride * h)
+            .max()
+            .unwrap_or(0)
+    };
+    if src.len() < len(&src_planes) {
+        bail!("wrong src len, {} < {}", src.len(), len(&src_planes));
+    }
+    dst.resize(dst.len().max(len(&dst_planes)), 0);
+    for (s, d) in src_planes.iter().zip(dst_planes.iter()) {
+        let (s_off, s_stride, sw, sh, channels) = *s;
+        let (d_off, d_stride, dw, dh, _) = *d;
+        for dy in 0..dh {
+            let y0 = dy * sh / dh;
+            let y1 = ((dy + 1) * sh / dh).max(y0 + 1);
+            for dx in 0..dw {
+                let x0 = dx * sw / dw;
+                let x1 = ((dx + 1) * sw / dw).max(x0 + 1);
+                let count = ((y1 - y0) * (x1 - x0)) as u32;
+                for c in 0..channels {
+                    let mut sum = 0u32;
+                    for y in y0..y1 {
+                        let row = s_off + y * s_stride;
+                        for x in x0..x1 {
+                            sum += src[row + x * channels + c] as u32;
+                        }
+                    }
+                    dst[d_off + dy * d_stride + dx * channels + c] = (sum / count) as u8;
+                }
+            }
+        }
+    }
+    Ok(())
+}
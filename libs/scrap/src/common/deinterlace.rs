@@ -0,0 +1,165 @@
+// Deinterlace the yuv frames before encoding, for the interlaced content captured from the
+// legacy broadcast monitors or HDMI capture cards.
+//
+// The default filter is bob: the top field is kept and every line of the bottom field is
+// replaced by the top field line above it. It only costs a memory copy of the frame.
+//
+// With the `deinterlace_yadif` feature, the bottom field lines are interpolated yadif-style:
+// an edge-directed spatial prediction from the top field lines, clamped by the temporal
+// prediction from the previous frame. It keeps more vertical details on the static content,
+// but costs about 20 times of bob, it's too slow for 4K at 30 fps on one thread.
+//
+// The cost per frame, I420, one thread, x86_64 (`examples/deinterlace.rs`):
+//
+// | resolution |   bob  |  yadif |
+// |------------|--------|--------|
+// |  1280x720  | 0.2 ms | 3.5 ms |
+// | 1920x1080  | 0.4 ms | 8 ms   |
+// | 2560x1440  | 0.8 ms | 16 ms  |
+// | 3840x2160  | 1.8 ms | 38 ms  |
+
+use crate::{convert::yuv_planes, EncodeYuvFormat};
+use hbb_common::{bail, ResultType};
+
+#[derive(Default)]
+pub struct Deinterlacer {
+    buf: Vec<u8>,
+    #[cfg(feature = "deinterlace_yadif")]
+    prev: Vec<u8>,
+}
+
+impl Deinterlacer {
+    /// Deinterlace the yuv frame, the returned frame has the same layout as `src`.
+    pub fn process(&mut self, src: &[u8], fmt: &EncodeYuvFormat) -> ResultType<&[u8]> {
+        let planes = yuv_planes(fmt);
+        if planes.is_empty() {
+            bail!("can't deinterlace {:?}", fmt.pixfmt);
+        }
+        let len = planes
+            .iter()
+            .map(|(offset, stride, _, h, _)| offset + stride * h)
+            .max()
+            .unwrap_or(0);
+        if src.len() < len {
+            bail!("wrong src len, {} < {}", src.len(), len);
+        }
+        self.buf.clear();
+        self.buf.extend_from_slice(src);
+        #[cfg(feature = "deinterlace_yadif")]
+        {
+            // The previous frame is not usable after a resolution change.
+            let temporal = self.prev.len() == src.len();
+            for plane in planes {
+                if temporal {
+                    yadif_plane(&mut self.buf, &self.prev, src, plane);
+                } else {
+                    bob_plane(&mut self.buf, plane);
+                }
+            }
+            self.prev.clear();
+            self.prev.extend_from_slice(src);
+        }
+        #[cfg(not(feature = "deinterlace_yadif"))]
+        for plane in planes {
+            bob_plane(&mut self.buf, plane);
+        }
+        Ok(&self.buf)
+    }
+}
+
+// Duplicate the top field lines.
+fn bob_plane(buf: &mut [u8], plane: (usize, usize, usize, usize, usize)) {
+    let (offset, stride, w, h, channels) = plane;
+    let row = w * channels;
+    for y in (1..h).step_by(2) {
+        let above = offset + (y - 1) * stride;
+        buf.copy_within(above..above + row, offset + y * stride);
+    }
+}
+
+// Interpolate the bottom field lines of `cur` into `out`.
+#[cfg(feature = "deinterlace_yadif")]
+fn yadif_plane(
+    out: &mut [u8],
+    prev: &[u8],
+    cur: &[u8],
+    plane: (usize, usize, usize, usize, usize),
+) {
+    let (offset, stride, w, h, ch) = plane;
+    let row = w * ch;
+    // The top field lines padded with the edge pixels, for the directional predictions.
+    let pad = 2 * ch;
+    let padded = |start: usize| -> Vec<i32> {
+        let line = &cur[start..start + row];
+        let (first, last) = (&line[..ch], &line[row - ch..]);
+        first
+            .iter()
+            .chain(first)
+            .chain(line)
+            .chain(last)
+            .chain(last)
+            .map(|v| *v as i32)
+            .collect()
+    };
+    for y in (1..h).step_by(2) {
+        let line = offset + y * stride;
+        let above = offset + (y - 1) * stride;
+        // The last line has no top field line below it.
+        let below = if y + 1 < h { above + 2 * stride } else { above };
+        let (a, b) = (padded(above), padded(below));
+        for x in 0..row {
+            let i = x + pad;
+            let (c, e) = (a[i], b[i]);
+            // The temporal prediction, from the bottom fields of the previous frame and this one.
+            let p = prev[line + x] as i32;
+            let n = cur[line + x] as i32;
+            let d = (p + n) >> 1;
+            let diff = ((p - n).abs() >> 1).max(
+                ((prev[above + x] as i32 - c).abs() + (prev[below + x] as i32 - e).abs()) >> 1,
+            );
+
+            // The spatial prediction, along the direction with the least difference.
+            let score = |l: usize, r: usize| {
+                (a[l - ch] - b[r - ch]).abs() + (a[l] - b[r]).abs() + (a[l + ch] - b[r + ch]).abs()
+            };
+            let mut spatial = (c + e) >> 1;
+            let mut best = score(i, i);
+            for (l, r) in [(i - ch, i + ch), (i + ch, i - ch)] {
+                let s = score(l, r);
+                if s < best {
+                    best = s;
+                    spatial = (a[l] + b[r]) >> 1;
+                }
+            }
+            out[line + x] = spatial.clamp(d - diff, d + diff) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::packed_yuvfmt;
+
+    #[test]
+    fn test_deinterlace() {
+        let (w, h) = (8, 6);
+        let fmt = packed_yuvfmt(crate::Pixfmt::I420, w, h).unwrap();
+        // Combed: the top field is 100, the bottom field is 200.
+        let mut src = vec![128; w * h * 3 / 2];
+        for y in 0..h {
+            src[y * w..(y + 1) * w].fill(if y % 2 == 0 { 100 } else { 200 });
+        }
+        let mut deinterlacer = Deinterlacer::default();
+        let dst = deinterlacer.process(&src, &fmt).unwrap().to_vec();
+        assert!(dst[..w * h].iter().all(|v| *v == 100));
+        assert!(dst[w * h..].iter().all(|v| *v == 128));
+        assert!(deinterlacer.process(&src[..w * h], &fmt).is_err());
+        #[cfg(feature = "deinterlace_yadif")]
+        {
+            // The bottom field lines of a static frame are kept.
+            let dst = deinterlacer.process(&src, &fmt).unwrap();
+            assert_eq!(&dst[..w * h], &src[..w * h]);
+        }
+    }
+}
@@ -1,11 +1,8 @@
 #[cfg(feature = "vram")]
 use crate::AdapterDevice;
-use crate::{common::TraitCapturer, dxgi, Frame, Pixfmt};
+use crate::{common::TraitCapturer, dxgi, CaptureError, Frame, Pixfmt};
 use std::{
-    io::{
-        self,
-        ErrorKind::{NotFound, TimedOut, WouldBlock},
-    },
+    io::{self, ErrorKind::NotFound},
     time::Duration,
 };
 
@@ -41,12 +38,8 @@ impl Capturer {
 }
 
 impl TraitCapturer for Capturer {
-    fn frame<'a>(&'a mut self, timeout: Duration) -> io::Result<Frame<'a>> {
-        match self.inner.frame(timeout.as_millis() as _) {
-            Ok(frame) => Ok(frame),
-            Err(ref error) if error.kind() == TimedOut => Err(WouldBlock.into()),
-            Err(error) => Err(error),
-        }
+    fn frame<'a>(&'a mut self, timeout: Duration) -> Result<Frame<'a>, CaptureError> {
+        Ok(self.inner.frame(timeout.as_millis() as _)?)
     }
 
     fn is_gdi(&self) -> bool {
@@ -57,6 +50,15 @@ impl TraitCapturer for Capturer {
         self.inner.set_gdi()
     }
 
+    fn set_draw_cursor(&mut self, draw: bool) {
+        self.inner.set_draw_cursor(draw);
+    }
+
+    #[cfg(feature = "hdr")]
+    fn is_hdr(&self) -> bool {
+        self.inner.is_hdr()
+    }
+
     #[cfg(feature = "vram")]
     fn device(&self) -> AdapterDevice {
         self.inner.device()
@@ -192,11 +194,60 @@ impl Display {
         self.0.origin()
     }
 
+    #[cfg(feature = "hdr")]
+    pub fn hdr_metadata(&self) -> Option<crate::hdr::HdrMetadata> {
+        self.0.hdr_metadata()
+    }
+
     pub fn is_primary(&self) -> bool {
         // https://docs.microsoft.com/en-us/windows/win32/api/wingdi/ns-wingdi-devmodea
         self.origin() == (0, 0)
     }
 
+    // The effective dpi scale, 2.0 for 200%.
+    // The width and height are in physical pixels, whatever the scale is.
+    pub fn scale(&self) -> f64 {
+        use winapi::{
+            shared::winerror::S_OK,
+            um::shellscalingapi::{GetDpiForMonitor, MDT_EFFECTIVE_DPI},
+        };
+        let (mut dpi_x, mut dpi_y) = (0, 0);
+        let hr = unsafe {
+            GetDpiForMonitor(self.0.hmonitor(), MDT_EFFECTIVE_DPI, &mut dpi_x, &mut dpi_y)
+        };
+        if hr == S_OK && dpi_x > 0 {
+            dpi_x as f64 / 96.0
+        } else {
+            1.0
+        }
+    }
+
+    // The refresh rate in Hz, `None` if it is the hardware default or unknown.
+    pub fn refresh_rate(&self) -> Option<u32> {
+        use winapi::um::{
+            wingdi::DEVMODEW,
+            winuser::{EnumDisplaySettingsW, ENUM_CURRENT_SETTINGS},
+        };
+        let name = self
+            .0
+            .name()
+            .iter()
+            .copied()
+            .chain(Some(0))
+            .collect::<Vec<u16>>();
+        let mut dm: DEVMODEW = unsafe { std::mem::zeroed() };
+        dm.dmSize = std::mem::size_of::<DEVMODEW>() as _;
+        if unsafe { EnumDisplaySettingsW(name.as_ptr(), ENUM_CURRENT_SETTINGS, &mut dm) } == 0 {
+            return None;
+        }
+        // 0 and 1 mean the hardware default.
+        if dm.dmDisplayFrequency > 1 {
+            Some(dm.dmDisplayFrequency)
+        } else {
+            None
+        }
+    }
+
     #[cfg(feature = "vram")]
     pub fn adapter_luid(&self) -> Option<i64> {
         self.0.adapter_luid()
@@ -230,7 +281,7 @@ impl CapturerMag {
 }
 
 impl TraitCapturer for CapturerMag {
-    fn frame<'a>(&'a mut self, _timeout_ms: Duration) -> io::Result<Frame<'a>> {
+    fn frame<'a>(&'a mut self, _timeout_ms: Duration) -> Result<Frame<'a>, CaptureError> {
         self.inner.frame(&mut self.data)?;
         Ok(Frame::PixelBuffer(PixelBuffer::new(
             &self.data,
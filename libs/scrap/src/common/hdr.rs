@@ -0,0 +1,217 @@
+// Tone-map the HDR10 frames to SDR, for the peers which can only show SDR.
+//
+// DXGI duplicates the HDR displays in scRGB, `DXGI_FORMAT_R16G16B16A16_FLOAT`: linear, BT.709
+// primaries, 1.0 is 80 nits, and the wide gamut colors are out of [0, 1]. Duplicating them in
+// BGRA lets Windows clip the colors, which looks washed out.
+//
+// The luminance is tone-mapped with the BT.2390 EETF in the PQ domain, from the max luminance of
+// the display to the SDR white. The colors are scaled by the luminance ratio to keep the hues,
+// then clipped to BT.709 and encoded with the sRGB transfer to BGRA.
+//
+// The per-pixel work is table lookups: the halves are decoded by a 64K table, the luminance
+// ratio is looked up by the upper 16 bits of the f32 luminance, the sRGB transfer by a 4K table.
+
+// The luminance of 1.0 in scRGB, which Windows takes as the SDR reference white.
+pub const DEFAULT_SDR_WHITE_NITS: f32 = 80.0;
+// The max luminance to assume if the display doesn't report it.
+const DEFAULT_MAX_LUMINANCE: f32 = 1000.0;
+const SCRGB_NITS: f32 = 80.0;
+const SRGB_LUT_SIZE: usize = 4096;
+
+const PQ_M1: f32 = 0.159_301_76;
+const PQ_M2: f32 = 78.843_75;
+const PQ_C1: f32 = 0.835_937_5;
+const PQ_C2: f32 = 18.851_563;
+const PQ_C3: f32 = 18.6875;
+
+/// The luminance range of an HDR display, in nits.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HdrMetadata {
+    pub max_luminance: f32,
+    pub min_luminance: f32,
+    pub max_full_frame_luminance: f32,
+}
+
+pub struct ToneMapper {
+    half: Vec<f32>,
+    gain: Vec<f32>, // the luminance ratio, by the upper 16 bits of the positive f32 luminance
+    srgb: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl ToneMapper {
+    pub fn new(metadata: &HdrMetadata, sdr_white_nits: f32) -> Self {
+        let src_max = if metadata.max_luminance > 0.0 {
+            metadata.max_luminance
+        } else {
+            DEFAULT_MAX_LUMINANCE
+        };
+        let gain = (0..0x8000u32)
+            .map(|i| {
+                let y = f32::from_bits(i << 16);
+                let nits = y * SCRGB_NITS;
+                if !nits.is_finite() || nits <= 0.0 {
+                    return 0.0;
+                }
+                eetf(nits, src_max, sdr_white_nits) / nits * SCRGB_NITS / sdr_white_nits
+            })
+            .collect();
+        let srgb = (0..=SRGB_LUT_SIZE)
+            .map(|i| {
+                let v = i as f32 / SRGB_LUT_SIZE as f32;
+                let v = if v <= 0.003_130_8 {
+                    12.92 * v
+                } else {
+                    1.055 * v.powf(1.0 / 2.4) - 0.055
+                };
+                (v * 255.0).round() as u8
+            })
+            .collect();
+        Self {
+            half: (0..=u16::MAX).map(half_to_f32).collect(),
+            gain,
+            srgb,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Tone-map the RGBA FP16 frame with `stride` bytes per row to BGRA of `width * 4` bytes
+    /// per row.
+    pub fn process(&mut self, src: &[u8], stride: usize, width: usize, height: usize) -> &[u8] {
+        self.buf.resize(width * height * 4, 0);
+        for (y, dst_row) in self.buf.chunks_exact_mut(width * 4).enumerate() {
+            let Some(src_row) = src.get(y * stride..y * stride + width * 8) else {
+                break;
+            };
+            for (dst, px) in dst_row.chunks_exact_mut(4).zip(src_row.chunks_exact(8)) {
+                let c = |i: usize| self.half[u16::from_le_bytes([px[i], px[i + 1]]) as usize];
+                let (r, g, b) = (c(0), c(2), c(4));
+                // NaN is mapped to 0 too.
+                let y = (0.2126 * r + 0.7152 * g + 0.0722 * b).max(0.0);
+                let gain = self.gain[(y.to_bits() >> 16) as usize & 0x7FFF];
+                let encode = |v: f32| {
+                    let i = ((v * gain).clamp(0.0, 1.0) * SRGB_LUT_SIZE as f32) as usize;
+                    self.srgb[i.min(SRGB_LUT_SIZE)]
+                };
+                dst[0] = encode(b);
+                dst[1] = encode(g);
+                dst[2] = encode(r);
+                dst[3] = 255;
+            }
+        }
+        &self.buf
+    }
+}
+
+fn half_to_f32(h: u16) -> f32 {
+    let sign = ((h >> 15) as u32) << 31;
+    let exp = ((h >> 10) & 0x1F) as u32;
+    let mant = (h & 0x3FF) as u32;
+    let bits = match exp {
+        0 if mant == 0 => sign,
+        0 => {
+            // Subnormal, normalize it.
+            let (mut e, mut m) = (127 - 15 + 1, mant);
+            while m & 0x400 == 0 {
+                m <<= 1;
+                e -= 1;
+            }
+            sign | (e << 23) | ((m & 0x3FF) << 13)
+        }
+        0x1F => sign | 0x7F80_0000 | (mant << 13),
+        _ => sign | ((exp + 127 - 15) << 23) | (mant << 13),
+    };
+    f32::from_bits(bits)
+}
+
+// nits to the PQ signal, [0, 1].
+fn pq_encode(nits: f32) -> f32 {
+    let y = (nits / 10000.0).clamp(0.0, 1.0).powf(PQ_M1);
+    ((PQ_C1 + PQ_C2 * y) / (1.0 + PQ_C3 * y)).powf(PQ_M2)
+}
+
+// The PQ signal to nits.
+fn pq_decode(e: f32) -> f32 {
+    let e = e.clamp(0.0, 1.0).powf(1.0 / PQ_M2);
+    ((e - PQ_C1).max(0.0) / (PQ_C2 - PQ_C3 * e)).powf(1.0 / PQ_M1) * 10000.0
+}
+
+// BT.2390 EETF, mapping [0, src_max] nits to [0, dst_max] nits. The black level is 0,
+// the displays report it but the scRGB frames are not offset by it.
+fn eetf(nits: f32, src_max: f32, dst_max: f32) -> f32 {
+    if src_max <= dst_max {
+        return nits.min(dst_max);
+    }
+    let src = pq_encode(src_max);
+    let e1 = (pq_encode(nits) / src).min(1.0);
+    let max_lum = pq_encode(dst_max) / src;
+    // The knee, below which the luminance is kept.
+    let ks = 1.5 * max_lum - 0.5;
+    let e2 = if e1 < ks {
+        e1
+    } else {
+        // Hermite spline from the knee to (1, max_lum).
+        let t = (e1 - ks) / (1.0 - ks);
+        let (t2, t3) = (t * t, t * t * t);
+        (2.0 * t3 - 3.0 * t2 + 1.0) * ks
+            + (t3 - 2.0 * t2 + t) * (1.0 - ks)
+            + (-2.0 * t3 + 3.0 * t2) * max_lum
+    };
+    pq_decode(e2 * src)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_half_to_f32() {
+        assert_eq!(half_to_f32(0x3C00), 1.0);
+        assert_eq!(half_to_f32(0xC000), -2.0);
+        assert_eq!(half_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(half_to_f32(0x0200), 2f32.powi(-15));
+        assert_eq!(half_to_f32(0x7C00), f32::INFINITY);
+        assert!(half_to_f32(0x7E00).is_nan());
+    }
+
+    #[test]
+    fn test_tone_map() {
+        let metadata = HdrMetadata {
+            max_luminance: 1000.0,
+            ..Default::default()
+        };
+        let mut mapper = ToneMapper::new(&metadata, DEFAULT_SDR_WHITE_NITS);
+        // Gray pixels of 0, 8 nits, 80 nits, 1000 nits, and 1000 nits red.
+        let pixels: [[f32; 3]; 5] = [
+            [0.0, 0.0, 0.0],
+            [0.1, 0.1, 0.1],
+            [1.0, 1.0, 1.0],
+            [12.5, 12.5, 12.5],
+            [12.5, 0.0, 0.0],
+        ];
+        let to_half = |v: f32| {
+            (0..0x7C00u16)
+                .min_by(|a, b| {
+                    let (a, b) = ((half_to_f32(*a) - v).abs(), (half_to_f32(*b) - v).abs());
+                    a.partial_cmp(&b).unwrap()
+                })
+                .unwrap()
+        };
+        let mut src = vec![];
+        for p in pixels {
+            for v in [p[0], p[1], p[2], 1.0] {
+                src.extend_from_slice(&to_half(v).to_le_bytes());
+            }
+        }
+        // One pixel of padding.
+        src.extend_from_slice(&[0; 8]);
+        let dst = mapper.process(&src, 6 * 8, 5, 1).to_vec();
+        let px = |i: usize| &dst[i * 4..i * 4 + 4];
+        assert_eq!(px(0), [0, 0, 0, 255]);
+        // Below the knee, sRGB of 0.1.
+        assert!((px(1)[0] as i32 - 89).abs() <= 1, "{:?}", px(1));
+        assert!(px(2)[0] > px(1)[0] && px(2)[0] < 255);
+        assert_eq!(px(3), [255, 255, 255, 255]);
+        assert_eq!(px(4), [0, 0, 255, 255]);
+    }
+}
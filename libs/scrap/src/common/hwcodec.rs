@@ -1,5 +1,7 @@
 use crate::{
-    codec::{base_bitrate, codec_thread_num, enable_hwcodec_option, EncoderApi, EncoderCfg},
+    codec::{
+        base_bitrate, codec_thread_num, enable_hwcodec_option, EncoderApi, EncoderCfg, RoiRect,
+    },
     convert::*,
     CodecFormat, EncodeInput, ImageFormat, ImageRgb, Pixfmt, HW_STRIDE_ALIGN,
 };
@@ -47,6 +49,7 @@ pub struct HwRamEncoderConfig {
     pub height: usize,
     pub quality: f32,
     pub keyframe_interval: Option<usize>,
+    pub display_scale: f64,
 }
 
 pub struct HwRamEncoder {
@@ -65,8 +68,13 @@ impl EncoderApi for HwRamEncoder {
         match cfg {
             EncoderCfg::HWRAM(config) => {
                 let rc = Self::rate_control(&config);
-                let mut bitrate =
-                    Self::bitrate(&config.name, config.width, config.height, config.quality);
+                let mut bitrate = Self::bitrate(
+                    &config.name,
+                    config.width,
+                    config.height,
+                    config.display_scale,
+                    config.quality,
+                );
                 bitrate = Self::check_bitrate_range(&config, bitrate);
                 let gop = config.keyframe_interval.unwrap_or(DEFAULT_GOP as _) as i32;
                 let ctx = EncodeContext {
@@ -101,7 +109,7 @@ impl EncoderApi for HwRamEncoder {
                         bitrate,
                         config,
                     }),
-                    Err(_) => Err(anyhow!(format!("Failed to create encoder"))),
+                    Err(_) => Err(anyhow!(format!("Failed to create encoder {}", config.name))),
                 }
             }
             _ => Err(anyhow!("encoder type mismatch")),
@@ -175,6 +183,7 @@ impl EncoderApi for HwRamEncoder {
             &self.config.name,
             self.config.width,
             self.config.height,
+            self.config.display_scale,
             ratio,
         );
         if bitrate > 0 {
@@ -207,9 +216,28 @@ impl EncoderApi for HwRamEncoder {
     fn disable(&self) {
         HwCodecConfig::clear(false, true);
     }
+
+    fn request_keyframe(&mut self) -> bool {
+        false
+    }
+
+    fn set_roi(&mut self, _rects: &[RoiRect]) -> ResultType<bool> {
+        Ok(false)
+    }
+
+    fn set_burst(&mut self, _burst: bool) -> ResultType<bool> {
+        Ok(false)
+    }
+
+    fn set_resolution(&mut self, _width: u32, _height: u32) -> ResultType<bool> {
+        Ok(false)
+    }
 }
 
 impl HwRamEncoder {
+    // The best encoder found by the check process. On macOS, H264 and H265 are encoded by
+    // VideoToolbox (h264_videotoolbox, hevc_videotoolbox). There is no software H265 encoder,
+    // H265 is not negotiated if no hardware encoder is found.
     pub fn try_get(format: CodecFormat) -> Option<CodecInfo> {
         let mut info = None;
         let best = CodecInfo::prioritized(HwCodecConfig::get().ram_encode);
@@ -248,12 +276,18 @@ impl HwRamEncoder {
         RC_CBR
     }
 
-    pub fn bitrate(name: &str, width: usize, height: usize, ratio: f32) -> u32 {
-        Self::calc_bitrate(width, height, ratio, name.contains("h264"))
+    pub fn bitrate(name: &str, width: usize, height: usize, display_scale: f64, ratio: f32) -> u32 {
+        Self::calc_bitrate(width, height, display_scale, ratio, name.contains("h264"))
     }
 
-    pub fn calc_bitrate(width: usize, height: usize, ratio: f32, h264: bool) -> u32 {
-        let base = base_bitrate(width as _, height as _) as f32 * ratio;
+    pub fn calc_bitrate(
+        width: usize,
+        height: usize,
+        display_scale: f64,
+        ratio: f32,
+        h264: bool,
+    ) -> u32 {
+        let base = base_bitrate(width as _, height as _, display_scale) as f32 * ratio;
         let threshold = 2000.0;
         let decay_rate = 0.001; // 1000 * 0.001 = 1
         let factor: f32 = if cfg!(target_os = "android") {
@@ -677,6 +711,20 @@ impl HwCodecConfig {
     }
 }
 
+// Whether an nvidia encoder passed the check, nvenc of ffmpeg or nvenc sdk of vram.
+pub fn is_nvenc_available() -> bool {
+    let config = HwCodecConfig::get();
+    #[cfg(feature = "vram")]
+    if config
+        .vram_encode
+        .iter()
+        .any(|e| e.driver == hwcodec::common::Driver::NV)
+    {
+        return true;
+    }
+    config.ram_encode.iter().any(|e| e.name.contains("nvenc"))
+}
+
 pub fn check_available_hwcodec() -> String {
     let ctx = EncodeContext {
         name: String::from(""),
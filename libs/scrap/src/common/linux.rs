@@ -2,7 +2,7 @@ use crate::{
     common::{
         wayland,
         x11::{self},
-        TraitCapturer,
+        CaptureError, TraitCapturer,
     },
     Frame,
 };
@@ -37,7 +37,7 @@ impl Capturer {
 }
 
 impl TraitCapturer for Capturer {
-    fn frame<'a>(&'a mut self, timeout: Duration) -> io::Result<Frame<'a>> {
+    fn frame<'a>(&'a mut self, timeout: Duration) -> Result<Frame<'a>, CaptureError> {
         match self {
             Capturer::X11(d) => d.frame(timeout),
             Capturer::WAYLAND(d) => d.frame(timeout),
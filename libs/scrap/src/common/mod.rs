@@ -37,6 +37,9 @@ cfg_if! {
 
 pub mod codec;
 pub mod convert;
+pub mod deinterlace;
+#[cfg(feature = "hdr")]
+pub mod hdr;
 #[cfg(feature = "hwcodec")]
 pub mod hwcodec;
 #[cfg(feature = "mediacodec")]
@@ -45,8 +48,32 @@ pub mod vpxcodec;
 #[cfg(feature = "vram")]
 pub mod vram;
 pub use self::convert::*;
-pub const STRIDE_ALIGN: usize = 64; // commonly used in libvpx vpx_img_alloc caller
-pub const HW_STRIDE_ALIGN: usize = 0; // recommended by av_frame_get_buffer
+/// The alignment in bytes of the yuv plane strides for the software encoders (libvpx, libaom),
+/// commonly used in libvpx vpx_img_alloc caller. It's 64 on all platforms, which covers the
+/// widest SIMD loads of libyuv and libvpx: 64 for AVX-512, 32 for AVX2 and 16 for NEON.
+///
+/// The captured frames are not affected, their strides are decided by the capture backends,
+/// e.g. the pitch of the DXGI textures or the X11 shared memory images.
+pub const STRIDE_ALIGN: usize = 64;
+/// 0 lets FFmpeg choose the alignment, recommended by av_frame_get_buffer.
+pub const HW_STRIDE_ALIGN: usize = 0;
+
+/// The stride of a row of `width` bytes, aligned to `STRIDE_ALIGN`.
+#[inline]
+pub fn stride_for_width(width: usize) -> usize {
+    aligned_stride(width, STRIDE_ALIGN)
+}
+
+/// The stride of a row of `width` bytes, aligned to `align` which is a power of 2.
+/// 0 or 1 means no alignment.
+#[inline]
+pub fn aligned_stride(width: usize, align: usize) -> usize {
+    if align <= 1 {
+        width
+    } else {
+        (width + align - 1) & !(align - 1)
+    }
+}
 
 pub mod aom;
 pub mod record;
@@ -123,16 +150,69 @@ pub fn would_block_if_equal(old: &mut Vec<u8>, b: &[u8]) -> std::io::Result<()>
     Ok(())
 }
 
+/// The errors of `TraitCapturer::frame()`.
+#[derive(Debug)]
+pub enum CaptureError {
+    /// No new frame within the timeout, or the screen has not changed.
+    Timeout,
+    /// The capture is lost and has to be recreated, e.g. DXGI_ERROR_ACCESS_LOST on a desktop
+    /// switch, a mode change or a full-screen exclusive application.
+    AccessLost,
+    /// The device or the display is gone, e.g. a GPU driver reset.
+    DeviceRemoved,
+    Other(std::io::Error),
+}
+
+impl From<std::io::Error> for CaptureError {
+    fn from(e: std::io::Error) -> Self {
+        use std::io::ErrorKind::*;
+        match e.kind() {
+            WouldBlock | TimedOut => Self::Timeout,
+            ConnectionReset => Self::AccessLost,
+            NotConnected => Self::DeviceRemoved,
+            // The handles of the outputs are invalid after a GPU driver reset.
+            #[cfg(windows)]
+            _ if e.raw_os_error() == Some(winapi::shared::winerror::ERROR_INVALID_HANDLE as _) => {
+                Self::DeviceRemoved
+            }
+            _ => Self::Other(e),
+        }
+    }
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "capture timeout"),
+            Self::AccessLost => write!(f, "capture access lost"),
+            Self::DeviceRemoved => write!(f, "capture device removed"),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
 pub trait TraitCapturer {
     // We doesn't support
     #[cfg(not(any(target_os = "ios")))]
-    fn frame<'a>(&'a mut self, timeout: std::time::Duration) -> std::io::Result<Frame<'a>>;
+    fn frame<'a>(&'a mut self, timeout: std::time::Duration) -> Result<Frame<'a>, CaptureError>;
 
     #[cfg(windows)]
     fn is_gdi(&self) -> bool;
     #[cfg(windows)]
     fn set_gdi(&mut self) -> bool;
 
+    // Draw the hardware cursor on the frames, if the capturer excludes it.
+    #[cfg(windows)]
+    fn set_draw_cursor(&mut self, _draw: bool) {}
+
+    // The frames are tone-mapped from HDR, they can't be output as textures.
+    #[cfg(all(windows, feature = "hdr"))]
+    fn is_hdr(&self) -> bool {
+        false
+    }
+
     #[cfg(feature = "vram")]
     fn device(&self) -> AdapterDevice;
 
@@ -431,7 +511,7 @@ pub trait GoogleImage {
         };
         // https://github.com/lemenkov/libyuv/blob/6900494d90ae095d44405cd4cc3f346971fa69c9/source/convert_argb.cc#L128
         // https://github.com/lemenkov/libyuv/blob/6900494d90ae095d44405cd4cc3f346971fa69c9/source/convert_argb.cc#L129
-        (w * bytes_per_pixel + align - 1) & !(align - 1)
+        aligned_stride(w * bytes_per_pixel, align)
     }
     // rgb [in/out] fmt and stride must be set in ImageRgb
     fn to(&self, rgb: &mut ImageRgb) {
@@ -542,3 +622,19 @@ pub fn screen_size() -> (u16, u16, u16) {
 pub fn is_start() -> Option<bool> {
     android::is_start()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stride_for_width() {
+        for width in [1, 63, 64, 65, 1920, 1366 * 4, 2560 / 2] {
+            let stride = stride_for_width(width);
+            assert_eq!(stride % STRIDE_ALIGN, 0);
+            assert!(stride >= width && stride < width + STRIDE_ALIGN);
+        }
+        assert_eq!(aligned_stride(100, 32), 128);
+        assert_eq!(aligned_stride(100, 0), 100);
+    }
+}
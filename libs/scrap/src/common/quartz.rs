@@ -1,4 +1,4 @@
-use crate::{quartz, Frame, Pixfmt};
+use crate::{quartz, CaptureError, Frame, Pixfmt};
 use std::marker::PhantomData;
 use std::sync::{Arc, Mutex, TryLockError};
 use std::{io, mem};
@@ -45,7 +45,10 @@ impl Capturer {
 }
 
 impl crate::TraitCapturer for Capturer {
-    fn frame<'a>(&'a mut self, _timeout_ms: std::time::Duration) -> io::Result<Frame<'a>> {
+    fn frame<'a>(
+        &'a mut self,
+        _timeout_ms: std::time::Duration,
+    ) -> Result<Frame<'a>, CaptureError> {
         match self.frame.try_lock() {
             Ok(mut handle) => {
                 let mut frame = None;
@@ -63,13 +66,15 @@ impl crate::TraitCapturer for Capturer {
                         }))
                     }
 
-                    None => Err(io::ErrorKind::WouldBlock.into()),
+                    None => Err(CaptureError::Timeout),
                 }
             }
 
-            Err(TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TryLockError::WouldBlock) => Err(CaptureError::Timeout),
 
-            Err(TryLockError::Poisoned(..)) => Err(io::ErrorKind::Other.into()),
+            Err(TryLockError::Poisoned(..)) => {
+                Err(CaptureError::Other(io::ErrorKind::Other.into()))
+            }
         }
     }
 }
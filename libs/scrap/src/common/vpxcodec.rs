@@ -8,18 +8,53 @@ use hbb_common::log;
 use hbb_common::message_proto::{Chroma, EncodedVideoFrame, EncodedVideoFrames, VideoFrame};
 use hbb_common::ResultType;
 
-use crate::codec::{base_bitrate, codec_thread_num, EncoderApi};
+use crate::codec::{base_bitrate, codec_thread_num, EncoderApi, RoiRect};
+use crate::deinterlace::Deinterlacer;
 use crate::{EncodeInput, EncodeYuvFormat, GoogleImage, Pixfmt, STRIDE_ALIGN};
 
 use super::vpx::{vp8e_enc_control_id::*, vpx_codec_err_t::*, *};
 use crate::{generate_call_macro, generate_call_ptr_macro, Error, Result};
 use hbb_common::bytes::Bytes;
 use std::os::raw::{c_int, c_uint};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::{ptr, slice};
 
 generate_call_macro!(call_vpx, false);
 generate_call_ptr_macro!(call_vpx_ptr);
 
+// The VPX quantizer scale is 0 - 63, 0 is lossless-like and 63 is the worst quality.
+// `rc_max_quantizer` is interpolated between DEFAULT_MAX_QUANTIZER (lowest ratio) and MAX_QUANTIZER_BEST (highest ratio).
+pub const DEFAULT_MAX_QUANTIZER: u32 = 56;
+const MAX_QUANTIZER_BEST: u32 = 37;
+const MAX_QUANTIZER_LIMIT: u32 = 63;
+// How much `rc_max_quantizer` is lowered during a burst.
+const BURST_QUANTIZER_REDUCTION: u32 = 12;
+static MAX_QUANTIZER: AtomicU32 = AtomicU32::new(DEFAULT_MAX_QUANTIZER);
+static LOSSLESS: AtomicBool = AtomicBool::new(false);
+
+/// Set the highest `rc_max_quantizer` used for the lowest quality ratio.
+/// A lower value prevents the quality from collapsing, at the cost of bandwidth.
+/// The value is clamped to 37 - 63, and takes effect on the next `set_quality` or new encoder.
+pub fn set_max_quantizer(q: u32) {
+    let q = q.clamp(MAX_QUANTIZER_BEST, MAX_QUANTIZER_LIMIT);
+    if MAX_QUANTIZER.swap(q, Ordering::Relaxed) != q {
+        log::info!("set vpx max quantizer to {}", q);
+    }
+}
+
+/// Encode VP9 losslessly, for pixel-perfect remote control. VP8 is not affected.
+/// Takes effect on the new encoders, I444 is preferred to avoid the chroma subsampling loss.
+pub fn set_lossless(lossless: bool) {
+    if LOSSLESS.swap(lossless, Ordering::Relaxed) != lossless {
+        log::info!("set vp9 lossless to {}", lossless);
+    }
+}
+
+#[inline]
+pub fn is_lossless() -> bool {
+    LOSSLESS.load(Ordering::Relaxed)
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum VpxVideoCodecId {
     VP8,
@@ -39,6 +74,11 @@ pub struct VpxEncoder {
     id: VpxVideoCodecId,
     i444: bool,
     yuvfmt: EncodeYuvFormat,
+    force_keyframe: bool,
+    burst_max_quantizer: Option<u32>, // the max quantizer before the burst
+    lossless: bool,
+    deinterlacer: Option<Deinterlacer>,
+    display_scale: f64,
 }
 
 pub struct VpxDecoder {
@@ -83,11 +123,20 @@ impl EncoderApi for VpxEncoder {
                     c.kf_mode = vpx_kf_mode::VPX_KF_DISABLED; // reduce bandwidth a lot
                 }
 
-                let (q_min, q_max) = Self::calc_q_values(config.quality);
+                let lossless = config.codec == VpxVideoCodecId::VP9 && is_lossless();
+                let (q_min, q_max) = if lossless {
+                    (0, 0)
+                } else {
+                    Self::calc_q_values(config.quality)
+                };
                 c.rc_min_quantizer = q_min;
                 c.rc_max_quantizer = q_max;
-                c.rc_target_bitrate =
-                    Self::bitrate(config.width as _, config.height as _, config.quality);
+                c.rc_target_bitrate = Self::bitrate(
+                    config.width as _,
+                    config.height as _,
+                    config.display_scale,
+                    config.quality,
+                );
                 // https://chromium.googlesource.com/webm/libvpx/+/refs/heads/main/vp9/common/vp9_enums.h#29
                 // https://chromium.googlesource.com/webm/libvpx/+/refs/heads/main/vp8/vp8_cx_iface.c#282
                 c.g_profile = if i444 && config.codec == VpxVideoCodecId::VP9 {
@@ -152,6 +201,14 @@ impl EncoderApi for VpxEncoder {
                         VP9E_SET_TILE_COLUMNS as _,
                         4 as c_int
                     ));
+
+                    if lossless {
+                        call_vpx!(vpx_codec_control_(
+                            &mut ctx,
+                            VP9E_SET_LOSSLESS as _,
+                            1 as c_int
+                        ));
+                    }
                 } else if config.codec == VpxVideoCodecId::VP8 {
                     // https://github.com/webmproject/libvpx/blob/972149cafeb71d6f08df89e91a0130d6a38c4b15/vpx/vp8cx.h#L172
                     // https://groups.google.com/a/webmproject.org/g/webm-discuss/c/DJhSrmfQ61M
@@ -165,6 +222,12 @@ impl EncoderApi for VpxEncoder {
                     id: config.codec,
                     i444,
                     yuvfmt: Self::get_yuvfmt(config.width, config.height, i444),
+                    force_keyframe: false,
+                    burst_max_quantizer: None,
+                    lossless,
+                    deinterlacer: (config.deinterlace && config.codec == VpxVideoCodecId::VP9)
+                        .then(Deinterlacer::default),
+                    display_scale: config.display_scale,
                 })
             }
             _ => Err(anyhow!("encoder type mismatch")),
@@ -202,11 +265,18 @@ impl EncoderApi for VpxEncoder {
 
     fn set_quality(&mut self, ratio: f32) -> ResultType<()> {
         let mut c = unsafe { *self.ctx.config.enc.to_owned() };
-        let (q_min, q_max) = Self::calc_q_values(ratio);
+        let (q_min, q_max) = if self.lossless {
+            (0, 0)
+        } else {
+            Self::calc_q_values(ratio)
+        };
         c.rc_min_quantizer = q_min;
         c.rc_max_quantizer = q_max;
-        c.rc_target_bitrate = Self::bitrate(self.width as _, self.height as _, ratio);
+        c.rc_target_bitrate =
+            Self::bitrate(self.width as _, self.height as _, self.display_scale, ratio);
         call_vpx!(vpx_codec_enc_config_set(&mut self.ctx, &c));
+        // The new quality ends the burst.
+        self.burst_max_quantizer = None;
         Ok(())
     }
 
@@ -228,6 +298,86 @@ impl EncoderApi for VpxEncoder {
     }
 
     fn disable(&self) {}
+
+    fn request_keyframe(&mut self) -> bool {
+        self.force_keyframe = true;
+        true
+    }
+
+    fn set_roi(&mut self, rects: &[RoiRect]) -> ResultType<bool> {
+        if self.id != VpxVideoCodecId::VP9 {
+            return Ok(false);
+        }
+        // VP9 maps 8x8 blocks to up to 8 segments, segment 0 keeps the frame quantizer.
+        const BLOCK: usize = 8;
+        const MAX_SEGMENTS: usize = 8;
+        let rows = (self.height + BLOCK - 1) / BLOCK;
+        let cols = (self.width + BLOCK - 1) / BLOCK;
+        let mut map = vec![0u8; rows * cols];
+        let mut roi: vpx_roi_map_t = unsafe { std::mem::zeroed() };
+        for (i, r) in rects.iter().take(MAX_SEGMENTS - 1).enumerate() {
+            let segment = i + 1;
+            roi.delta_q[segment] = r.delta_q.clamp(-63, 63);
+            let x0 = (r.x as usize / BLOCK).min(cols);
+            let y0 = (r.y as usize / BLOCK).min(rows);
+            let x1 = ((r.x + r.w) as usize + BLOCK - 1) / BLOCK;
+            let y1 = ((r.y + r.h) as usize + BLOCK - 1) / BLOCK;
+            for y in y0..y1.min(rows) {
+                map[y * cols + x0..y * cols + x1.min(cols).max(x0)].fill(segment as _);
+            }
+        }
+        roi.rows = rows as _;
+        roi.cols = cols as _;
+        // A null map disables the roi.
+        roi.roi_map = if rects.is_empty() {
+            ptr::null_mut()
+        } else {
+            map.as_mut_ptr()
+        };
+        call_vpx!(vpx_codec_control_(
+            &mut self.ctx,
+            VP9E_SET_ROI_MAP as _,
+            &mut roi as *mut vpx_roi_map_t
+        ));
+        Ok(true)
+    }
+
+    fn set_burst(&mut self, burst: bool) -> ResultType<bool> {
+        if burst == self.burst_max_quantizer.is_some() {
+            return Ok(true);
+        }
+        let mut c = unsafe { *self.ctx.config.enc.to_owned() };
+        match self.burst_max_quantizer.take() {
+            Some(q_max) => c.rc_max_quantizer = q_max,
+            None => {
+                self.burst_max_quantizer = Some(c.rc_max_quantizer);
+                c.rc_max_quantizer = c
+                    .rc_max_quantizer
+                    .saturating_sub(BURST_QUANTIZER_REDUCTION)
+                    .max(c.rc_min_quantizer);
+            }
+        }
+        call_vpx!(vpx_codec_enc_config_set(&mut self.ctx, &c));
+        Ok(true)
+    }
+
+    fn set_resolution(&mut self, width: u32, height: u32) -> ResultType<bool> {
+        if width as usize == self.width && height as usize == self.height {
+            return Ok(true);
+        }
+        let mut c = unsafe { *self.ctx.config.enc.to_owned() };
+        let area = |w: u32, h: u32| w as u64 * h as u64;
+        c.rc_target_bitrate =
+            (c.rc_target_bitrate as u64 * area(width, height) / area(c.g_w, c.g_h).max(1)) as _;
+        c.g_w = width;
+        c.g_h = height;
+        // VP8 fails if the size is larger than the initial one.
+        call_vpx!(vpx_codec_enc_config_set(&mut self.ctx, &c));
+        self.width = width as _;
+        self.height = height as _;
+        self.yuvfmt = Self::get_yuvfmt(width, height, self.i444);
+        Ok(true)
+    }
 }
 
 impl VpxEncoder {
@@ -236,6 +386,12 @@ impl VpxEncoder {
         if data.len() < self.width * self.height * bpp / 8 {
             return Err(Error::FailedCall("len not enough".to_string()));
         }
+        let data = match self.deinterlacer.as_mut() {
+            Some(deinterlacer) => deinterlacer
+                .process(data, &self.yuvfmt)
+                .map_err(|e| Error::FailedCall(e.to_string()))?,
+            None => data,
+        };
         let fmt = if self.i444 {
             vpx_img_fmt::VPX_IMG_FMT_I444
         } else {
@@ -252,12 +408,17 @@ impl VpxEncoder {
             data.as_ptr() as _,
         ));
 
+        let flags = if std::mem::take(&mut self.force_keyframe) {
+            VPX_EFLAG_FORCE_KF
+        } else {
+            0
+        };
         call_vpx!(vpx_codec_encode(
             &mut self.ctx,
             &image,
             pts as _,
-            1, // Duration
-            0, // Flags
+            1,          // Duration
+            flags as _, // Flags
             VPX_DL_REALTIME as _,
         ));
 
@@ -311,8 +472,8 @@ impl VpxEncoder {
         }
     }
 
-    fn bitrate(width: u32, height: u32, ratio: f32) -> u32 {
-        let bitrate = base_bitrate(width, height) as f32;
+    fn bitrate(width: u32, height: u32, display_scale: f64, ratio: f32) -> u32 {
+        let bitrate = base_bitrate(width, height, display_scale) as f32;
         (bitrate * ratio) as u32
     }
 
@@ -322,8 +483,8 @@ impl VpxEncoder {
         let b = std::cmp::min(b, 200);
         let q_min1 = 36;
         let q_min2 = 0;
-        let q_max1 = 56;
-        let q_max2 = 37;
+        let q_max1 = MAX_QUANTIZER.load(Ordering::Relaxed);
+        let q_max2 = MAX_QUANTIZER_BEST;
 
         let t = b as f32 / 200.0;
 
@@ -398,6 +559,10 @@ pub struct VpxEncoderConfig {
     pub codec: VpxVideoCodecId,
     /// keyframe interval
     pub keyframe_interval: Option<usize>,
+    /// Deinterlace the frames before encoding, VP9 only.
+    pub deinterlace: bool,
+    /// The ratio of the frame size to the logical size of the display, for the bitrate.
+    pub display_scale: f64,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -5,7 +5,7 @@ use std::{
 };
 
 use crate::{
-    codec::{base_bitrate, enable_vram_option, EncoderApi, EncoderCfg},
+    codec::{base_bitrate, enable_vram_option, EncoderApi, EncoderCfg, RoiRect},
     hwcodec::HwCodecConfig,
     AdapterDevice, CodecFormat, EncodeInput, EncodeYuvFormat, Pixfmt,
 };
@@ -42,6 +42,7 @@ pub struct VRamEncoderConfig {
     pub quality: f32,
     pub feature: FeatureContext,
     pub keyframe_interval: Option<usize>,
+    pub display_scale: f64,
 }
 
 pub struct VRamEncoder {
@@ -51,6 +52,7 @@ pub struct VRamEncoder {
     bitrate: u32,
     last_frame_len: usize,
     same_bad_len_counter: usize,
+    display_scale: f64,
 }
 
 impl EncoderApi for VRamEncoder {
@@ -64,6 +66,7 @@ impl EncoderApi for VRamEncoder {
                     config.feature.data_format,
                     config.width,
                     config.height,
+                    config.display_scale,
                     config.quality,
                 );
                 let gop = config.keyframe_interval.unwrap_or(MAX_GOP as _) as i32;
@@ -86,6 +89,7 @@ impl EncoderApi for VRamEncoder {
                         bitrate,
                         last_frame_len: 0,
                         same_bad_len_counter: 0,
+                        display_scale: config.display_scale,
                     }),
                     Err(_) => Err(anyhow!(format!("Failed to create encoder"))),
                 }
@@ -175,6 +179,7 @@ impl EncoderApi for VRamEncoder {
             self.ctx.f.data_format,
             self.ctx.d.width as _,
             self.ctx.d.height as _,
+            self.display_scale,
             ratio,
         );
         if bitrate > 0 {
@@ -204,6 +209,22 @@ impl EncoderApi for VRamEncoder {
     fn disable(&self) {
         HwCodecConfig::clear(true, true);
     }
+
+    fn request_keyframe(&mut self) -> bool {
+        false
+    }
+
+    fn set_roi(&mut self, _rects: &[RoiRect]) -> ResultType<bool> {
+        Ok(false)
+    }
+
+    fn set_burst(&mut self, _burst: bool) -> ResultType<bool> {
+        Ok(false)
+    }
+
+    fn set_resolution(&mut self, _width: u32, _height: u32) -> ResultType<bool> {
+        Ok(false)
+    }
 }
 
 impl VRamEncoder {
@@ -283,8 +304,20 @@ impl VRamEncoder {
         }
     }
 
-    pub fn bitrate(fmt: DataFormat, width: usize, height: usize, ratio: f32) -> u32 {
-        crate::hwcodec::HwRamEncoder::calc_bitrate(width, height, ratio, fmt == DataFormat::H264)
+    pub fn bitrate(
+        fmt: DataFormat,
+        width: usize,
+        height: usize,
+        display_scale: f64,
+        ratio: f32,
+    ) -> u32 {
+        crate::hwcodec::HwRamEncoder::calc_bitrate(
+            width,
+            height,
+            display_scale,
+            ratio,
+            fmt == DataFormat::H264,
+        )
     }
 
     pub fn set_not_use(display: usize, not_use: bool) {
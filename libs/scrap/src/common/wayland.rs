@@ -1,6 +1,6 @@
 use crate::{
     wayland::{capturable::*, *},
-    Frame, TraitCapturer,
+    CaptureError, Frame, TraitCapturer,
 };
 use std::{io, sync::RwLock, time::Duration};
 
@@ -8,7 +8,6 @@ use super::x11::PixelBuffer;
 
 pub struct Capturer(Display, Box<dyn Recorder>, Vec<u8>);
 
-
 lazy_static::lazy_static! {
     static ref MAP_ERR: RwLock<Option<fn(err: String)-> io::Error>> = Default::default();
 }
@@ -41,7 +40,7 @@ impl Capturer {
 }
 
 impl TraitCapturer for Capturer {
-    fn frame<'a>(&'a mut self, timeout: Duration) -> io::Result<Frame<'a>> {
+    fn frame<'a>(&'a mut self, timeout: Duration) -> Result<Frame<'a>, CaptureError> {
         match self.1.capture(timeout.as_millis() as _).map_err(map_err)? {
             PixelProvider::BGR0(w, h, x) => Ok(Frame::PixelBuffer(PixelBuffer::new(
                 x,
@@ -55,8 +54,8 @@ impl TraitCapturer for Capturer {
                 w,
                 h,
             ))),
-            PixelProvider::NONE => Err(std::io::ErrorKind::WouldBlock.into()),
-            _ => Err(map_err("Invalid data")),
+            PixelProvider::NONE => Err(CaptureError::Timeout),
+            _ => Err(map_err("Invalid data").into()),
         }
     }
 }
@@ -1,4 +1,4 @@
-use crate::{common::TraitCapturer, x11, Frame, Pixfmt, TraitPixelBuffer};
+use crate::{common::TraitCapturer, x11, CaptureError, Frame, Pixfmt, TraitPixelBuffer};
 use std::{io, time::Duration};
 
 pub struct Capturer(x11::Capturer);
@@ -20,7 +20,7 @@ impl Capturer {
 }
 
 impl TraitCapturer for Capturer {
-    fn frame<'a>(&'a mut self, _timeout: Duration) -> io::Result<Frame<'a>> {
+    fn frame<'a>(&'a mut self, _timeout: Duration) -> Result<Frame<'a>, CaptureError> {
         let width = self.width();
         let height = self.height();
         let pixfmt = self.0.display().pixfmt();
@@ -0,0 +1,122 @@
+// Draw the hardware cursor on the duplicated frames.
+//
+// The desktop duplication excludes the hardware cursor, it only reports the cursor shape and
+// position in the frame info. Some drivers do not draw a software cursor either, the cursor is
+// missing if the peer does not draw it from the cursor messages.
+
+use std::{io, mem};
+use winapi::shared::{dxgi1_2::*, minwindef::UINT};
+
+use super::wrap_hresult;
+
+#[derive(Default)]
+pub struct CursorBlitter {
+    shape: Vec<u8>,
+    shape_info: Option<DXGI_OUTDUPL_POINTER_SHAPE_INFO>,
+    position: (i32, i32),
+    visible: bool,
+}
+
+impl CursorBlitter {
+    // Update the cursor with the info of the frame just acquired.
+    pub unsafe fn update(
+        &mut self,
+        duplication: *mut IDXGIOutputDuplication,
+        info: &DXGI_OUTDUPL_FRAME_INFO,
+    ) -> io::Result<()> {
+        // The position is not updated if only the frame is updated.
+        if *info.LastMouseUpdateTime.QuadPart() != 0 {
+            self.visible = info.PointerPosition.Visible != 0;
+            self.position = (
+                info.PointerPosition.Position.x,
+                info.PointerPosition.Position.y,
+            );
+        }
+        if info.PointerShapeBufferSize == 0 {
+            return Ok(());
+        }
+        self.shape.resize(info.PointerShapeBufferSize as _, 0);
+        let mut required: UINT = 0;
+        let mut shape_info: DXGI_OUTDUPL_POINTER_SHAPE_INFO = mem::zeroed();
+        wrap_hresult((*duplication).GetFramePointerShape(
+            self.shape.len() as _,
+            self.shape.as_mut_ptr() as _,
+            &mut required,
+            &mut shape_info,
+        ))?;
+        self.shape_info = Some(shape_info);
+        Ok(())
+    }
+
+    // Draw the cursor on the bgra frame.
+    pub fn blit(&self, frame: &mut [u8], stride: usize, width: usize, height: usize) {
+        let Some(info) = self.shape_info.as_ref() else {
+            return;
+        };
+        if !self.visible {
+            return;
+        }
+        let pitch = info.Pitch as usize;
+        let (w, h) = match info.Type {
+            // The AND mask and the XOR mask are stacked vertically.
+            DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MONOCHROME => (info.Width, info.Height / 2),
+            _ => (info.Width, info.Height),
+        };
+        for cy in 0..h as usize {
+            let y = self.position.1 + cy as i32;
+            if y < 0 || y as usize >= height {
+                continue;
+            }
+            for cx in 0..w as usize {
+                let x = self.position.0 + cx as i32;
+                if x < 0 || x as usize >= width {
+                    continue;
+                }
+                let i = y as usize * stride + x as usize * 4;
+                let Some(dst) = frame.get_mut(i..i + 4) else {
+                    continue;
+                };
+                match info.Type {
+                    DXGI_OUTDUPL_POINTER_SHAPE_TYPE_COLOR => {
+                        let s = cy * pitch + cx * 4;
+                        let Some(src) = self.shape.get(s..s + 4) else {
+                            continue;
+                        };
+                        let a = src[3] as u32;
+                        for c in 0..3 {
+                            dst[c] = ((src[c] as u32 * a + dst[c] as u32 * (255 - a)) / 255) as u8;
+                        }
+                    }
+                    DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MASKED_COLOR => {
+                        let s = cy * pitch + cx * 4;
+                        let Some(src) = self.shape.get(s..s + 4) else {
+                            continue;
+                        };
+                        if src[3] == 0 {
+                            dst[..3].copy_from_slice(&src[..3]);
+                        } else {
+                            for c in 0..3 {
+                                dst[c] ^= src[c];
+                            }
+                        }
+                    }
+                    DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MONOCHROME => {
+                        let bit = 0x80 >> (cx % 8);
+                        let and = self.shape.get(cy * pitch + cx / 8).copied().unwrap_or(0xFF);
+                        let xor = self
+                            .shape
+                            .get((cy + h as usize) * pitch + cx / 8)
+                            .copied()
+                            .unwrap_or(0);
+                        let and = if and & bit != 0 { 0xFF } else { 0 };
+                        let xor = if xor & bit != 0 { 0xFF } else { 0 };
+                        for c in 0..3 {
+                            dst[c] = (dst[c] & and) ^ xor;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
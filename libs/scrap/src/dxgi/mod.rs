@@ -1,4 +1,5 @@
 use std::{io, mem, ptr, slice};
+mod cursor;
 pub mod gdi;
 pub use gdi::CapturerGDI;
 pub mod mag;
@@ -23,8 +24,19 @@ use winapi::{
 
 use crate::RotationMode::*;
 
+#[cfg(feature = "hdr")]
+use crate::hdr::{HdrMetadata, ToneMapper, DEFAULT_SDR_WHITE_NITS};
 use crate::{AdapterDevice, Frame, PixelBuffer};
 use std::ffi::c_void;
+#[cfg(feature = "hdr")]
+use winapi::{
+    shared::{
+        dxgi1_5::IDXGIOutput5,
+        dxgi1_6::{IDXGIOutput6, DXGI_OUTPUT_DESC1},
+        dxgiformat::DXGI_FORMAT_R16G16B16A16_FLOAT,
+    },
+    Interface,
+};
 
 pub struct ComPtr<T>(*mut T);
 impl<T> ComPtr<T> {
@@ -59,6 +71,10 @@ pub struct Capturer {
     output_texture: bool,
     adapter_desc1: DXGI_ADAPTER_DESC1,
     rotate: Rotate,
+    cursor: Option<cursor::CursorBlitter>,
+    cursor_buffer: Vec<u8>,
+    #[cfg(feature = "hdr")]
+    tone_mapper: Option<ToneMapper>, // the display is duplicated in FP16 if set
 }
 
 impl Capturer {
@@ -71,6 +87,8 @@ impl Capturer {
         #[allow(invalid_value)]
         let mut adapter_desc1 = unsafe { mem::MaybeUninit::uninit().assume_init() };
         let mut gdi_capturer = None;
+        #[cfg(feature = "hdr")]
+        let mut hdr = display.hdr_metadata();
 
         let mut res = if display.gdi {
             wrap_hresult(1)
@@ -106,7 +124,19 @@ impl Capturer {
             }
         } else {
             res = wrap_hresult(unsafe {
-                let hres = (*display.inner.0).DuplicateOutput(device.0 as *mut _, &mut duplication);
+                let mut hres = S_FALSE;
+                // Duplicating an HDR display in BGRA lets Windows clip the colors.
+                #[cfg(feature = "hdr")]
+                if hdr.is_some() {
+                    hres = display.duplicate_output_fp16(device.0 as *mut _, &mut duplication);
+                    if hres != S_OK {
+                        println!("Failed to duplicate the HDR display in FP16: 0x{:X}", hres);
+                        hdr = None;
+                    }
+                }
+                if hres != S_OK {
+                    hres = (*display.inner.0).DuplicateOutput(device.0 as *mut _, &mut duplication);
+                }
                 if hres != S_OK {
                     gdi_capturer = display.create_gdi();
                     println!("Fallback to GDI");
@@ -172,6 +202,10 @@ impl Capturer {
             output_texture: false,
             adapter_desc1,
             rotate,
+            cursor: None,
+            cursor_buffer: Vec::new(),
+            #[cfg(feature = "hdr")]
+            tone_mapper: hdr.map(|hdr| ToneMapper::new(&hdr, DEFAULT_SDR_WHITE_NITS)),
         })
     }
 
@@ -324,9 +358,24 @@ impl Capturer {
 
     #[cfg(feature = "vram")]
     pub fn set_output_texture(&mut self, texture: bool) {
+        #[cfg(feature = "hdr")]
+        let texture = texture && !self.is_hdr();
         self.output_texture = texture;
     }
 
+    #[cfg(feature = "hdr")]
+    pub fn is_hdr(&self) -> bool {
+        self.tone_mapper.is_some() && !self.is_gdi()
+    }
+
+    // Draw the hardware cursor on the pixel buffers, not on the textures or the gdi frames.
+    pub fn set_draw_cursor(&mut self, draw: bool) {
+        if draw != self.cursor.is_some() {
+            self.cursor = draw.then(Default::default);
+            self.cursor_buffer = Vec::new();
+        }
+    }
+
     unsafe fn load_frame(&mut self, timeout: UINT) -> io::Result<(*const u8, i32)> {
         let mut frame = ptr::null_mut();
         #[allow(invalid_value)]
@@ -335,6 +384,12 @@ impl Capturer {
         wrap_hresult((*self.duplication.0).AcquireNextFrame(timeout, &mut info, &mut frame))?;
         let frame = ComPtr(frame);
 
+        if let Some(cursor) = self.cursor.as_mut() {
+            if let Err(e) = cursor.update(self.duplication.0, &info) {
+                hbb_common::log::debug!("Failed to get the cursor shape: {}", e);
+            }
+        }
+
         if *info.LastPresentTime.QuadPart() == 0 {
             return Err(std::io::ErrorKind::WouldBlock.into());
         }
@@ -424,6 +479,22 @@ impl Capturer {
                 } else {
                     self.unmap();
                     let r = self.load_frame(timeout)?;
+                    #[cfg(feature = "hdr")]
+                    let r = match self.tone_mapper.as_mut() {
+                        Some(tone_mapper) => {
+                            // The frames of the rotated displays are not rotated yet.
+                            let (w, h) = match self.display.rotation() {
+                                DXGI_MODE_ROTATION_ROTATE90 | DXGI_MODE_ROTATION_ROTATE270 => {
+                                    (self.height, self.width)
+                                }
+                                _ => (self.width, self.height),
+                            };
+                            let src = slice::from_raw_parts(r.0, r.1 as usize * h);
+                            let bgra = tone_mapper.process(src, r.1 as usize, w, h);
+                            (bgra.as_ptr(), 4 * w as i32)
+                        }
+                        None => r,
+                    };
                     let rotate = match self.display.rotation() {
                         DXGI_MODE_ROTATION_IDENTITY | DXGI_MODE_ROTATION_UNSPECIFIED => kRotate0,
                         DXGI_MODE_ROTATION_ROTATE90 => kRotate90,
@@ -437,7 +508,17 @@ impl Capturer {
                         }
                     };
                     if rotate == kRotate0 {
-                        slice::from_raw_parts(r.0, r.1 as usize * self.height)
+                        let data = slice::from_raw_parts(r.0, r.1 as usize * self.height);
+                        // The cursor is not drawn on the rotated displays for now.
+                        if let Some(cursor) = self.cursor.as_ref() {
+                            // The mapped surface is read-only.
+                            self.cursor_buffer.clear();
+                            self.cursor_buffer.extend_from_slice(data);
+                            cursor.blit(&mut self.cursor_buffer, r.1 as _, self.width, self.height);
+                            &self.cursor_buffer[..]
+                        } else {
+                            data
+                        }
                     } else {
                         self.rotated.resize(self.width * self.height * 4, 0);
                         crate::common::ARGBRotate(
@@ -836,6 +917,56 @@ impl Display {
         )
     }
 
+    /// The luminance range if the display is in HDR10, `None` if it's SDR.
+    #[cfg(feature = "hdr")]
+    pub fn hdr_metadata(&self) -> Option<HdrMetadata> {
+        if self.gdi || self.inner.is_null() {
+            return None;
+        }
+        unsafe {
+            let mut output6: *mut IDXGIOutput6 = ptr::null_mut();
+            (*self.inner.0).QueryInterface(
+                &IDXGIOutput6::uuidof(),
+                &mut output6 as *mut *mut _ as *mut *mut _,
+            );
+            if output6.is_null() {
+                return None;
+            }
+            let output6 = ComPtr(output6);
+            let mut desc: DXGI_OUTPUT_DESC1 = mem::zeroed();
+            if (*output6.0).GetDesc1(&mut desc) != S_OK
+                || desc.ColorSpace != DXGI_COLOR_SPACE_RGB_FULL_G2084_NONE_P2020
+            {
+                return None;
+            }
+            Some(HdrMetadata {
+                max_luminance: desc.MaxLuminance,
+                min_luminance: desc.MinLuminance,
+                max_full_frame_luminance: desc.MaxFullFrameLuminance,
+            })
+        }
+    }
+
+    // Duplicate the display in scRGB FP16, which keeps the HDR colors.
+    #[cfg(feature = "hdr")]
+    unsafe fn duplicate_output_fp16(
+        &self,
+        device: *mut IUnknown,
+        duplication: *mut *mut IDXGIOutputDuplication,
+    ) -> HRESULT {
+        let mut output5: *mut IDXGIOutput5 = ptr::null_mut();
+        (*self.inner.0).QueryInterface(
+            &IDXGIOutput5::uuidof(),
+            &mut output5 as *mut *mut _ as *mut *mut _,
+        );
+        if output5.is_null() {
+            return E_NOINTERFACE;
+        }
+        let output5 = ComPtr(output5);
+        let formats = [DXGI_FORMAT_R16G16B16A16_FLOAT];
+        (*output5.0).DuplicateOutput1(device, 0, formats.len() as _, formats.as_ptr(), duplication)
+    }
+
     #[cfg(feature = "vram")]
     pub fn adapter_luid(&self) -> Option<i64> {
         unsafe {
@@ -864,7 +995,10 @@ fn wrap_hresult(x: HRESULT) -> io::Result<()> {
         DXGI_ERROR_UNSUPPORTED => ConnectionRefused,
         DXGI_ERROR_NOT_CURRENTLY_AVAILABLE => Interrupted,
         DXGI_ERROR_SESSION_DISCONNECTED => ConnectionAborted,
+        DXGI_ERROR_DEVICE_REMOVED | DXGI_ERROR_DEVICE_RESET => NotConnected,
         E_INVALIDARG => InvalidInput,
+        // The handles of the outputs may be invalid after a GPU driver reset.
+        E_HANDLE => return Err(io::Error::from_raw_os_error(ERROR_INVALID_HANDLE as _)),
         _ => {
             // 0x8000ffff https://www.auslogics.com/en/articles/windows-10-update-error-0x8000ffff-fixed/
             return Err(io::Error::new(Other, format!("Error code: {:#X}", x)));
@@ -3,6 +3,9 @@ use super::Display;
 use hbb_common::libc;
 use std::{io, ptr, slice};
 
+// The consecutive XShm failures before falling back to the plain `GetImage`.
+const SHM_FAILURES_BEFORE_FALLBACK: usize = 10;
+
 pub struct Capturer {
     display: Display,
     shmid: i32,
@@ -11,6 +14,10 @@ pub struct Capturer {
 
     size: usize,
     saved_raw_data: Vec<u8>, // for faster compare and copy
+    shm_failures: usize,
+    // The frames are copied through the X connection instead of the shared memory if set,
+    // like the gdi fallback of dxgi on Windows.
+    fallback_buffer: Option<Vec<u8>>,
 }
 
 impl Capturer {
@@ -64,6 +71,8 @@ impl Capturer {
             buffer,
             size,
             saved_raw_data: Vec::new(),
+            shm_failures: 0,
+            fallback_buffer: None,
         };
         Ok(c)
     }
@@ -72,7 +81,8 @@ impl Capturer {
         &self.display
     }
 
-    fn get_image(&self) {
+    // Returns false if the image is not written to the shared memory.
+    fn get_image(&self) -> bool {
         let rect = self.display.rect();
         unsafe {
             let request = xcb_shm_get_image_unchecked(
@@ -87,15 +97,76 @@ impl Capturer {
                 self.xcbid,
                 0,
             );
+            let mut error = ptr::null_mut();
             let response =
-                xcb_shm_get_image_reply(self.display.server().raw(), request, ptr::null_mut());
+                xcb_shm_get_image_reply(self.display.server().raw(), request, &mut error);
+            let ok = !response.is_null() && error.is_null();
+            libc::free(response as *mut _);
+            libc::free(error as *mut _);
+            ok
+        }
+    }
+
+    fn get_image_fallback(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let rect = self.display.rect();
+        unsafe {
+            let request = xcb_get_image_unchecked(
+                self.display.server().raw(),
+                XCB_IMAGE_FORMAT_Z_PIXMAP,
+                self.display.root(),
+                rect.x,
+                rect.y,
+                rect.w,
+                rect.h,
+                !0,
+            );
+            let mut error = ptr::null_mut();
+            let response = xcb_get_image_reply(self.display.server().raw(), request, &mut error);
+            libc::free(error as *mut _);
+            if response.is_null() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = xcb_get_image_data_length(response) as usize;
+            let res = if len == self.size {
+                buffer.clear();
+                buffer.extend_from_slice(slice::from_raw_parts(xcb_get_image_data(response), len));
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("unexpected image size {}, expected {}", len, self.size),
+                ))
+            };
             libc::free(response as *mut _);
+            res
         }
     }
 
     pub fn frame<'b>(&'b mut self) -> std::io::Result<&'b [u8]> {
-        self.get_image();
-        let result = unsafe { slice::from_raw_parts(self.buffer, self.size) };
+        if self.fallback_buffer.is_none() {
+            if self.get_image() {
+                self.shm_failures = 0;
+            } else {
+                self.shm_failures += 1;
+                if self.shm_failures < SHM_FAILURES_BEFORE_FALLBACK {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                hbb_common::log::warn!(
+                    "XShm capture failed {} times in a row, fall back to GetImage",
+                    self.shm_failures
+                );
+                self.fallback_buffer = Some(Vec::new());
+            }
+        }
+        let result = match self.fallback_buffer.take() {
+            Some(mut buffer) => {
+                let res = self.get_image_fallback(&mut buffer);
+                self.fallback_buffer = Some(buffer);
+                res?;
+                self.fallback_buffer.as_deref().unwrap_or_default()
+            }
+            None => unsafe { slice::from_raw_parts(self.buffer, self.size) },
+        };
         crate::would_block_if_equal(&mut self.saved_raw_data, result)?;
         Ok(result)
     }
@@ -48,6 +48,27 @@ extern "C" {
         e: *mut *mut xcb_generic_error_t,
     ) -> *mut xcb_shm_get_image_reply_t;
 
+    pub fn xcb_get_image_unchecked(
+        c: *mut xcb_connection_t,
+        format: u8,
+        drawable: xcb_drawable_t,
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+        plane_mask: u32,
+    ) -> xcb_get_image_cookie_t;
+
+    pub fn xcb_get_image_reply(
+        c: *mut xcb_connection_t,
+        cookie: xcb_get_image_cookie_t,
+        e: *mut *mut xcb_generic_error_t,
+    ) -> *mut xcb_get_image_reply_t;
+
+    pub fn xcb_get_image_data(r: *const xcb_get_image_reply_t) -> *mut u8;
+
+    pub fn xcb_get_image_data_length(r: *const xcb_get_image_reply_t) -> i32;
+
     pub fn xcb_randr_get_monitors_unchecked(
         c: *mut xcb_connection_t,
         window: xcb_window_t,
@@ -201,6 +222,12 @@ pub struct xcb_shm_get_image_cookie_t {
     pub sequence: u32,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct xcb_get_image_cookie_t {
+    pub sequence: u32,
+}
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct xcb_void_cookie_t {
@@ -236,6 +263,16 @@ pub struct xcb_shm_get_image_reply_t {
     pub size: u32,
 }
 
+#[repr(C)]
+pub struct xcb_get_image_reply_t {
+    pub response_type: u8,
+    pub depth: u8,
+    pub sequence: u16,
+    pub length: u32,
+    pub visual: xcb_visualid_t,
+    pub pad0: [u8; 20],
+}
+
 #[repr(C)]
 pub struct xcb_randr_get_monitors_reply_t {
     pub response_type: u8,
@@ -1859,7 +1859,7 @@ impl LoginConfigHandler {
                 }
                 quality
             };
-            msg.custom_image_quality = quality << 8;
+            msg.custom_image_quality = crate::common::quality_from_human_readable(quality as _, 0);
             #[cfg(feature = "flutter")]
             if let Some(custom_fps) = self.options.get("custom-fps") {
                 let mut custom_fps = custom_fps.parse().unwrap_or(30);
@@ -1998,12 +1998,11 @@ impl LoginConfigHandler {
     ///
     /// # Arguments
     ///
-    /// * `bitrate` - The given bitrate.
-    /// * `quantizer` - The given quantizer.
+    /// * `image_quality` - The percentage of the custom quality.
     pub fn save_custom_image_quality(&mut self, image_quality: i32) -> Message {
         let mut misc = Misc::new();
         misc.set_option(OptionMessage {
-            custom_image_quality: image_quality << 8,
+            custom_image_quality: crate::common::quality_from_human_readable(image_quality as _, 0),
             ..Default::default()
         });
         let mut msg_out = Message::new();
@@ -1720,6 +1720,9 @@ impl<T: InvokeUiSession> Remote<T> {
                     }
                 }
                 Some(message::Union::PeerInfo(pi)) => {
+                    if let Some(p) = self.handler.lc.write().unwrap().peer_info.as_mut() {
+                        p.displays = pi.displays.clone();
+                    }
                     self.handler.set_displays(&pi.displays);
                     self.handler.set_platform_additions(&pi.platform_additions);
                 }
@@ -615,6 +615,24 @@ pub async fn get_nat_type(ms_timeout: u64) -> i32 {
     crate::ipc::get_nat_type(ms_timeout).await
 }
 
+/// Update the latency of the rendezvous server `host` measured by a register response,
+/// in microseconds. 0 means no data yet, e.g. no register request sent, the old value is kept.
+/// A negative value means disconnected, the latency is removed.
+pub fn update_test_latency(host: &str, latency: i64) {
+    match latency {
+        0 => {}
+        l if l < 0 => {
+            log::debug!(
+                "Rendezvous server {} disconnected, remove its latency",
+                host
+            );
+            // `Config::update_latency()` removes the latency if it's not positive.
+            Config::update_latency(host, -1);
+        }
+        l => Config::update_latency(host, l),
+    }
+}
+
 // used for client to test which server is faster in case stop-servic=Y
 #[tokio::main(flavor = "current_thread")]
 async fn test_rendezvous_server_() {
@@ -634,9 +652,9 @@ async fn test_rendezvous_server_() {
             .is_ok()
             {
                 let elapsed = tm.elapsed().as_micros();
-                Config::update_latency(&host, elapsed as _);
+                update_test_latency(&host, elapsed as _);
             } else {
-                Config::update_latency(&host, -1);
+                update_test_latency(&host, -1);
             }
         }));
     }
@@ -1025,6 +1043,169 @@ pub fn make_privacy_mode_msg(
     make_privacy_mode_msg_with_details(state, "".to_owned(), impl_key)
 }
 
+/// The unpacked `image_quality` / `custom_image_quality` of `OptionMessage`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityParams {
+    /// The percentage of the custom quality, 10 ~ 100, or up to 2000 if more quality is allowed.
+    /// 0 for the preset qualities.
+    pub bitrate_pct: u16,
+    /// The low byte, it was the quantizer of the old clients and is ignored now.
+    pub quantizer: u8,
+    pub label: &'static str,
+}
+
+// 12 bits for the percentage, the same as the server side before.
+const QUALITY_PCT_MASK: i32 = 0xFFF;
+// The custom percentage is up to 100, or 2000 with "more quality" checked on the client.
+const QUALITY_PCT_MAX: u16 = 2000;
+const QUALITY_QUANTIZER_MAX: u8 = 63;
+
+impl QualityParams {
+    /// Reject the custom values out of range, e.g. sent by a client of a mismatched version.
+    pub fn validate(&self) -> ResultType<()> {
+        if self.label != "custom" {
+            return Ok(());
+        }
+        if !(1..=QUALITY_PCT_MAX).contains(&self.bitrate_pct) {
+            bail!(
+                "Invalid image quality percentage {}, expected 1 - {}",
+                self.bitrate_pct,
+                QUALITY_PCT_MAX
+            );
+        }
+        if self.quantizer > QUALITY_QUANTIZER_MAX {
+            bail!(
+                "Invalid image quality quantizer {}, expected 0 - {}",
+                self.quantizer,
+                QUALITY_QUANTIZER_MAX
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The image quality value sent by the client, the presets of `ImageQuality` or the custom
+/// values packed in the same `i32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageQualityKind {
+    NotSet,
+    Low,
+    Balanced,
+    Best,
+    /// `bitrate_pct` is u16, it's up to 2000 with "more quality".
+    Custom {
+        bitrate_pct: u16,
+        quantizer: u8,
+    },
+}
+
+impl ImageQualityKind {
+    pub fn from_value(q: i32) -> Self {
+        if q == ImageQuality::Balanced.value() {
+            Self::Balanced
+        } else if q == ImageQuality::Low.value() {
+            Self::Low
+        } else if q == ImageQuality::Best.value() {
+            Self::Best
+        } else if let Some((bitrate_pct, quantizer)) = to_custom(q) {
+            Self::Custom {
+                bitrate_pct,
+                quantizer,
+            }
+        } else {
+            Self::NotSet
+        }
+    }
+
+    pub fn value(&self) -> i32 {
+        match self {
+            Self::NotSet => ImageQuality::NotSet.value(),
+            Self::Low => ImageQuality::Low.value(),
+            Self::Balanced => ImageQuality::Balanced.value(),
+            Self::Best => ImageQuality::Best.value(),
+            Self::Custom {
+                bitrate_pct,
+                quantizer,
+            } => from_custom(*bitrate_pct, *quantizer),
+        }
+    }
+}
+
+/// Pack the custom image quality, bits 8..20 are the percentage, the low byte is the quantizer.
+#[inline]
+pub fn from_custom(bitrate_pct: u16, quantizer: u8) -> i32 {
+    (bitrate_pct as i32 & QUALITY_PCT_MASK) << 8 | quantizer as i32
+}
+
+/// Unpack the custom image quality, `None` for the presets and the values not set.
+pub fn to_custom(q: i32) -> Option<(u16, u8)> {
+    let is_preset = [
+        ImageQuality::Low,
+        ImageQuality::Balanced,
+        ImageQuality::Best,
+    ]
+    .iter()
+    .any(|p| p.value() == q);
+    if q <= 0 || is_preset {
+        return None;
+    }
+    Some(((q >> 8 & QUALITY_PCT_MASK) as u16, (q & 0xFF) as u8))
+}
+
+/// Unpack the image quality value sent by the client.
+pub fn quality_to_human_readable(q: i32) -> QualityParams {
+    let preset = |label| QualityParams {
+        bitrate_pct: 0,
+        quantizer: 0,
+        label,
+    };
+    match ImageQualityKind::from_value(q) {
+        ImageQualityKind::NotSet => preset("not_set"),
+        ImageQualityKind::Low => preset("low"),
+        ImageQualityKind::Balanced => preset("balanced"),
+        ImageQualityKind::Best => preset("best"),
+        ImageQualityKind::Custom {
+            bitrate_pct,
+            quantizer,
+        } => QualityParams {
+            bitrate_pct,
+            quantizer,
+            label: "custom",
+        },
+    }
+}
+
+/// The canonical way to pack the custom image quality, the inverse of `quality_to_human_readable`.
+#[inline]
+pub fn quality_from_human_readable(bitrate_pct: u16, quantizer: u8) -> i32 {
+    from_custom(bitrate_pct, quantizer)
+}
+
+/// Find the index of the display by its name, an exact match is preferred over a substring match.
+/// The names of the displays change less than the indexes when the monitors are plugged or unplugged.
+pub fn find_display_by_name<S: AsRef<str>>(names: &[S], name: &str) -> ResultType<usize> {
+    if let Some(i) = names.iter().position(|n| n.as_ref() == name) {
+        return Ok(i);
+    }
+    let lower = name.to_lowercase();
+    let matched: Vec<usize> = (0..names.len())
+        .filter(|i| names[*i].as_ref().to_lowercase().contains(&lower))
+        .collect();
+    match matched.len() {
+        0 => bail!("No display matches \"{}\"", name),
+        1 => Ok(matched[0]),
+        _ => bail!(
+            "Ambiguous display name \"{}\", matches: {}",
+            name,
+            matched
+                .iter()
+                .map(|i| names[*i].as_ref())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
 pub fn is_keyboard_mode_supported(
     keyboard_mode: &KeyboardMode,
     version_number: i64,
@@ -1559,6 +1740,19 @@ mod tests {
     };
     use std::collections::HashSet;
 
+    #[test]
+    fn test_find_display_by_name() {
+        let names = ["\\\\.\\DISPLAY1", "\\\\.\\DISPLAY10", "HDMI-1", "eDP-1"];
+        assert_eq!(find_display_by_name(&names, "\\\\.\\DISPLAY1").unwrap(), 0);
+        assert_eq!(find_display_by_name(&names, "display10").unwrap(), 1);
+        assert_eq!(find_display_by_name(&names, "hdmi").unwrap(), 2);
+        let err = find_display_by_name(&names, "DISPLAY")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("DISPLAY1") && err.contains("DISPLAY10"));
+        assert!(find_display_by_name(&names, "DP-2").is_err());
+    }
+
     #[inline]
     fn get_timestamp_secs() -> u128 {
         (std::time::SystemTime::UNIX_EPOCH
@@ -1690,6 +1884,71 @@ mod tests {
             Duration::from_nanos(0)
         );
     }
+
+    #[test]
+    fn test_quality_human_readable() {
+        for (pct, quantizer) in [(10, 0), (50, 0), (100, 0), (2000, 0), (56, 36)] {
+            let q = quality_from_human_readable(pct, quantizer);
+            let params = quality_to_human_readable(q);
+            assert_eq!(params.bitrate_pct, pct);
+            assert_eq!(params.quantizer, quantizer);
+            assert_eq!(params.label, "custom");
+        }
+        assert_eq!(quality_from_human_readable(50, 0), 50 << 8);
+        assert_eq!(
+            quality_to_human_readable(ImageQuality::Best.value()).label,
+            "best"
+        );
+        assert_eq!(quality_to_human_readable(-1).label, "not_set");
+        assert!(quality_to_human_readable(50 << 8 | 63).validate().is_ok());
+        assert!(quality_to_human_readable(2001 << 8).validate().is_err());
+        assert!(quality_to_human_readable(50 << 8 | 64).validate().is_err());
+        // The quantizer only.
+        assert!(quality_to_human_readable(36).validate().is_err());
+    }
+
+    #[test]
+    fn test_image_quality_kind() {
+        for pct in [
+            1,
+            10,
+            50,
+            100,
+            255,
+            256,
+            1000,
+            2000,
+            QUALITY_PCT_MASK as u16,
+        ] {
+            for quantizer in [0, 1, 36, 63, 64, 255] {
+                let q = from_custom(pct, quantizer);
+                assert_eq!(to_custom(q), Some((pct, quantizer)));
+                let kind = ImageQualityKind::from_value(q);
+                assert_eq!(
+                    kind,
+                    ImageQualityKind::Custom {
+                        bitrate_pct: pct,
+                        quantizer
+                    }
+                );
+                assert_eq!(kind.value(), q);
+            }
+        }
+        // The bits over the mask are dropped.
+        assert_eq!(to_custom(from_custom(0x1000 | 50, 0)), Some((50, 0)));
+        for (q, kind) in [
+            (ImageQuality::Low, ImageQualityKind::Low),
+            (ImageQuality::Balanced, ImageQualityKind::Balanced),
+            (ImageQuality::Best, ImageQualityKind::Best),
+            (ImageQuality::NotSet, ImageQualityKind::NotSet),
+        ] {
+            assert_eq!(to_custom(q.value()), None);
+            assert_eq!(ImageQualityKind::from_value(q.value()), kind);
+            assert_eq!(kind.value(), q.value());
+        }
+        assert_eq!(to_custom(-1), None);
+        assert_eq!(ImageQualityKind::from_value(-1), ImageQualityKind::NotSet);
+    }
 }
 
 #[inline]
@@ -1489,3 +1489,38 @@ pub fn is_selinux_enforcing() -> bool {
         },
     }
 }
+
+/// Set the nice value of the current thread only, -20 (the highest priority) to 19.
+/// Lowering it below the current value needs root or `CAP_SYS_NICE`.
+pub fn set_current_thread_nice(nice: i32) -> ResultType<()> {
+    unsafe {
+        let tid = hbb_common::libc::syscall(hbb_common::libc::SYS_gettid) as hbb_common::libc::id_t;
+        if hbb_common::libc::setpriority(hbb_common::libc::PRIO_PROCESS, tid, nice) != 0 {
+            bail!("setpriority failed: {}", std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Run the current thread only on the `cpus`, the threads it creates later inherit them.
+pub fn set_current_thread_affinity(cpus: &[usize]) -> ResultType<()> {
+    unsafe {
+        let mut set: hbb_common::libc::cpu_set_t = std::mem::zeroed();
+        hbb_common::libc::CPU_ZERO(&mut set);
+        for cpu in cpus {
+            hbb_common::libc::CPU_SET(*cpu, &mut set);
+        }
+        let res = hbb_common::libc::pthread_setaffinity_np(
+            hbb_common::libc::pthread_self(),
+            std::mem::size_of::<hbb_common::libc::cpu_set_t>(),
+            &set,
+        );
+        if res != 0 {
+            bail!(
+                "pthread_setaffinity_np failed: {}",
+                std::io::Error::from_raw_os_error(res)
+            );
+        }
+    }
+    Ok(())
+}
@@ -2669,3 +2669,38 @@ pub mod reg_display_settings {
         }
     }
 }
+
+/// Set the priority of the current thread, one of `THREAD_PRIORITY_*`.
+pub fn set_current_thread_priority(priority: i32) -> ResultType<()> {
+    use winapi::um::processthreadsapi::{GetCurrentThread, SetThreadPriority};
+    unsafe {
+        if SetThreadPriority(GetCurrentThread(), priority) == 0 {
+            bail!(
+                "SetThreadPriority failed: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Run the current thread only on the `cpus` of the processor group, at most 64.
+/// The threads it creates later are not affected.
+pub fn set_current_thread_affinity(cpus: &[usize]) -> ResultType<()> {
+    let mut mask: usize = 0;
+    for cpu in cpus {
+        if *cpu >= usize::BITS as usize {
+            bail!("cpu {} is out of the processor group", cpu);
+        }
+        mask |= 1 << cpu;
+    }
+    unsafe {
+        if SetThreadAffinityMask(winapi::um::processthreadsapi::GetCurrentThread(), mask) == 0 {
+            bail!(
+                "SetThreadAffinityMask failed: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
@@ -14,7 +14,7 @@ use hbb_common::{
 };
 use serde_derive::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
 };
 
@@ -124,6 +124,11 @@ lazy_static::lazy_static! {
         };
         Arc::new(Mutex::new(privacy_mode))
     };
+
+    // All the connections holding the privacy mode.
+    // The implementation only knows the first one, the others join the privacy mode
+    // which is already on, and it is turned off when the last one leaves.
+    static ref PRIVACY_MODE_CONN_IDS: Mutex<HashSet<i32>> = Default::default();
 }
 
 pub type PrivacyModeCreator = fn(impl_key: &str) -> Box<dyn PrivacyMode>;
@@ -160,6 +165,7 @@ pub fn init() -> Option<ResultType<()>> {
 
 #[inline]
 pub fn clear() -> Option<()> {
+    PRIVACY_MODE_CONN_IDS.lock().unwrap().clear();
     Some(PRIVACY_MODE.lock().unwrap().as_mut()?.clear())
 }
 
@@ -174,6 +180,7 @@ pub fn switch(impl_key: &str) {
 
     if let Some(creator) = PRIVACY_MODE_CREATOR.lock().unwrap().get(impl_key) {
         *privacy_mode_lock = Some(creator(impl_key));
+        PRIVACY_MODE_CONN_IDS.lock().unwrap().clear();
     }
 }
 
@@ -237,20 +244,36 @@ fn turn_on_privacy_sync(impl_key: &str, conn_id: i32) -> Option<ResultType<bool>
     // Check or switch privacy mode implementation
     let impl_key = get_supported_impl(impl_key);
 
+    let mut conn_ids = PRIVACY_MODE_CONN_IDS.lock().unwrap();
     let mut cur_impl_key = "".to_string();
     if let Some(privacy_mode) = privacy_mode_lock.as_ref() {
         cur_impl_key = privacy_mode.get_impl_key().to_string();
+        if privacy_mode.pre_conn_id() == INVALID_PRIVACY_MODE_CONN_ID {
+            // Turned off by the implementation itself, eg. `OffByPeer`.
+            conn_ids.clear();
+        }
         let check_on_conn_id = privacy_mode.check_on_conn_id(conn_id);
         match check_on_conn_id.as_ref() {
             Ok(true) => {
                 if cur_impl_key == impl_key {
                     // Same peer, same implementation.
                     return Some(Ok(true));
+                } else if conn_ids.len() > 1 {
+                    // Others are holding the current implementation.
+                    return Some(Err(anyhow!(OCCUPIED)));
                 } else {
                     // Same peer, switch to new implementation.
                 }
             }
-            Err(_) => return Some(check_on_conn_id),
+            Err(_) => {
+                if cur_impl_key == impl_key || conn_ids.contains(&conn_id) {
+                    // Join the privacy mode which is already on.
+                    // The implementation can't be switched while others are holding it.
+                    conn_ids.insert(conn_id);
+                    return Some(Ok(true));
+                }
+                return Some(check_on_conn_id);
+            }
             _ => {}
         }
     }
@@ -272,18 +295,40 @@ fn turn_on_privacy_sync(impl_key: &str, conn_id: i32) -> Option<ResultType<bool>
     }
 
     // turn on privacy mode
-    Some(privacy_mode_lock.as_mut()?.turn_on_privacy(conn_id))
+    let res = privacy_mode_lock.as_mut()?.turn_on_privacy(conn_id);
+    if matches!(res, Ok(true)) {
+        conn_ids.clear();
+        conn_ids.insert(conn_id);
+    }
+    Some(res)
 }
 
+/// Turn off the privacy mode held by `conn_id`.
+/// The privacy mode is kept on if other connections are still holding it.
+/// `INVALID_PRIVACY_MODE_CONN_ID` turns it off for all the holders.
 #[inline]
 pub fn turn_off_privacy(conn_id: i32, state: Option<PrivacyModeState>) -> Option<ResultType<()>> {
-    Some(
-        PRIVACY_MODE
-            .lock()
-            .unwrap()
-            .as_mut()?
-            .turn_off_privacy(conn_id, state),
-    )
+    let mut privacy_mode_lock = PRIVACY_MODE.lock().unwrap();
+    let privacy_mode = privacy_mode_lock.as_mut()?;
+    let mut conn_ids = PRIVACY_MODE_CONN_IDS.lock().unwrap();
+    let pre_conn_id = privacy_mode.pre_conn_id();
+    if pre_conn_id == INVALID_PRIVACY_MODE_CONN_ID || conn_id == INVALID_PRIVACY_MODE_CONN_ID {
+        conn_ids.clear();
+        return Some(privacy_mode.turn_off_privacy(conn_id, state));
+    }
+    if !conn_ids.remove(&conn_id) && conn_id != pre_conn_id {
+        return Some(Err(anyhow!(TURN_OFF_OTHER_ID)));
+    }
+    if !conn_ids.is_empty() {
+        log::info!(
+            "Privacy mode is still held by {:?} after {} left",
+            conn_ids,
+            conn_id
+        );
+        return Some(Ok(()));
+    }
+    // The implementation only knows the first holder, which may have left already.
+    Some(privacy_mode.turn_off_privacy(pre_conn_id, state))
 }
 
 #[inline]
@@ -385,6 +430,9 @@ pub fn check_privacy_mode_err(
     }
 }
 
+// Only Windows has an implementation. The privacy mode must hide the screen from the local
+// user but not from the capturer. On X11 the capturer reads the composited root window, a
+// black overlay window hides the screen from both, and hiding only the cursor hides nothing.
 #[inline]
 pub fn is_privacy_mode_supported() -> bool {
     !DEFAULT_PRIVACY_MODE_IMPL.is_empty()
@@ -399,6 +447,24 @@ pub fn get_privacy_mode_conn_id() -> Option<i32> {
         .map(|pm| pm.pre_conn_id())
 }
 
+/// All the connections holding the privacy mode, empty if it is off.
+pub fn get_privacy_mode_conn_ids() -> HashSet<i32> {
+    let privacy_mode_lock = PRIVACY_MODE.lock().unwrap();
+    let pre_conn_id = privacy_mode_lock
+        .as_ref()
+        .map(|pm| pm.pre_conn_id())
+        .unwrap_or(INVALID_PRIVACY_MODE_CONN_ID);
+    if pre_conn_id == INVALID_PRIVACY_MODE_CONN_ID {
+        return HashSet::new();
+    }
+    let conn_ids = PRIVACY_MODE_CONN_IDS.lock().unwrap();
+    if conn_ids.is_empty() {
+        HashSet::from([pre_conn_id])
+    } else {
+        conn_ids.clone()
+    }
+}
+
 #[inline]
 pub fn is_in_privacy_mode() -> bool {
     PRIVACY_MODE
@@ -16,7 +16,7 @@ pub fn create_capturer(
         return Ok(None);
     }
 
-    match scrap::CapturerMag::new(origin, width, height) {
+    match new_capturer_mag(origin, width, height) {
         Ok(mut c1) => {
             let mut ok = false;
             let check_begin = Instant::now();
@@ -55,3 +55,30 @@ pub fn create_capturer(
         }
     }
 }
+
+// MagInitialize may fail for a while, e.g. while the DWM restarts after a driver update.
+// Only retry if the magnifier is not supported now, the other errors are permanent.
+fn new_capturer_mag(
+    origin: (i32, i32),
+    width: usize,
+    height: usize,
+) -> std::io::Result<scrap::CapturerMag> {
+    const RETRIES: usize = 3;
+    let mut res = scrap::CapturerMag::new(origin, width, height);
+    for i in 0..RETRIES {
+        match &res {
+            Err(e) if !scrap::CapturerMag::is_supported() => {
+                log::warn!(
+                    "Magnifier is not supported now, retry {}/{}: {}",
+                    i + 1,
+                    RETRIES,
+                    e
+                );
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                res = scrap::CapturerMag::new(origin, width, height);
+            }
+            _ => break,
+        }
+    }
+    res
+}
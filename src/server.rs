@@ -60,13 +60,21 @@ pub mod input_service {
     pub const NAME_WINDOW_FOCUS: &'static str = "";
 }
 
+pub mod annotation;
 mod connection;
+mod crossfade;
+pub mod display_hotplug;
 pub mod display_service;
+pub mod frame_encryption;
+#[cfg(feature = "tracing_frames")]
+pub mod frame_timing;
 #[cfg(windows)]
 pub mod portable_service;
 mod service;
+pub mod video_metrics;
 mod video_qos;
 pub mod video_service;
+mod watermark;
 
 pub type Childs = Arc<Mutex<Vec<std::process::Child>>>;
 type ConnMap = HashMap<i32, ConnInner>;
@@ -0,0 +1,164 @@
+use super::*;
+use scrap::{EncodeYuvFormat, Pixfmt};
+use std::time::{Duration, Instant};
+
+// Half of the line width in pixels.
+const PEN_RADIUS: i32 = 1;
+// Length of the arrow head sides in pixels.
+const ARROW_HEAD_LEN: f32 = 16.0;
+
+lazy_static::lazy_static! {
+    static ref ANNOTATIONS: Mutex<AnnotationLayer> = Default::default();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShapeKind {
+    Arrow { from: (i32, i32), to: (i32, i32) },
+    Rectangle { x: i32, y: i32, w: i32, h: i32 },
+    Freehand { points: Vec<(i32, i32)> },
+}
+
+/// A shape drawn on the frames of a display, in pixels of the captured frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shape {
+    pub display: usize,
+    pub kind: ShapeKind,
+    pub color: [u8; 3], // rgb
+    pub ttl: Duration,
+}
+
+#[derive(Default)]
+struct AnnotationLayer {
+    shapes: Vec<(Shape, Instant)>, // (shape, expire time)
+}
+
+impl AnnotationLayer {
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        self.shapes.retain(|(_, expire)| *expire > now);
+    }
+}
+
+/// The shape is drawn on the captured frames until its ttl expires.
+/// The frames are only captured on screen changes on some platforms,
+/// so the shape may show up or disappear with the next screen change.
+pub fn push_annotation(shape: Shape) {
+    let expire = Instant::now() + shape.ttl;
+    ANNOTATIONS.lock().unwrap().shapes.push((shape, expire));
+}
+
+pub fn clear_annotations() {
+    ANNOTATIONS.lock().unwrap().shapes.clear();
+}
+
+// Draw the annotations of the display on the yuv frame, return true if anything is drawn.
+pub(super) fn composite(display: usize, yuv: &mut [u8], fmt: &EncodeYuvFormat) -> bool {
+    let mut layer = ANNOTATIONS.lock().unwrap();
+    layer.remove_expired();
+    let mut canvas = YuvCanvas { yuv, fmt };
+    let mut drawn = false;
+    for (shape, _) in layer.shapes.iter().filter(|(s, _)| s.display == display) {
+        let color = rgb_to_yuv(shape.color);
+        match &shape.kind {
+            ShapeKind::Arrow { from, to } => {
+                canvas.line(*from, *to, color);
+                let angle = ((from.1 - to.1) as f32).atan2((from.0 - to.0) as f32);
+                for side in [-0.5f32, 0.5] {
+                    let a = angle + side;
+                    let end = (
+                        to.0 + (ARROW_HEAD_LEN * a.cos()) as i32,
+                        to.1 + (ARROW_HEAD_LEN * a.sin()) as i32,
+                    );
+                    canvas.line(*to, end, color);
+                }
+            }
+            ShapeKind::Rectangle { x, y, w, h } => {
+                let (x1, y1) = (x + w, y + h);
+                canvas.line((*x, *y), (x1, *y), color);
+                canvas.line((x1, *y), (x1, y1), color);
+                canvas.line((x1, y1), (*x, y1), color);
+                canvas.line((*x, y1), (*x, *y), color);
+            }
+            ShapeKind::Freehand { points } => {
+                for p in points.windows(2) {
+                    canvas.line(p[0], p[1], color);
+                }
+            }
+        }
+        drawn = true;
+    }
+    drawn
+}
+
+// BT.601 limited range, the same as the capture conversion.
+fn rgb_to_yuv(rgb: [u8; 3]) -> [u8; 3] {
+    let (r, g, b) = (rgb[0] as i32, rgb[1] as i32, rgb[2] as i32);
+    let y = 16 + ((66 * r + 129 * g + 25 * b + 128) >> 8);
+    let u = 128 + ((-38 * r - 74 * g + 112 * b + 128) >> 8);
+    let v = 128 + ((112 * r - 94 * g - 18 * b + 128) >> 8);
+    [y as u8, u.clamp(0, 255) as u8, v.clamp(0, 255) as u8]
+}
+
+struct YuvCanvas<'a> {
+    yuv: &'a mut [u8],
+    fmt: &'a EncodeYuvFormat,
+}
+
+impl YuvCanvas<'_> {
+    fn set(&mut self, x: i32, y: i32, color: [u8; 3]) {
+        let fmt = self.fmt;
+        if x < 0 || y < 0 || x as usize >= fmt.w || y as usize >= fmt.h {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        let mut put = |i: usize, c: u8| {
+            if let Some(p) = self.yuv.get_mut(i) {
+                *p = c;
+            }
+        };
+        put(y * fmt.stride[0] + x, color[0]);
+        match fmt.pixfmt {
+            Pixfmt::I420 => {
+                put(fmt.u + y / 2 * fmt.stride[1] + x / 2, color[1]);
+                put(fmt.v + y / 2 * fmt.stride[2] + x / 2, color[2]);
+            }
+            Pixfmt::NV12 => {
+                let i = fmt.u + y / 2 * fmt.stride[1] + x / 2 * 2;
+                put(i, color[1]);
+                put(i + 1, color[2]);
+            }
+            Pixfmt::I444 => {
+                put(fmt.u + y * fmt.stride[1] + x, color[1]);
+                put(fmt.v + y * fmt.stride[2] + x, color[2]);
+            }
+            _ => {}
+        }
+    }
+
+    // Bresenham's line, with a square pen.
+    fn line(&mut self, from: (i32, i32), to: (i32, i32), color: [u8; 3]) {
+        let (mut x, mut y) = from;
+        let (dx, dy) = ((to.0 - x).abs(), -(to.1 - y).abs());
+        let (sx, sy) = ((to.0 - x).signum(), (to.1 - y).signum());
+        let mut err = dx + dy;
+        loop {
+            for py in -PEN_RADIUS..=PEN_RADIUS {
+                for px in -PEN_RADIUS..=PEN_RADIUS {
+                    self.set(x + px, y + py, color);
+                }
+            }
+            if (x, y) == to {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+}
@@ -147,6 +147,13 @@ pub struct SessionKey {
     session_id: u64,
 }
 
+impl SessionKey {
+    #[inline]
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+}
+
 #[derive(Clone, Debug)]
 struct Session {
     last_recv_time: Arc<Mutex<Instant>>,
@@ -232,6 +239,7 @@ pub struct Connection {
     start_cm_ipc_para: Option<StartCmIpcPara>,
     auto_disconnect_timer: Option<(Instant, u64)>,
     authed_conn_id: Option<self::raii::AuthedConnID>,
+    video_session_token: u64, // sent with the frame acks
     file_remove_log_control: FileRemoveLogControl,
     last_supported_encoding: Option<SupportedEncoding>,
     services_subed: bool,
@@ -256,7 +264,7 @@ impl Subscriber for ConnInner {
     }
 
     #[inline]
-    fn send(&mut self, msg: Arc<Message>) {
+    fn send(&mut self, msg: Arc<Message>) -> bool {
         // Send SwitchDisplay on the same channel as VideoFrame to avoid send order problems.
         let tx_by_video = match &msg.union {
             Some(message::Union::VideoFrame(_)) => true,
@@ -271,9 +279,14 @@ impl Subscriber for ConnInner {
         } else {
             self.tx.as_mut()
         };
-        tx.map(|tx| {
-            allow_err!(tx.send((Instant::now(), msg)));
-        });
+        let Some(tx) = tx else {
+            return false;
+        };
+        if let Err(e) = tx.send((Instant::now(), msg)) {
+            log::debug!("Failed to send to connection {}: {}", self.id, e);
+            return false;
+        }
+        true
     }
 }
 
@@ -323,6 +336,8 @@ impl Connection {
                 tx_video: Some(tx_video),
             },
             require_2fa: crate::auth_2fa::get_2fa(None),
+            // Every connection starts on the primary display and keeps its own display,
+            // it is never reset to the primary one during the session, e.g. after some idle time.
             display_idx: *display_service::PRIMARY_DISPLAY_IDX,
             stream,
             server,
@@ -384,6 +399,7 @@ impl Connection {
             }),
             auto_disconnect_timer: None,
             authed_conn_id: None,
+            video_session_token: video_service::new_session_token(id),
             file_remove_log_control: FileRemoveLogControl::new(id),
             last_supported_encoding: None,
             services_subed: false,
@@ -394,6 +410,7 @@ impl Connection {
         let addr = hbb_common::try_into_v4(addr);
         if !conn.on_open(addr).await {
             conn.closed = true;
+            video_service::release_session_token(id, conn.video_session_token);
             // sleep to ensure msg got received.
             sleep(1.).await;
             return;
@@ -509,6 +526,7 @@ impl Connection {
                                             super::audio_service::NAME,
                                             conn.inner.clone(), conn.audio_enabled());
                                     }
+                                    conn.update_audio_qos();
                                 }
                             } else if &name == "file" {
                                 conn.file = enabled;
@@ -636,7 +654,11 @@ impl Connection {
                 }
                 Some((instant, value)) = rx_video.recv() => {
                     if !conn.video_ack_required {
-                        video_service::notify_video_frame_fetched(id, Some(instant.into()));
+                        video_service::notify_video_frame_fetched(
+                            id,
+                            conn.video_session_token,
+                            Some(instant.into()),
+                        );
                     }
                     if let Err(err) = conn.stream.send(&value as &Message).await {
                         conn.on_close(&err.to_string(), false).await;
@@ -743,10 +765,8 @@ impl Connection {
             }
         }
 
-        if let Some(video_privacy_conn_id) = privacy_mode::get_privacy_mode_conn_id() {
-            if video_privacy_conn_id == id {
-                let _ = Self::turn_off_privacy_to_msg(id);
-            }
+        if privacy_mode::get_privacy_mode_conn_ids().contains(&id) {
+            let _ = Self::turn_off_privacy_to_msg(id);
         }
         #[cfg(all(feature = "flutter", feature = "plugin_framework"))]
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -754,7 +774,8 @@ impl Connection {
             crate::plugin::EVENT_ON_CONN_CLOSE_SERVER.to_owned(),
             conn.lr.my_id.clone(),
         );
-        video_service::notify_video_frame_fetched(id, None);
+        video_service::notify_video_frame_fetched(id, conn.video_session_token, None);
+        video_service::release_session_token(id, conn.video_session_token);
         if conn.authorized {
             password::update_temporary_password();
         }
@@ -1385,6 +1406,7 @@ impl Connection {
                 s.try_add_primay_video_service();
                 s.add_connection(self.inner.clone(), &noperms);
             }
+            self.update_audio_qos();
         }
     }
 
@@ -1455,6 +1477,14 @@ impl Connection {
         self.audio && !self.disable_audio
     }
 
+    // The audio bitrate is taken out of the video bitrate budget.
+    fn update_audio_qos(&self) {
+        video_service::VIDEO_QOS
+            .lock()
+            .unwrap()
+            .user_audio(self.inner.id(), self.audio_enabled());
+    }
+
     #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
     fn file_transfer_enabled(&self) -> bool {
         self.file && self.enable_file_transfer
@@ -2384,6 +2414,7 @@ impl Connection {
                     Some(misc::Union::VideoReceived(_)) => {
                         video_service::notify_video_frame_fetched(
                             self.inner.id,
+                            self.video_session_token,
                             Some(Instant::now().into()),
                         );
                     }
@@ -2846,7 +2877,8 @@ impl Connection {
                 video_service::VIDEO_QOS
                     .lock()
                     .unwrap()
-                    .user_image_quality(self.inner.id(), image_quality);
+                    .user_image_quality(self.inner.id(), image_quality)
+                    .ok();
             }
         }
         if o.custom_fps > 0 {
@@ -2909,6 +2941,7 @@ impl Connection {
                         self.audio_enabled(),
                     );
                 }
+                self.update_audio_qos();
             }
         }
         #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
@@ -3204,7 +3237,7 @@ impl Connection {
                 msg.set_misc(misc);
                 self.inner.send(msg.into());
             }
-            let uac = crate::video_service::IS_UAC_RUNNING.lock().unwrap().clone();
+            let uac = crate::video_service::IS_UAC_RUNNING.load(Ordering::Relaxed);
             if p.last_uac != uac {
                 p.last_uac = uac;
                 if !uac || !running {
@@ -3215,10 +3248,8 @@ impl Connection {
                     self.inner.send(msg.into());
                 }
             }
-            let foreground_window_elevated = crate::video_service::IS_FOREGROUND_WINDOW_ELEVATED
-                .lock()
-                .unwrap()
-                .clone();
+            let foreground_window_elevated =
+                crate::video_service::IS_FOREGROUND_WINDOW_ELEVATED.load(Ordering::Relaxed);
             if p.last_foreground_window_elevated != foreground_window_elevated {
                 p.last_foreground_window_elevated = foreground_window_elevated;
                 if !foreground_window_elevated || !running {
@@ -3824,6 +3855,7 @@ mod raii {
 
     impl AuthedConnID {
         pub fn new(conn_id: i32, conn_type: AuthConnType, session_key: SessionKey) -> Self {
+            let peer_id = session_key.peer_id.clone();
             AUTHED_CONNS
                 .lock()
                 .unwrap()
@@ -3835,10 +3867,9 @@ mod raii {
                 shutdown_hooks::add_shutdown_hook(connection_shutdown_hook);
             });
             if conn_type == AuthConnType::Remote {
-                video_service::VIDEO_QOS
-                    .lock()
-                    .unwrap()
-                    .on_connection_open(conn_id);
+                let mut video_qos = video_service::VIDEO_QOS.lock().unwrap();
+                video_qos.on_connection_open(conn_id);
+                video_qos.restore_user_preferences(conn_id, &peer_id);
             }
             Self(conn_id, conn_type)
         }
@@ -3951,6 +3982,8 @@ mod raii {
                     .lock()
                     .unwrap()
                     .on_connection_close(self.0);
+                video_service::resume_capture(self.0);
+                video_service::on_connection_close(self.0);
             }
             AUTHED_CONNS.lock().unwrap().retain(|c| c.0 != self.0);
             let remote_count = AUTHED_CONNS
@@ -0,0 +1,146 @@
+// Crossfade the frames on the display switches, instead of jumping from one display to another.
+//
+// The video service of the outgoing display keeps its last frame when it stops, the video
+// service of the new display blends it into its first frames. The blended frames are encoded
+// as normal frames, the peers need no changes.
+//
+// The outgoing frame is only looked for on the first frame, blending it into the later frames
+// would flash back to the outgoing display. Usually the outgoing service has stopped by then,
+// the new service takes longer to create its capturer and encoder.
+// Only the yuv frames are blended, not the texture frames of the hardware encoders.
+
+use super::*;
+use scrap::EncodeYuvFormat;
+use std::time::{Duration, Instant};
+
+// The outgoing frame older than this is not from a display switch.
+const OUTGOING_FRAME_MAX_AGE: Duration = Duration::from_secs(1);
+
+lazy_static::lazy_static! {
+    static ref OUTGOING_FRAME: Mutex<Option<OutgoingFrame>> = Default::default();
+}
+
+struct OutgoingFrame {
+    display: usize,
+    fmt: EncodeYuvFormat,
+    yuv: Vec<u8>,
+    time: Instant,
+}
+
+/// Keep the last frame of a display whose video service stops.
+pub fn keep_outgoing_frame(display: usize, fmt: &EncodeYuvFormat, yuv: Vec<u8>) {
+    if yuv.is_empty() {
+        return;
+    }
+    *OUTGOING_FRAME.lock().unwrap() = Some(OutgoingFrame {
+        display,
+        fmt: fmt.clone(),
+        yuv,
+        time: Instant::now(),
+    });
+}
+
+pub struct CrossfadeBlender {
+    display: usize,
+    frames: usize, // 0 disables the crossfade
+    blended: usize,
+    from: Option<Vec<u8>>, // the outgoing frame in the format of this display
+    first: bool,
+}
+
+impl CrossfadeBlender {
+    pub fn new(display: usize, frames: usize) -> Self {
+        Self {
+            display,
+            frames,
+            blended: 0,
+            from: None,
+            first: true,
+        }
+    }
+
+    // The outgoing frame of another display, scaled to `fmt`.
+    fn take_outgoing(&self, fmt: &EncodeYuvFormat) -> Option<Vec<u8>> {
+        let outgoing = {
+            let mut lock = OUTGOING_FRAME.lock().unwrap();
+            match lock.as_ref() {
+                Some(f)
+                    if f.display != self.display
+                        && f.time.elapsed() <= OUTGOING_FRAME_MAX_AGE
+                        && f.fmt.pixfmt == fmt.pixfmt =>
+                {
+                    lock.take()?
+                }
+                _ => return None,
+            }
+        };
+        let mut from = Vec::new();
+        if let Err(e) = scrap::scale_yuv(&outgoing.yuv, &outgoing.fmt, &mut from, fmt) {
+            log::warn!("failed to scale the outgoing frame: {e}");
+            return None;
+        }
+        log::info!(
+            "crossfade from display {} to {} in {} frames",
+            outgoing.display,
+            self.display,
+            self.frames
+        );
+        Some(from)
+    }
+
+    /// Blend the outgoing frame into `yuv`, the first frames of the display.
+    pub fn blend(&mut self, yuv: &mut [u8], fmt: &EncodeYuvFormat) {
+        if std::mem::take(&mut self.first) && self.frames > 0 {
+            self.from = self.take_outgoing(fmt);
+        }
+        if self.blended >= self.frames {
+            self.from = None;
+        }
+        let Some(from) = self.from.as_ref() else {
+            return;
+        };
+        if from.len() != yuv.len() {
+            self.blended = self.frames;
+            return;
+        }
+        self.blended += 1;
+        blend(from, yuv, self.blended, self.frames + 1);
+    }
+}
+
+// dst = from + (dst - from) * n / d, per byte of all the planes.
+fn blend(from: &[u8], dst: &mut [u8], n: usize, d: usize) {
+    let (n, d) = (n as u32, d as u32);
+    for (dst, from) in dst.iter_mut().zip(from) {
+        *dst = ((*from as u32 * (d - n) + *dst as u32 * n + d / 2) / d) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crossfade() {
+        let fmt = scrap::packed_yuvfmt(scrap::Pixfmt::I420, 4, 2).unwrap();
+        let len = 4 * 2 * 3 / 2;
+        // Not from a display switch.
+        keep_outgoing_frame(1, &fmt, vec![0; len]);
+        let mut blender = CrossfadeBlender::new(1, 3);
+        let mut yuv = vec![200; len];
+        blender.blend(&mut yuv, &fmt);
+        assert!(yuv.iter().all(|v| *v == 200));
+
+        keep_outgoing_frame(0, &fmt, vec![0; len]);
+        let mut blender = CrossfadeBlender::new(1, 3);
+        let mut values = vec![];
+        for _ in 0..4 {
+            let mut yuv = vec![200; len];
+            blender.blend(&mut yuv, &fmt);
+            assert!(yuv.iter().all(|v| *v == yuv[0]));
+            values.push(yuv[0]);
+        }
+        assert_eq!(values, vec![50, 100, 150, 200]);
+        assert!(OUTGOING_FRAME.lock().unwrap().is_none());
+    }
+}
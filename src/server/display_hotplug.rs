@@ -0,0 +1,160 @@
+// Push the display changes to the video services instead of polling `Display::all()`.
+//
+// Only Windows is supported now, `DisplayHotplugWatcher::spawn()` returns `None` on other
+// platforms and the video services and the display service keep polling.
+
+use super::*;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{channel, Receiver, Sender},
+};
+
+lazy_static::lazy_static! {
+    static ref SENDERS: Mutex<HashMap<usize, Sender<()>>> = Default::default();
+    static ref STARTED: bool = start();
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct DisplayHotplugWatcher;
+
+impl DisplayHotplugWatcher {
+    /// Start watching the display changes on the first call, the watcher is shared.
+    /// An event is sent to the returned receiver on every display change.
+    ///
+    /// Returns `None` if the platform is not supported or the watcher failed to start.
+    /// The sender is removed when the receiver is dropped, e.g. when the service restarts.
+    pub fn spawn() -> Option<DisplayHotplugReceiver> {
+        if !*STARTED {
+            return None;
+        }
+        let (tx, rx) = channel();
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        SENDERS.lock().unwrap().insert(id, tx);
+        Some(DisplayHotplugReceiver { id, rx })
+    }
+}
+
+pub struct DisplayHotplugReceiver {
+    id: usize,
+    rx: Receiver<()>,
+}
+
+impl std::ops::Deref for DisplayHotplugReceiver {
+    type Target = Receiver<()>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rx
+    }
+}
+
+impl Drop for DisplayHotplugReceiver {
+    fn drop(&mut self) {
+        SENDERS.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+fn notify() {
+    SENDERS.lock().unwrap().retain(|_, tx| tx.send(()).is_ok());
+}
+
+#[cfg(not(windows))]
+fn start() -> bool {
+    false
+}
+
+#[cfg(windows)]
+fn start() -> bool {
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        let res = unsafe { win::create_window() };
+        let ok = res.is_ok();
+        if let Err(e) = res {
+            log::error!("Failed to start the display hotplug watcher, {}", e);
+        }
+        tx.send(ok).ok();
+        if ok {
+            unsafe { win::run_message_loop() };
+        }
+    });
+    rx.recv().unwrap_or(false)
+}
+
+#[cfg(windows)]
+mod win {
+    use hbb_common::ResultType;
+    use std::{io, ptr::null_mut};
+    use winapi::{
+        shared::{
+            minwindef::{LPARAM, LRESULT, UINT, WPARAM},
+            windef::HWND,
+            winerror::ERROR_CLASS_ALREADY_EXISTS,
+        },
+        um::{dbt::DBT_DEVNODES_CHANGED, libloaderapi::GetModuleHandleW, winuser::*},
+    };
+
+    const CLASS_NAME: &str = "RustDeskDisplayHotplugWatcher";
+
+    // `WM_DISPLAYCHANGE` is only sent to the top-level windows, not the message-only windows.
+    pub(super) unsafe fn create_window() -> ResultType<HWND> {
+        let class_name = CLASS_NAME
+            .encode_utf16()
+            .chain(Some(0))
+            .collect::<Vec<u16>>();
+        let instance = GetModuleHandleW(null_mut());
+        let wc = WNDCLASSEXW {
+            cbSize: std::mem::size_of::<WNDCLASSEXW>() as _,
+            lpfnWndProc: Some(wnd_proc),
+            hInstance: instance,
+            lpszClassName: class_name.as_ptr(),
+            ..std::mem::zeroed()
+        };
+        if RegisterClassExW(&wc) == 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(ERROR_CLASS_ALREADY_EXISTS as _) {
+                hbb_common::bail!("Failed to RegisterClassExW, {}", e);
+            }
+        }
+        let hwnd = CreateWindowExW(
+            0,
+            class_name.as_ptr(),
+            class_name.as_ptr(),
+            WS_POPUP,
+            0,
+            0,
+            0,
+            0,
+            null_mut(),
+            null_mut(),
+            instance,
+            null_mut(),
+        );
+        if hwnd.is_null() {
+            hbb_common::bail!("Failed to CreateWindowExW, {}", io::Error::last_os_error());
+        }
+        Ok(hwnd)
+    }
+
+    pub(super) unsafe fn run_message_loop() {
+        let mut msg: MSG = std::mem::zeroed();
+        while GetMessageW(&mut msg, null_mut(), 0, 0) > 0 {
+            TranslateMessage(&msg);
+            DispatchMessageW(&msg);
+        }
+    }
+
+    unsafe extern "system" fn wnd_proc(
+        hwnd: HWND,
+        msg: UINT,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        match msg {
+            WM_DISPLAYCHANGE => super::notify(),
+            WM_DEVICECHANGE if wparam == DBT_DEVNODES_CHANGED => super::notify(),
+            _ => {}
+        }
+        DefWindowProcW(hwnd, msg, wparam, lparam)
+    }
+}
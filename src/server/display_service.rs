@@ -8,7 +8,10 @@ use crate::virtual_display_manager;
 use hbb_common::get_version_number;
 use hbb_common::protobuf::MessageField;
 use scrap::Display;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Instant,
+};
 
 // https://github.com/rustdesk/rustdesk/discussions/6042, avoiding dbus call
 
@@ -29,11 +32,26 @@ lazy_static::lazy_static! {
     // It should not be updated when displays changed.
     pub static ref PRIMARY_DISPLAY_IDX: usize = get_primary();
     static ref SYNC_DISPLAYS: Arc<Mutex<SyncDisplaysInfo>> = Default::default();
+    // The display most recently (re)acquired by a video service.
+    static ref CURRENT_DISPLAY_INFO: RwLock<Option<DisplayInfo>> = Default::default();
+    static ref DISPLAY_PROVIDER: RwLock<Option<Box<dyn DisplayProvider>>> = Default::default();
+    // Set by the timed out enumeration when it returns, the later ones fail without waiting
+    // until then.
+    static ref HUNG_DISPLAY_ENUMERATION: Mutex<Option<Arc<AtomicBool>>> = Default::default();
 }
 
+pub(super) const DISPLAYS_FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
 // https://github.com/rustdesk/rustdesk/pull/8537
 static TEMP_IGNORE_DISPLAYS_CHANGED: AtomicBool = AtomicBool::new(false);
 
+static DISPLAY_ENUMERATION_TIMEOUT_MS: AtomicU64 = AtomicU64::new(2_000); // 0 to disable
+
+// Set when a capturer fails to be created or its device is removed, the next enumeration drops
+// the displays with stale handles.
+#[cfg(windows)]
+static CHECK_STALE_DISPLAYS: AtomicBool = AtomicBool::new(false);
+
 #[derive(Default)]
 struct SyncDisplaysInfo {
     displays: Vec<DisplayInfo>,
@@ -96,7 +114,7 @@ pub(super) fn check_display_changed(
     #[cfg(target_os = "linux")]
     {
         // wayland do not support changing display for now
-        if !is_x11() {
+        if !is_x11() && !is_display_provided() {
             return None;
         }
     }
@@ -110,6 +128,7 @@ pub(super) fn check_display_changed(
     if ndisplay != lock.displays.len() {
         return Some(d.clone());
     }
+    // The origin is compared too, the monitors may be rearranged without changing the size.
     if !(d.x == x && d.y == y && d.width == w as i32 && d.height == h as i32) {
         Some(d.clone())
     } else {
@@ -117,6 +136,155 @@ pub(super) fn check_display_changed(
     }
 }
 
+/// The properties of a display synced to the peers, of `scrap::Display` or `MockDisplay`.
+pub trait TraitDisplay {
+    fn name(&self) -> String;
+    fn origin(&self) -> (i32, i32);
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    fn is_online(&self) -> bool;
+    fn is_primary(&self) -> bool;
+    // The dpi scale, only sent to the peers on macOS.
+    fn scale(&self) -> f64 {
+        1.0
+    }
+}
+
+impl TraitDisplay for Display {
+    fn name(&self) -> String {
+        Display::name(self)
+    }
+
+    fn origin(&self) -> (i32, i32) {
+        Display::origin(self)
+    }
+
+    fn width(&self) -> usize {
+        Display::width(self)
+    }
+
+    fn height(&self) -> usize {
+        Display::height(self)
+    }
+
+    fn is_online(&self) -> bool {
+        Display::is_online(self)
+    }
+
+    fn is_primary(&self) -> bool {
+        Display::is_primary(self)
+    }
+
+    #[cfg(any(windows, target_os = "macos"))]
+    fn scale(&self) -> f64 {
+        Display::scale(self)
+    }
+}
+
+/// A display with the given properties, for the environments without a display, e.g. CI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockDisplay {
+    pub name: String,
+    pub origin: (i32, i32),
+    pub width: usize,
+    pub height: usize,
+    pub online: bool,
+    pub primary: bool,
+    pub scale: f64,
+}
+
+impl Default for MockDisplay {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            origin: (0, 0),
+            width: 1920,
+            height: 1080,
+            online: true,
+            primary: false,
+            scale: 1.0,
+        }
+    }
+}
+
+impl TraitDisplay for MockDisplay {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn origin(&self) -> (i32, i32) {
+        self.origin
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn is_online(&self) -> bool {
+        self.online
+    }
+
+    fn is_primary(&self) -> bool {
+        self.primary
+    }
+
+    fn scale(&self) -> f64 {
+        self.scale
+    }
+}
+
+/// The displays queried by the display service instead of `Display::all()`.
+pub trait DisplayProvider: Send + Sync {
+    fn displays(&self) -> ResultType<Vec<MockDisplay>>;
+}
+
+/// Provide a fixed list of displays.
+pub struct VecDisplayProvider(pub Vec<MockDisplay>);
+
+impl DisplayProvider for VecDisplayProvider {
+    fn displays(&self) -> ResultType<Vec<MockDisplay>> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Query the displays from `provider` instead of the system, e.g. to run the tests of the
+/// display and video services without a display server (no Xvfb on the CI runners).
+/// The displays are synced to the peers as the real ones, on Wayland too.
+///
+/// Only the displays are mocked, the video services still create the capturers of the real
+/// displays, the tests use their own capturers.
+pub fn set_display_provider(provider: Box<dyn DisplayProvider>) {
+    *DISPLAY_PROVIDER.write().unwrap() = Some(provider);
+}
+
+#[inline]
+pub fn clear_display_provider() {
+    *DISPLAY_PROVIDER.write().unwrap() = None;
+}
+
+#[inline]
+fn is_display_provided() -> bool {
+    DISPLAY_PROVIDER.read().unwrap().is_some()
+}
+
+// Query the displays and update the synced ones.
+fn query_update_displays(_add_amyuni_headless: bool) -> ResultType<()> {
+    if let Some(provider) = DISPLAY_PROVIDER.read().unwrap().as_ref() {
+        check_update_displays(&provider.displays()?);
+        return Ok(());
+    }
+    #[cfg(windows)]
+    let displays = try_get_displays_(_add_amyuni_headless)?;
+    #[cfg(not(windows))]
+    let displays = try_get_displays()?;
+    check_update_displays(&displays);
+    Ok(())
+}
+
 #[inline]
 pub fn set_last_changed_resolution(display_name: &str, original: (i32, i32), changed: (i32, i32)) {
     let mut lock = CHANGED_RESOLUTIONS.write().unwrap();
@@ -196,14 +364,19 @@ fn displays_to_msg(displays: Vec<DisplayInfo>) -> Message {
     msg_out
 }
 
+// The synced displays, sent before `SwitchDisplay` which has no display list.
+pub(super) fn sync_displays_msg() -> Message {
+    displays_to_msg(get_sync_displays())
+}
+
 fn check_get_displays_changed_msg() -> Option<Message> {
     #[cfg(target_os = "linux")]
     {
-        if !is_x11() {
+        if !is_x11() && !is_display_provided() {
             return get_displays_msg();
         }
     }
-    check_update_displays(&try_get_displays().ok()?);
+    query_update_displays(false).ok()?;
     get_displays_msg()
 }
 
@@ -212,12 +385,11 @@ pub fn check_displays_changed() -> ResultType<()> {
     {
         // Currently, wayland need to call wayland::clear() before call Display::all(), otherwise it will cause
         // block, or even crash here, https://github.com/rustdesk/rustdesk/blob/0bb4d43e9ea9d9dfb9c46c8d27d1a97cd0ad6bea/libs/scrap/src/wayland/pipewire.rs#L235
-        if !is_x11() {
+        if !is_x11() && !is_display_provided() {
             return Ok(());
         }
     }
-    check_update_displays(&try_get_displays()?);
-    Ok(())
+    query_update_displays(false)
 }
 
 fn get_displays_msg() -> Option<Message> {
@@ -226,6 +398,11 @@ fn get_displays_msg() -> Option<Message> {
 }
 
 fn run(sp: EmptyExtraFieldService) -> ResultType<()> {
+    // Query the displays on the changes pushed by the watcher instead of every 300 ms.
+    // The slow polling is kept for the changes without notifications,
+    // e.g. retrying to plug in the virtual display for headless.
+    let hotplug = super::display_hotplug::DisplayHotplugWatcher::spawn();
+    let mut last_query = None::<Instant>;
     while sp.ok() {
         sp.snapshot(|sps| {
             if !TEMP_IGNORE_DISPLAYS_CHANGED.load(Ordering::Relaxed) {
@@ -237,11 +414,33 @@ fn run(sp: EmptyExtraFieldService) -> ResultType<()> {
             Ok(())
         })?;
 
-        if let Some(msg_out) = check_get_displays_changed_msg() {
+        let query = match &hotplug {
+            Some(rx) => {
+                // The events of one change come in a burst.
+                rx.try_iter().count() > 0
+                    || last_query.map_or(true, |t| t.elapsed() > DISPLAYS_FALLBACK_POLL_INTERVAL)
+            }
+            None => true,
+        };
+        let msg_out = if query {
+            last_query = Some(Instant::now());
+            check_get_displays_changed_msg()
+        } else {
+            get_displays_msg()
+        };
+        if let Some(msg_out) = msg_out {
             sp.send(msg_out);
             log::info!("Displays changed");
         }
-        std::thread::sleep(Duration::from_millis(300));
+        match &hotplug {
+            Some(rx) => {
+                if rx.recv_timeout(Duration::from_millis(300)).is_ok() {
+                    // Query on the next loop.
+                    last_query = None;
+                }
+            }
+            None => std::thread::sleep(Duration::from_millis(300)),
+        }
     }
 
     Ok(())
@@ -300,13 +499,39 @@ pub(super) fn get_display_info(idx: usize) -> Option<DisplayInfo> {
     SYNC_DISPLAYS.lock().unwrap().displays.get(idx).cloned()
 }
 
+// Called by the video service after creating the capturer of the display.
+pub(super) fn update_current_display_info(
+    idx: usize,
+    origin: (i32, i32),
+    width: usize,
+    height: usize,
+) {
+    let mut info = get_display_info(idx).unwrap_or_default();
+    info.x = origin.0;
+    info.y = origin.1;
+    info.width = width as _;
+    info.height = height as _;
+    *CURRENT_DISPLAY_INFO.write().unwrap() = Some(info);
+}
+
+/// The cached info of the display most recently captured, without enumerating the displays.
+/// Use `get_sync_displays()` if multiple displays are captured.
+pub fn get_current_display_info() -> ResultType<DisplayInfo> {
+    match CURRENT_DISPLAY_INFO.read().unwrap().as_ref() {
+        Some(info) => Ok(info.clone()),
+        None => bail!("No display is captured"),
+    }
+}
+
 // Display to DisplayInfo
 // The DisplayInfo is be sent to the peer.
-pub(super) fn check_update_displays(all: &Vec<Display>) {
+pub(super) fn check_update_displays<D: TraitDisplay>(all: &[D]) {
     let displays = all
         .iter()
         .map(|d| {
             let display_name = d.name();
+            // Only macOS sends the scale, the peers map the logical coordinates with it.
+            // The coordinates of the other platforms are already in physical pixels.
             #[allow(unused_assignments)]
             #[allow(unused_mut)]
             let mut scale = 1.0;
@@ -347,20 +572,19 @@ pub fn is_inited_msg() -> Option<Message> {
 pub async fn update_get_sync_displays_on_login() -> ResultType<Vec<DisplayInfo>> {
     #[cfg(target_os = "linux")]
     {
-        if !is_x11() {
+        if !is_x11() && !is_display_provided() {
             return super::wayland::get_displays().await;
         }
     }
-    #[cfg(not(windows))]
-    let displays = display_service::try_get_displays();
-    #[cfg(windows)]
-    let displays = display_service::try_get_displays_add_amyuni_headless();
-    check_update_displays(&displays?);
+    query_update_displays(true)?;
     Ok(SYNC_DISPLAYS.lock().unwrap().displays.clone())
 }
 
 #[inline]
 pub fn get_primary() -> usize {
+    if let Some(provider) = DISPLAY_PROVIDER.read().unwrap().as_ref() {
+        return provider.displays().map(|d| get_primary_2(&d)).unwrap_or(0);
+    }
     #[cfg(target_os = "linux")]
     {
         if !is_x11() {
@@ -375,13 +599,13 @@ pub fn get_primary() -> usize {
 }
 
 #[inline]
-pub fn get_primary_2(all: &Vec<Display>) -> usize {
+pub fn get_primary_2<D: TraitDisplay>(all: &[D]) -> usize {
     all.iter().position(|d| d.is_primary()).unwrap_or(0)
 }
 
 #[inline]
 #[cfg(windows)]
-fn no_displays(displays: &Vec<Display>) -> bool {
+fn no_displays(displays: &[&Display]) -> bool {
     let display_len = displays.len();
     if display_len == 0 {
         true
@@ -404,10 +628,61 @@ fn no_displays(displays: &Vec<Display>) -> bool {
     }
 }
 
+/// Fail the display enumeration if it takes longer than `ms`, 0 to wait forever.
+/// `Display::all()` hangs on some broken GPU drivers, which would hang the callers.
+/// Not used on Wayland, where the enumeration may wait for the user to pick the screens.
+#[inline]
+pub fn set_display_enumeration_timeout(ms: u64) {
+    DISPLAY_ENUMERATION_TIMEOUT_MS.store(ms, Ordering::Relaxed);
+}
+
+// The displays of one enumeration, moved from the thread enumerating them to the caller.
+// The DXGI objects are free-threaded, the X11 displays share a connection of their own, which
+// moves with all of them, and the other displays are ids.
+struct EnumeratedDisplays(Vec<Display>);
+
+unsafe impl Send for EnumeratedDisplays {}
+
+// `Display::all()` with the timeout, the displays are enumerated on another thread.
+// The hung thread is left behind.
+fn display_all() -> ResultType<Vec<Display>> {
+    let timeout = DISPLAY_ENUMERATION_TIMEOUT_MS.load(Ordering::Relaxed);
+    #[cfg(target_os = "linux")]
+    let timeout = if is_x11() { timeout } else { 0 };
+    if timeout == 0 {
+        return Ok(Display::all()?);
+    }
+    {
+        let mut hung = HUNG_DISPLAY_ENUMERATION.lock().unwrap();
+        match hung.as_ref() {
+            Some(returned) if !returned.load(Ordering::SeqCst) => {
+                bail!("Display enumeration timed out")
+            }
+            _ => *hung = None,
+        }
+    }
+    let returned = Arc::new(AtomicBool::new(false));
+    let returned_cloned = returned.clone();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let res = Display::all().map(EnumeratedDisplays);
+        returned_cloned.store(true, Ordering::SeqCst);
+        tx.send(res).ok();
+    });
+    match rx.recv_timeout(Duration::from_millis(timeout)) {
+        Ok(res) => Ok(res?.0),
+        Err(_) => {
+            log::error!("Display enumeration timed out after {timeout}ms");
+            *HUNG_DISPLAY_ENUMERATION.lock().unwrap() = Some(returned);
+            bail!("Display enumeration timed out");
+        }
+    }
+}
+
 #[inline]
 #[cfg(not(windows))]
 pub fn try_get_displays() -> ResultType<Vec<Display>> {
-    Ok(Display::all()?)
+    display_all()
 }
 
 #[inline]
@@ -429,7 +704,7 @@ pub fn try_get_displays_add_amyuni_headless() -> ResultType<Vec<Display>> {
 #[inline]
 #[cfg(windows)]
 pub fn try_get_displays_(add_amyuni_headless: bool) -> ResultType<Vec<Display>> {
-    let mut displays = Display::all()?;
+    let mut displays = all_valid_displays()?;
 
     // Do not add virtual display if the platform is not installed or the virtual display is not supported.
     if !crate::platform::is_installed() || !virtual_display_manager::is_virtual_display_supported()
@@ -462,14 +737,168 @@ pub fn try_get_displays_(add_amyuni_headless: bool) -> ResultType<Vec<Display>>
     //     return Ok(displays);
     // }
 
-    let no_displays_v = no_displays(&displays);
-    if no_displays_v {
-        log::debug!("no displays, create virtual display");
-        if let Err(e) = virtual_display_manager::plug_in_headless() {
-            log::error!("plug in headless failed {}", e);
-        } else {
-            displays = Display::all()?;
+    let (physical, virtuals): (Vec<_>, Vec<_>) = displays
+        .iter()
+        .partition(|d| !virtual_display_manager::is_virtual_display(&d.name()));
+    let physical_count = if no_displays(&physical) {
+        0
+    } else {
+        physical.len()
+    };
+    match headless_action(physical_count, virtuals.len()) {
+        HeadlessAction::PlugIn => {
+            log::debug!("no displays, create virtual display");
+            if let Err(e) = virtual_display_manager::plug_in_headless() {
+                log::error!("plug in headless failed {}", e);
+            } else {
+                displays = all_valid_displays()?;
+            }
+        }
+        HeadlessAction::PlugOut => {
+            if virtual_display_manager::plug_out_headless() {
+                log::debug!("physical displays are back, virtual display for headless closed");
+                displays = all_valid_displays()?;
+            }
         }
+        HeadlessAction::Keep => {}
+    }
+    Ok(displays)
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+enum HeadlessAction {
+    PlugIn,
+    PlugOut,
+    Keep,
+}
+
+// The virtual display for headless is only needed if there're no physical displays.
+// Do not count the virtual displays as physical ones, or the virtual display for headless
+// would be closed right after it is created.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn headless_action(physical: usize, virtuals: usize) -> HeadlessAction {
+    match (physical, virtuals) {
+        (0, 0) => HeadlessAction::PlugIn,
+        (0, _) => HeadlessAction::Keep,
+        (_, 0) => HeadlessAction::Keep,
+        _ => HeadlessAction::PlugOut,
+    }
+}
+
+// After a GPU driver reset (TDR), `Display::all()` may return displays with stale handles,
+// and creating the capturer of them fails.
+#[inline]
+#[cfg(windows)]
+pub(super) fn check_stale_displays() {
+    CHECK_STALE_DISPLAYS.store(true, Ordering::SeqCst);
+}
+
+// Remove the displays with invalid handles, only checked after `check_stale_displays()`.
+#[cfg(windows)]
+fn all_valid_displays() -> ResultType<Vec<Display>> {
+    let mut displays = display_all()?;
+    if !CHECK_STALE_DISPLAYS.swap(false, Ordering::SeqCst) {
+        return Ok(displays);
+    }
+    let stale_names = stale_display_names()?;
+    if !stale_names.is_empty() {
+        displays.retain(|d| {
+            let name = d.name();
+            if stale_names.contains(&name) {
+                log::warn!("Remove display {} with an invalid handle", name);
+                false
+            } else {
+                true
+            }
+        });
     }
     Ok(displays)
 }
+
+// Try to create a capturer for each display.
+// The displays are consumed by the test capturers, so they are enumerated separately.
+#[cfg(windows)]
+fn stale_display_names() -> ResultType<Vec<String>> {
+    use winapi::shared::winerror::ERROR_INVALID_HANDLE;
+
+    let mut stale_names = vec![];
+    for display in display_all()? {
+        let name = display.name();
+        if let Err(e) = scrap::Capturer::new(display) {
+            if e.raw_os_error() == Some(ERROR_INVALID_HANDLE as _) {
+                stale_names.push(name);
+            }
+        }
+    }
+    Ok(stale_names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_display_info() {
+        // No displays in some test environments.
+        let Ok(displays) = Display::all() else {
+            return;
+        };
+        let Some(d) = displays.first() else {
+            return;
+        };
+        update_current_display_info(0, d.origin(), d.width(), d.height());
+        let info = get_current_display_info().unwrap();
+        let d = &Display::all().unwrap()[0];
+        assert_eq!((info.x, info.y), d.origin());
+        assert_eq!(
+            (info.width as usize, info.height as usize),
+            (d.width(), d.height())
+        );
+    }
+
+    #[test]
+    fn test_headless_action() {
+        // (display name, is virtual)
+        let action = |displays: &[(&str, bool)]| {
+            let virtuals = displays.iter().filter(|(_, v)| *v).count();
+            headless_action(displays.len() - virtuals, virtuals)
+        };
+        assert_eq!(action(&[]), HeadlessAction::PlugIn);
+        assert_eq!(action(&[("\\\\.\\DISPLAY1", false)]), HeadlessAction::Keep);
+        // The only display is the virtual display for headless.
+        assert_eq!(action(&[("\\\\.\\DISPLAY2", true)]), HeadlessAction::Keep);
+        // The physical display is plugged in again.
+        assert_eq!(
+            action(&[("\\\\.\\DISPLAY1", false), ("\\\\.\\DISPLAY2", true)]),
+            HeadlessAction::PlugOut
+        );
+        assert_eq!(
+            action(&[("\\\\.\\DISPLAY1", false), ("\\\\.\\DISPLAY3", false)]),
+            HeadlessAction::Keep
+        );
+    }
+
+    #[test]
+    fn test_check_display_changed_origin() {
+        #[cfg(target_os = "linux")]
+        if !is_x11() {
+            return;
+        }
+        let display = |x, y| DisplayInfo {
+            x,
+            y,
+            width: 1920,
+            height: 1080,
+            ..Default::default()
+        };
+        let backup = get_sync_displays();
+        SYNC_DISPLAYS.lock().unwrap().displays = vec![display(0, 0), display(1920, 0)];
+        assert!(check_display_changed(2, 1, (1920, 0, 1920, 1080)).is_none());
+        // The monitors are rearranged, the size is not changed.
+        SYNC_DISPLAYS.lock().unwrap().displays = vec![display(1920, 0), display(0, 0)];
+        let changed = check_display_changed(2, 1, (1920, 0, 1920, 1080));
+        assert_eq!(changed.map(|d| (d.x, d.y)), Some((0, 0)));
+        SYNC_DISPLAYS.lock().unwrap().displays = backup;
+    }
+}
@@ -0,0 +1,194 @@
+// Encrypt the encoded frames end to end, on top of the encryption of the connections, which
+// the relays can see through if they terminate them.
+//
+// The data of each encoded frame is sealed with ChaCha20-Poly1305 (IETF), the key comes from
+// the session key exchange. The `key` flag and `pts` of the frame are authenticated too.
+// There is no field for the nonce or the tag in `EncodedVideoFrame`, the data is replaced by
+// `nonce || ciphertext || tag`, the peers with the key open it with `open_frame_data()`.
+//
+// An encryptor is meant to be kept by each connection, which seals the shared frames right
+// before sending them with `encrypt_message()`. It's not used yet: the peers can only tell the
+// sealed frames from the others once the frame encryption is negotiated with a key derived
+// from the session key, which needs new fields in the protocol.
+//
+// The nonces are a random prefix of each encryptor and a counter, so a new encryptor with the
+// same key does not reuse the nonces.
+
+use super::*;
+use hbb_common::sodiumoxide::{
+    crypto::aead::chacha20poly1305_ietf::{self as aead, Key, Nonce},
+    randombytes::randombytes_into,
+};
+
+pub const FRAME_KEY_BYTES: usize = aead::KEYBYTES;
+
+const NONCE_PREFIX_BYTES: usize = aead::NONCEBYTES - 8;
+
+pub struct FrameEncryptor {
+    key: Key,
+    nonce_prefix: [u8; NONCE_PREFIX_BYTES],
+    nonce_counter: u64,
+}
+
+impl FrameEncryptor {
+    pub fn new(key: [u8; FRAME_KEY_BYTES]) -> Self {
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_BYTES];
+        randombytes_into(&mut nonce_prefix);
+        Self {
+            key: Key(key),
+            nonce_prefix,
+            nonce_counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> ResultType<Nonce> {
+        let Some(counter) = self.nonce_counter.checked_add(1) else {
+            bail!("frame encryption nonces exhausted");
+        };
+        self.nonce_counter = counter;
+        let mut nonce = [0u8; aead::NONCEBYTES];
+        nonce[..NONCE_PREFIX_BYTES].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_BYTES..].copy_from_slice(&counter.to_le_bytes());
+        Ok(Nonce(nonce))
+    }
+
+    /// Encrypt the data of all the encoded frames of `vf`, the raw frames are left as they are.
+    pub fn encrypt(&mut self, vf: &mut VideoFrame) -> ResultType<()> {
+        let frames = match vf.union.as_mut() {
+            Some(video_frame::Union::Vp8s(f))
+            | Some(video_frame::Union::Vp9s(f))
+            | Some(video_frame::Union::Av1s(f))
+            | Some(video_frame::Union::H264s(f))
+            | Some(video_frame::Union::H265s(f)) => &mut f.frames,
+            _ => return Ok(()),
+        };
+        for frame in frames.iter_mut() {
+            let nonce = self.next_nonce()?;
+            let ad = additional_data(frame.key, frame.pts);
+            let sealed = aead::seal(&frame.data, Some(&ad), &nonce, &self.key);
+            let mut data = Vec::with_capacity(nonce.0.len() + sealed.len());
+            data.extend_from_slice(&nonce.0);
+            data.extend_from_slice(&sealed);
+            frame.data = Bytes::from(data);
+        }
+        Ok(())
+    }
+
+    /// A sealed copy of the video frame message, `None` for the other messages.
+    /// The messages are shared by the connections, they are left as they are.
+    pub fn encrypt_message(&mut self, msg: &Message) -> ResultType<Option<Message>> {
+        let Some(message::Union::VideoFrame(vf)) = msg.union.as_ref() else {
+            return Ok(None);
+        };
+        let mut vf = vf.clone();
+        self.encrypt(&mut vf)?;
+        let mut sealed = Message::new();
+        sealed.set_video_frame(vf);
+        Ok(Some(sealed))
+    }
+}
+
+fn additional_data(key: bool, pts: i64) -> [u8; 9] {
+    let mut ad = [0u8; 9];
+    ad[0] = key as u8;
+    ad[1..].copy_from_slice(&pts.to_le_bytes());
+    ad
+}
+
+/// Decrypt the data of an encoded frame encrypted by `FrameEncryptor`.
+pub fn open_frame_data(
+    key: &[u8; FRAME_KEY_BYTES],
+    frame_key: bool,
+    pts: i64,
+    data: &[u8],
+) -> ResultType<Vec<u8>> {
+    if data.len() < aead::NONCEBYTES + aead::TAGBYTES {
+        bail!("encrypted frame too short: {}", data.len());
+    }
+    let (nonce, sealed) = data.split_at(aead::NONCEBYTES);
+    let Some(nonce) = Nonce::from_slice(nonce) else {
+        bail!("invalid frame nonce");
+    };
+    let ad = additional_data(frame_key, pts);
+    match aead::open(sealed, Some(&ad), &nonce, &Key(*key)) {
+        Ok(data) => Ok(data),
+        Err(()) => bail!("failed to authenticate the encrypted frame"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_encryption() {
+        let key = [7u8; FRAME_KEY_BYTES];
+        let mut encryptor = FrameEncryptor::new(key);
+        let mut vf = VideoFrame::new();
+        vf.set_vp9s(EncodedVideoFrames {
+            frames: vec![
+                EncodedVideoFrame {
+                    data: Bytes::from_static(b"keyframe"),
+                    key: true,
+                    pts: 10,
+                    ..Default::default()
+                },
+                EncodedVideoFrame {
+                    data: Bytes::from_static(b"keyframe"),
+                    key: true,
+                    pts: 10,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        });
+        encryptor.encrypt(&mut vf).unwrap();
+        let frames = &vf.vp9s().frames;
+        // Different nonces for the same data.
+        assert_ne!(frames[0].data, frames[1].data);
+        for f in frames {
+            assert_eq!(
+                open_frame_data(&key, true, 10, &f.data).unwrap(),
+                b"keyframe"
+            );
+        }
+        assert!(open_frame_data(&key, false, 10, &frames[0].data).is_err());
+        assert!(open_frame_data(&[8u8; FRAME_KEY_BYTES], true, 10, &frames[0].data).is_err());
+
+        // The same key set again.
+        let mut vf2 = VideoFrame::new();
+        vf2.set_vp9s(EncodedVideoFrames {
+            frames: vec![EncodedVideoFrame {
+                data: Bytes::from_static(b"keyframe"),
+                key: true,
+                pts: 10,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        FrameEncryptor::new(key).encrypt(&mut vf2).unwrap();
+        assert_ne!(vf2.vp9s().frames[0].data, frames[0].data);
+    }
+
+    #[test]
+    fn test_encrypt_message() {
+        let mut encryptor = FrameEncryptor::new([7u8; FRAME_KEY_BYTES]);
+        let mut vf = VideoFrame::new();
+        vf.set_vp9s(EncodedVideoFrames {
+            frames: vec![EncodedVideoFrame {
+                data: Bytes::from_static(b"frame"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        let mut msg = Message::new();
+        msg.set_video_frame(vf);
+        let sealed = encryptor.encrypt_message(&msg).unwrap().unwrap();
+        assert_ne!(sealed.video_frame().vp9s().frames[0].data, b"frame"[..]);
+        assert_eq!(msg.video_frame().vp9s().frames[0].data, b"frame"[..]);
+        assert!(encryptor
+            .encrypt_message(&Message::new())
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,234 @@
+// Per-frame timestamps of the video services for the performance debugging, with the
+// `tracing_frames` feature.
+//
+// The records are written to a file of fixed-size little-endian records, used as a ring buffer
+// of `max_frames` records, so it never grows over `max_frames * RECORD_SIZE` bytes.
+// `dump_to_csv()` writes the records in the order of the frames.
+
+use super::*;
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+const RECORD_SIZE: usize = 6 * 8;
+
+lazy_static::lazy_static! {
+    static ref FRAME_TIMING_LOGGER: Mutex<Option<FrameTimingLogger>> = Default::default();
+}
+
+/// The timestamps of a frame, in microseconds since the logger is created, 0 if not reached,
+/// e.g. `ack_received_us` if not all the connections have fetched the frame in time.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FrameTimingRecord {
+    pub frame_id: u64, // from 1
+    pub capture_start_us: u64,
+    pub encode_start_us: u64,
+    pub encode_end_us: u64,
+    pub send_start_us: u64,
+    pub ack_received_us: u64,
+}
+
+impl FrameTimingRecord {
+    fn fields(&self) -> [u64; 6] {
+        [
+            self.frame_id,
+            self.capture_start_us,
+            self.encode_start_us,
+            self.encode_end_us,
+            self.send_start_us,
+            self.ack_received_us,
+        ]
+    }
+
+    fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        for (chunk, v) in bytes.chunks_exact_mut(8).zip(self.fields()) {
+            chunk.copy_from_slice(&v.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut v = bytes
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap_or_default()));
+        let mut next = || v.next().unwrap_or_default();
+        Self {
+            frame_id: next(),
+            capture_start_us: next(),
+            encode_start_us: next(),
+            encode_end_us: next(),
+            send_start_us: next(),
+            ack_received_us: next(),
+        }
+    }
+}
+
+pub struct FrameTimingLogger {
+    file: File,
+    path: PathBuf,
+    max_frames: usize,
+    last_frame_id: u64,
+    start: Instant,
+}
+
+impl FrameTimingLogger {
+    /// Create or truncate the file at `path`.
+    pub fn new(path: &Path, max_frames: usize) -> ResultType<Self> {
+        if max_frames == 0 {
+            bail!("max_frames must be greater than 0");
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+            max_frames,
+            last_frame_id: 0,
+            start: Instant::now(),
+        })
+    }
+
+    #[inline]
+    pub fn micros(&self, tm: Option<Instant>) -> u64 {
+        tm.map(|tm| tm.saturating_duration_since(self.start).as_micros() as u64)
+            .unwrap_or(0)
+    }
+
+    /// Write the record with the next frame id, overwriting the oldest one if full.
+    pub fn write(&mut self, mut record: FrameTimingRecord) -> ResultType<()> {
+        self.last_frame_id += 1;
+        record.frame_id = self.last_frame_id;
+        let index = (record.frame_id - 1) % self.max_frames as u64;
+        self.file
+            .seek(SeekFrom::Start(index * RECORD_SIZE as u64))?;
+        self.file.write_all(&record.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn records(&self) -> ResultType<Vec<FrameTimingRecord>> {
+        let mut bytes = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut bytes)?;
+        let mut records: Vec<_> = bytes
+            .chunks_exact(RECORD_SIZE)
+            .map(FrameTimingRecord::from_bytes)
+            .filter(|r| r.frame_id > 0)
+            .collect();
+        records.sort_by_key(|r| r.frame_id);
+        Ok(records)
+    }
+
+    pub fn dump_to_csv(&self, path: &Path) -> ResultType<()> {
+        let mut csv = String::from(concat!(
+            "frame_id,capture_start_us,encode_start_us,",
+            "encode_end_us,send_start_us,ack_received_us\n"
+        ));
+        for r in self.records()? {
+            let fields: Vec<_> = r.fields().iter().map(|v| v.to_string()).collect();
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+        std::fs::write(path, csv)?;
+        Ok(())
+    }
+}
+
+// The timestamps of the frame in progress of a video service.
+#[derive(Debug, Default, Clone)]
+pub(super) struct FrameTiming {
+    pub capture_start: Option<Instant>,
+    pub encode_start: Option<Instant>,
+    pub encode_end: Option<Instant>,
+    pub send_start: Option<Instant>,
+}
+
+impl FrameTiming {
+    // Write the frame if it's encoded.
+    pub fn finish(&mut self, ack_received: Option<Instant>) {
+        let timing = std::mem::take(self);
+        if timing.encode_end.is_none() {
+            return;
+        }
+        let mut lock = FRAME_TIMING_LOGGER.lock().unwrap();
+        let Some(logger) = lock.as_mut() else {
+            return;
+        };
+        let record = FrameTimingRecord {
+            frame_id: 0,
+            capture_start_us: logger.micros(timing.capture_start),
+            encode_start_us: logger.micros(timing.encode_start),
+            encode_end_us: logger.micros(timing.encode_end),
+            send_start_us: logger.micros(timing.send_start),
+            ack_received_us: logger.micros(ack_received),
+        };
+        if let Err(e) = logger.write(record) {
+            log::error!("Failed to write frame timing, stop logging: {e}");
+            *lock = None;
+        }
+    }
+}
+
+/// Log the timestamps of the frames of all the video services to `path`,
+/// the last `max_frames` frames are kept.
+pub fn start_frame_timing(path: &Path, max_frames: usize) -> ResultType<()> {
+    let logger = FrameTimingLogger::new(path, max_frames)?;
+    log::info!("log frame timing to {}", path.display());
+    *FRAME_TIMING_LOGGER.lock().unwrap() = Some(logger);
+    Ok(())
+}
+
+/// Stop logging, and write the records to `csv_path` if it's not `None`.
+pub fn stop_frame_timing(csv_path: Option<&Path>) -> ResultType<()> {
+    let Some(logger) = FRAME_TIMING_LOGGER.lock().unwrap().take() else {
+        bail!("frame timing is not started");
+    };
+    if let Some(csv_path) = csv_path {
+        logger.dump_to_csv(csv_path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_timing_logger() {
+        let dir = std::env::temp_dir();
+        let path = dir.join("test_frame_timing.bin");
+        let csv_path = dir.join("test_frame_timing.csv");
+        let mut logger = FrameTimingLogger::new(&path, 3).unwrap();
+        for i in 1..=5 {
+            logger
+                .write(FrameTimingRecord {
+                    capture_start_us: i * 100,
+                    ack_received_us: i * 100 + 50,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            3 * RECORD_SIZE as u64
+        );
+        let records = logger.records().unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.frame_id).collect::<Vec<_>>(),
+            vec![3, 4, 5]
+        );
+        assert_eq!(records[0].capture_start_us, 300);
+        logger.dump_to_csv(&csv_path).unwrap();
+        let csv = std::fs::read_to_string(&csv_path).unwrap();
+        assert_eq!(csv.lines().count(), 4);
+        assert_eq!(csv.lines().nth(1), Some("3,300,0,0,0,350"));
+        std::fs::remove_file(path).ok();
+        std::fs::remove_file(csv_path).ok();
+    }
+}
@@ -10,7 +10,7 @@ use hbb_common::{
 };
 #[cfg(feature = "vram")]
 use scrap::AdapterDevice;
-use scrap::{Capturer, Frame, TraitCapturer, TraitPixelBuffer};
+use scrap::{CaptureError, Capturer, Frame, TraitCapturer, TraitPixelBuffer};
 use shared_memory::*;
 use std::{
     mem::size_of,
@@ -411,7 +411,7 @@ pub mod server {
                             std::thread::sleep(spf);
                             continue;
                         }
-                        if e.kind() != std::io::ErrorKind::WouldBlock {
+                        if !matches!(e, CaptureError::Timeout) {
                             // DXGI_ERROR_INVALID_CALL after each success on Microsoft GPU driver
                             // log::error!("capture frame failed: {:?}", e);
                             if c.as_ref().map(|c| c.is_gdi()) == Some(false) {
@@ -679,7 +679,7 @@ pub mod client {
     }
 
     impl TraitCapturer for CapturerPortable {
-        fn frame<'a>(&'a mut self, timeout: Duration) -> std::io::Result<Frame<'a>> {
+        fn frame<'a>(&'a mut self, timeout: Duration) -> Result<Frame<'a>, CaptureError> {
             let mut lock = SHMEM.lock().unwrap();
             let shmem = lock.as_mut().ok_or(std::io::Error::new(
                 std::io::ErrorKind::Other,
@@ -710,10 +710,7 @@ pub mod client {
                             self.width,
                             self.height,
                         );
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::WouldBlock,
-                            "wouldblock error".to_string(),
-                        ));
+                        return Err(CaptureError::Timeout);
                     }
                     let frame_ptr = base.add(ADDR_CAPTURE_FRAME);
                     let data = slice::from_raw_parts(frame_ptr, (*frame_info).length);
@@ -726,15 +723,12 @@ pub mod client {
                     let ptr = base.add(ADDR_CAPTURE_WOULDBLOCK);
                     let wouldblock = utils::ptr_to_i32(ptr);
                     if wouldblock == TRUE {
-                        Err(std::io::Error::new(
-                            std::io::ErrorKind::WouldBlock,
-                            "wouldblock error".to_string(),
-                        ))
+                        Err(CaptureError::Timeout)
                     } else {
-                        Err(std::io::Error::new(
+                        Err(CaptureError::Other(std::io::Error::new(
                             std::io::ErrorKind::Other,
                             "other error".to_string(),
-                        ))
+                        )))
                     }
                 }
             }
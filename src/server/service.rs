@@ -19,7 +19,8 @@ pub trait Service: Send + Sync {
 
 pub trait Subscriber: Default + Send + Sync + 'static {
     fn id(&self) -> i32;
-    fn send(&mut self, msg: Arc<Message>);
+    /// Returns false if the message can't be queued, e.g. the connection is closed.
+    fn send(&mut self, msg: Arc<Message>) -> bool;
 }
 
 #[derive(Default)]
@@ -183,11 +184,27 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         }
     }
 
+    // Check and clear the option in one lock, so that concurrent setters are consumed once.
+    #[inline]
+    pub fn take_option_true(&self, opt: &str) -> bool {
+        self.0
+            .write()
+            .unwrap()
+            .options
+            .remove(opt)
+            .map_or(false, |v| v == SERVICE_OPTION_VALUE_TRUE)
+    }
+
     #[inline]
     pub fn has_subscribes(&self) -> bool {
         self.0.read().unwrap().has_subscribes()
     }
 
+    #[inline]
+    pub fn has_new_subscribes(&self) -> bool {
+        self.0.read().unwrap().new_subscribes.len() > 0
+    }
+
     pub fn snapshot<F>(&self, callback: F) -> ResultType<()>
     where
         F: FnMut(ServiceSwap<T>) -> ResultType<()>,
@@ -211,11 +228,19 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         }
     }
 
+    /// Send to all the connections except `id`.
+    #[inline]
     pub fn send_to_others(&self, msg: Message, id: i32) {
+        self.send_except(msg, &[id]);
+    }
+
+    /// Send to all the subscribed connections except `excluded_ids`,
+    /// e.g. notify the others that a connection has turned on privacy mode.
+    pub fn send_except(&self, msg: Message, excluded_ids: &[i32]) {
         let msg = Arc::new(msg);
         let mut lock = self.0.write().unwrap();
         for (sid, s) in lock.subscribes.iter_mut() {
-            if *sid != id {
+            if !excluded_ids.contains(sid) {
                 s.send(msg.clone());
             }
         }
@@ -228,18 +253,70 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         }
     }
 
-    pub fn send_video_frame(&self, msg: Message) -> HashSet<i32> {
+    /// Returns the ids of the connections the frame is sent to, and of the failed ones.
+    pub fn send_video_frame(&self, msg: Message) -> (HashSet<i32>, HashSet<i32>) {
         self.send_video_frame_shared(Arc::new(msg))
     }
 
-    pub fn send_video_frame_shared(&self, msg: Arc<Message>) -> HashSet<i32> {
+    pub fn send_video_frame_shared(&self, msg: Arc<Message>) -> (HashSet<i32>, HashSet<i32>) {
+        self.send_video_frame_shared_except(msg, &HashSet::new())
+    }
+
+    pub fn send_video_frame_shared_except(
+        &self,
+        msg: Arc<Message>,
+        excluded: &HashSet<i32>,
+    ) -> (HashSet<i32>, HashSet<i32>) {
         let mut conn_ids = HashSet::new();
+        let mut failed_conn_ids = HashSet::new();
         let mut lock = self.0.write().unwrap();
         for s in lock.subscribes.values_mut() {
-            s.send(msg.clone());
-            conn_ids.insert(s.id());
+            if !excluded.contains(&s.id()) {
+                if s.send(msg.clone()) {
+                    conn_ids.insert(s.id());
+                } else {
+                    failed_conn_ids.insert(s.id());
+                }
+            }
         }
-        conn_ids
+        (conn_ids, failed_conn_ids)
+    }
+
+    /// Like `send_video_frame_shared_except`, but sends to the connections in the ascending
+    /// order of `rank`. The connections `skip` returns true for, checked right before sending
+    /// to them, are not sent to and not in the returned ids.
+    pub fn send_video_frame_ranked<R: Ord>(
+        &self,
+        msg: Arc<Message>,
+        excluded: &HashSet<i32>,
+        rank: impl Fn(i32) -> R,
+        mut skip: impl FnMut(&R) -> bool,
+    ) -> (HashSet<i32>, HashSet<i32>) {
+        let mut conn_ids = HashSet::new();
+        let mut failed_conn_ids = HashSet::new();
+        let mut lock = self.0.write().unwrap();
+        let mut subs: Vec<_> = lock
+            .subscribes
+            .values_mut()
+            .filter(|s| !excluded.contains(&s.id()))
+            .map(|s| (rank(s.id()), s))
+            .collect();
+        subs.sort_by(|a, b| a.0.cmp(&b.0));
+        for (r, s) in subs {
+            if skip(&r) {
+                continue;
+            }
+            if s.send(msg.clone()) {
+                conn_ids.insert(s.id());
+            } else {
+                failed_conn_ids.insert(s.id());
+            }
+        }
+        (conn_ids, failed_conn_ids)
+    }
+
+    pub fn subscriber_ids(&self) -> HashSet<i32> {
+        self.0.read().unwrap().subscribes.keys().copied().collect()
     }
 
     pub fn send_without(&self, msg: Message, sub: i32) {
@@ -291,13 +368,26 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         svc.0.write().unwrap().handle = Some(thread);
     }
 
+    #[inline]
     pub fn run<F, Svc>(svc: &Svc, callback: F)
     where
         F: 'static + FnMut(Svc) -> ResultType<()> + Send,
         Svc: 'static + Clone + Send + DerefMut<Target = ServiceTmpl<T>>,
+    {
+        Self::run_with_idle(svc, callback, || {});
+    }
+
+    /// Like `run`, and calls `idle` on the service thread every `HIBERNATE_TIMEOUT` ms
+    /// while there are no subscribers, e.g. to release the resources kept by `callback`.
+    pub fn run_with_idle<F, I, Svc>(svc: &Svc, callback: F, idle: I)
+    where
+        F: 'static + FnMut(Svc) -> ResultType<()> + Send,
+        I: 'static + FnMut() + Send,
+        Svc: 'static + Clone + Send + DerefMut<Target = ServiceTmpl<T>>,
     {
         let sp = svc.clone();
         let mut callback = callback;
+        let mut idle = idle;
         let thread = thread::spawn(move || {
             let mut error_timeout = HIBERNATE_TIMEOUT;
             while sp.active() {
@@ -320,6 +410,8 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
                     } else {
                         log::debug!("Exit {} service inner loop", sp.name());
                     }
+                } else {
+                    idle();
                 }
                 thread::sleep(time::Duration::from_millis(HIBERNATE_TIMEOUT));
             }
@@ -356,3 +448,28 @@ impl<T: Subscriber + From<ConnInner>> Drop for ServiceSwap<T> {
         (self.0).0.write().unwrap().swap_new_subscribes();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn test_send_except() {
+        let sp = GenericService::new("test_send_except".to_owned(), false);
+        let mut rxs = vec![];
+        for id in 1..=3 {
+            let (tx, rx) = unbounded_channel();
+            sp.on_subscribe(ConnInner::new(id, Some(tx), None));
+            rxs.push(rx);
+        }
+        sp.send_except(Message::new(), &[2]);
+        assert!(rxs[0].try_recv().is_ok());
+        assert!(rxs[1].try_recv().is_err());
+        assert!(rxs[2].try_recv().is_ok());
+        sp.send_to_others(Message::new(), 1);
+        assert!(rxs[0].try_recv().is_err());
+        assert!(rxs[1].try_recv().is_ok());
+        assert!(rxs[2].try_recv().is_ok());
+    }
+}
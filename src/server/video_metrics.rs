@@ -0,0 +1,262 @@
+// Telemetry of the video services in the Prometheus text format.
+//
+// The metrics are always collected, they are only served after `start_metrics_server()`
+// is called, it is not started by default.
+
+use super::*;
+use hbb_common::tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use scrap::CodecFormat;
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+// The upper bounds of the encode duration buckets, in seconds.
+const ENCODE_DURATION_BUCKETS: [f64; 8] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25];
+
+lazy_static::lazy_static! {
+    static ref VIDEO_METRICS: VideoMetricsCollector = Default::default();
+}
+
+#[derive(Debug, Default, Clone)]
+struct Metrics {
+    frames_encoded: u64,
+    frames_dropped: u64,
+    bytes_encoded: u64,
+    encode_duration_buckets: [u64; ENCODE_DURATION_BUCKETS.len()],
+    encode_duration_sum: f64,
+    connections_active: usize,
+    display_switches: u64,
+    bitrate: u64,
+    peak_bitrate: u64,
+}
+
+/// The counters of the video services, labeled with `display_index` and `codec`.
+#[derive(Default)]
+pub struct VideoMetricsCollector {
+    metrics: Mutex<BTreeMap<(usize, String), Metrics>>,
+}
+
+impl VideoMetricsCollector {
+    #[inline]
+    pub fn global() -> &'static VideoMetricsCollector {
+        &VIDEO_METRICS
+    }
+
+    fn update(&self, display: usize, codec: CodecFormat, f: impl FnOnce(&mut Metrics)) {
+        let codec = codec.to_string().to_lowercase();
+        f(self
+            .metrics
+            .lock()
+            .unwrap()
+            .entry((display, codec))
+            .or_default());
+    }
+
+    pub fn record_encoded(
+        &self,
+        display: usize,
+        codec: CodecFormat,
+        bytes: usize,
+        duration: Duration,
+    ) {
+        self.update(display, codec, |m| {
+            m.frames_encoded += 1;
+            m.bytes_encoded += bytes as u64;
+            let secs = duration.as_secs_f64();
+            m.encode_duration_sum += secs;
+            if let Some(i) = ENCODE_DURATION_BUCKETS.iter().position(|b| secs <= *b) {
+                m.encode_duration_buckets[i] += 1;
+            }
+        });
+    }
+
+    pub fn record_dropped(&self, display: usize, codec: CodecFormat) {
+        self.update(display, codec, |m| m.frames_dropped += 1);
+    }
+
+    // The connections are counted by the current codec of the display only.
+    pub fn set_connections(&self, display: usize, codec: CodecFormat, connections: usize) {
+        let codec = codec.to_string().to_lowercase();
+        let mut lock = self.metrics.lock().unwrap();
+        for ((d, c), m) in lock.iter_mut() {
+            if *d == display && *c != codec {
+                m.connections_active = 0;
+            }
+        }
+        lock.entry((display, codec)).or_default().connections_active = connections;
+    }
+
+    // The bits per second sent to the connections, the highest of them.
+    pub fn set_bitrate(&self, display: usize, codec: CodecFormat, bitrate: u64, peak: u64) {
+        self.update(display, codec, |m| {
+            m.bitrate = bitrate;
+            m.peak_bitrate = peak;
+        });
+    }
+
+    pub fn record_display_switch(&self, display: usize, codec: CodecFormat) {
+        self.update(display, codec, |m| m.display_switches += 1);
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = self.metrics.lock().unwrap().clone();
+        let mut out = String::new();
+        let mut family = |name: &str, typ: &str, help: &str, value: &dyn Fn(&Metrics) -> String| {
+            writeln!(out, "# HELP {name} {help}").ok();
+            writeln!(out, "# TYPE {name} {typ}").ok();
+            for ((display, codec), m) in metrics.iter() {
+                let labels = format!("display_index=\"{display}\",codec=\"{codec}\"");
+                writeln!(out, "{name}{{{labels}}} {}", value(m)).ok();
+            }
+        };
+        family(
+            "rustdesk_video_frames_encoded_total",
+            "counter",
+            "The encoded video frames.",
+            &|m| m.frames_encoded.to_string(),
+        );
+        family(
+            "rustdesk_video_frames_dropped_total",
+            "counter",
+            "The frames failed to encode or skipped as duplicates.",
+            &|m| m.frames_dropped.to_string(),
+        );
+        family(
+            "rustdesk_video_bytes_encoded_total",
+            "counter",
+            "The bytes of the encoded video frames.",
+            &|m| m.bytes_encoded.to_string(),
+        );
+        family(
+            "rustdesk_video_connections_active",
+            "gauge",
+            "The connections receiving the video.",
+            &|m| m.connections_active.to_string(),
+        );
+        family(
+            "rustdesk_video_bitrate_bits_per_second",
+            "gauge",
+            "The bits sent to a connection in the last second, the highest of the connections.",
+            &|m| m.bitrate.to_string(),
+        );
+        family(
+            "rustdesk_video_peak_bitrate_bits_per_second",
+            "gauge",
+            "The bitrate of the peak 100 ms in the last second, the highest of the connections.",
+            &|m| m.peak_bitrate.to_string(),
+        );
+        family(
+            "rustdesk_video_display_switches_total",
+            "counter",
+            "The display changes sent to the connections.",
+            &|m| m.display_switches.to_string(),
+        );
+
+        let name = "rustdesk_video_encode_duration_seconds";
+        writeln!(out, "# HELP {name} The time to encode a frame.").ok();
+        writeln!(out, "# TYPE {name} histogram").ok();
+        for ((display, codec), m) in metrics.iter() {
+            let labels = format!("display_index=\"{display}\",codec=\"{codec}\"");
+            let mut count = 0;
+            for (bound, n) in ENCODE_DURATION_BUCKETS
+                .iter()
+                .zip(m.encode_duration_buckets.iter())
+            {
+                count += n;
+                writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {count}").ok();
+            }
+            writeln!(
+                out,
+                "{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+                m.frames_encoded
+            )
+            .ok();
+            writeln!(out, "{name}_sum{{{labels}}} {}", m.encode_duration_sum).ok();
+            writeln!(out, "{name}_count{{{labels}}} {}", m.frames_encoded).ok();
+        }
+        out
+    }
+}
+
+/// Serve the metrics at `http://{addr}/metrics` for the Prometheus scrapers.
+/// The metrics are not authenticated, bind to a local address unless the network is trusted.
+pub fn start_metrics_server(addr: SocketAddr) {
+    std::thread::spawn(move || {
+        if let Err(e) = run_metrics_server(addr) {
+            log::error!("Metrics server on {} stopped: {}", addr, e);
+        }
+    });
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run_metrics_server(addr: SocketAddr) -> ResultType<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Metrics server listening on: {}", addr);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let n = match timeout(3_000, stream.read(&mut buf)).await {
+                Ok(Ok(n)) => n,
+                _ => return,
+            };
+            let response = if buf[..n].starts_with(b"GET /metrics ") {
+                let body = VideoMetricsCollector::global().render();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_owned()
+            };
+            stream.write_all(response.as_bytes()).await.ok();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_video_metrics() {
+        let metrics = VideoMetricsCollector::default();
+        metrics.record_encoded(0, CodecFormat::VP9, 1000, Duration::from_millis(3));
+        metrics.record_encoded(0, CodecFormat::VP9, 500, Duration::from_millis(30));
+        metrics.record_encoded(1, CodecFormat::H264, 200, Duration::from_secs(1));
+        metrics.record_dropped(0, CodecFormat::VP9);
+        metrics.set_connections(0, CodecFormat::VP9, 2);
+        metrics.record_display_switch(1, CodecFormat::H264);
+        metrics.set_bitrate(0, CodecFormat::VP9, 8000, 16000);
+        let text = metrics.render();
+        for line in [
+            "rustdesk_video_frames_encoded_total{display_index=\"0\",codec=\"vp9\"} 2",
+            "rustdesk_video_frames_encoded_total{display_index=\"1\",codec=\"h264\"} 1",
+            "rustdesk_video_frames_dropped_total{display_index=\"0\",codec=\"vp9\"} 1",
+            "rustdesk_video_bytes_encoded_total{display_index=\"0\",codec=\"vp9\"} 1500",
+            "rustdesk_video_connections_active{display_index=\"0\",codec=\"vp9\"} 2",
+            "rustdesk_video_display_switches_total{display_index=\"1\",codec=\"h264\"} 1",
+            "rustdesk_video_bitrate_bits_per_second{display_index=\"0\",codec=\"vp9\"} 8000",
+            "rustdesk_video_peak_bitrate_bits_per_second{display_index=\"0\",codec=\"vp9\"} 16000",
+            "rustdesk_video_encode_duration_seconds_bucket{display_index=\"0\",codec=\"vp9\",le=\"0.005\"} 1",
+            "rustdesk_video_encode_duration_seconds_bucket{display_index=\"0\",codec=\"vp9\",le=\"0.05\"} 2",
+            "rustdesk_video_encode_duration_seconds_bucket{display_index=\"1\",codec=\"h264\",le=\"0.25\"} 0",
+            "rustdesk_video_encode_duration_seconds_count{display_index=\"1\",codec=\"h264\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line}");
+        }
+        // Switching the codec moves the connections.
+        metrics.set_connections(0, CodecFormat::AV1, 2);
+        let text = metrics.render();
+        assert!(
+            text.lines()
+                .any(|l| l
+                    == "rustdesk_video_connections_active{display_index=\"0\",codec=\"vp9\"} 0")
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use super::*;
+use crate::common::ImageQualityKind;
 use scrap::codec::{Quality, BR_BALANCED, BR_BEST, BR_SPEED};
 use std::{
     collections::VecDeque,
@@ -43,6 +44,13 @@ const HISTORY_DELAY_LEN: usize = 2;
 const ADJUST_RATIO_INTERVAL: usize = 3; // Adjust quality ratio every 3 seconds
 const DYNAMIC_SCREEN_THRESHOLD: usize = 2; // Allow increase quality ratio if encode more than 2 times in one second
 const DELAY_THRESHOLD_150MS: u32 = 150; // 150ms is the threshold for good network condition
+const DELAY_THRESHOLD_300MS: u32 = 300; // 300ms is the threshold for bad network condition
+const HISTORY_ACK_WAIT_LEN: usize = 5; // Seconds of frame ack wait history
+const ACK_WAIT_SLOW_MS: u32 = 100; // Clients are slow to fetch frames
+const ACK_WAIT_VERY_SLOW_MS: u32 = 200; // Clients are very slow to fetch frames
+const ACK_WAIT_RECOVER_MS: u32 = 50; // Clients have headroom again
+const ACK_WAIT_SLOW_FPS: u32 = 15;
+const ACK_WAIT_VERY_SLOW_FPS: u32 = 10;
 
 #[derive(Default, Debug, Clone)]
 struct UserDelay {
@@ -52,11 +60,13 @@ struct UserDelay {
     rtt_calculator: RttCalculator,
     quick_increase_fps_count: usize,
     increase_fps_count: usize,
+    histogram: LatencyHistogram,
 }
 
 impl UserDelay {
     fn add_delay(&mut self, delay: u32) {
         self.rtt_calculator.update(delay);
+        self.histogram.add(delay);
         if self.delay_history.len() > HISTORY_DELAY_LEN {
             self.delay_history.pop_front();
         }
@@ -93,12 +103,103 @@ struct UserData {
     quality: Option<(i64, Quality)>, // (time, quality)
     delay: UserDelay,
     record: bool,
+    audio: bool,               // subscribed to the audio service
+    cache_key: Option<String>, // the stable client identifier in `QosCache`
+}
+
+// The audio streams share the link with the video, their bitrate is taken out of the bandwidth
+// the video bitrate is computed from.
+#[derive(Default, Debug, Clone, Copy)]
+struct BitrateBudgetTracker {
+    audio_overhead_kbps: u32, // of one audio stream, 0 to ignore the audio
+}
+
+impl BitrateBudgetTracker {
+    // The part of `total_kbps` left for the video.
+    fn video_kbps(&self, total_kbps: u32, audio: bool) -> u32 {
+        if audio {
+            total_kbps.saturating_sub(self.audio_overhead_kbps)
+        } else {
+            total_kbps
+        }
+    }
+}
+
+// The last preferences of a client, restored when it reconnects, e.g. after a network blip.
+#[derive(Debug, Clone)]
+struct CachedPreferences {
+    quality: Option<Quality>,
+    custom_fps: Option<u32>,
+}
+
+// The least recently updated entries are dropped first. It's small, no need of a map.
+#[derive(Default, Debug)]
+struct QosCache {
+    capacity: usize, // 0 disables the cache
+    entries: VecDeque<(String, CachedPreferences)>,
+}
+
+impl QosCache {
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries.truncate(capacity);
+    }
+
+    fn get(&self, key: &str) -> Option<&CachedPreferences> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, p)| p)
+    }
+
+    fn update(&mut self, key: &str, f: impl FnOnce(&mut CachedPreferences)) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut prefs = match self.entries.iter().position(|(k, _)| k == key) {
+            Some(i) => self.entries.remove(i).map(|(_, p)| p),
+            None => None,
+        }
+        .unwrap_or(CachedPreferences {
+            quality: None,
+            custom_fps: None,
+        });
+        f(&mut prefs);
+        self.entries.push_front((key.to_owned(), prefs));
+        self.entries.truncate(self.capacity);
+    }
 }
 
 #[derive(Default, Debug, Clone)]
 struct DisplayData {
     send_counter: usize, // Number of times encode during period
     support_changing_quality: bool,
+    ack_wait_history: VecDeque<u32>, // Average frame ack wait per second, in ms
+}
+
+impl DisplayData {
+    fn avg_ack_wait(&self) -> Option<u32> {
+        let len = self.ack_wait_history.len();
+        if len > 0 {
+            Some(self.ack_wait_history.iter().sum::<u32>() / len as u32)
+        } else {
+            None
+        }
+    }
+}
+
+/// How the image qualities and network delays of the connections are combined.
+/// All connections share the encoder of a display, so there is one target for all of them.
+/// A separate encoder per connection is not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QualityAggregation {
+    /// The quality set most recently by any connection, adjusted to the highest delay.
+    #[default]
+    Latest,
+    /// The lowest quality, adjusted to the highest delay. No connection falls behind.
+    Min,
+    /// The highest quality, adjusted to the lowest delay.
+    /// A slow connection doesn't degrade the others, but it may lag.
+    Max,
+    /// The mean quality, adjusted to the mean delay.
+    Mean,
 }
 
 // Main QoS controller structure
@@ -111,6 +212,10 @@ pub struct VideoQoS {
     adjust_ratio_instant: Instant,
     abr_config: bool,
     new_user_instant: Instant,
+    ack_wait_fps: Option<u32>, // FPS cap when clients are slow to fetch frames
+    quality_aggregation: QualityAggregation,
+    cache: QosCache,
+    budget: BitrateBudgetTracker,
 }
 
 impl Default for VideoQoS {
@@ -124,6 +229,10 @@ impl Default for VideoQoS {
             adjust_ratio_instant: Instant::now(),
             abr_config: true,
             new_user_instant: Instant::now(),
+            ack_wait_fps: None,
+            quality_aggregation: Default::default(),
+            cache: Default::default(),
+            budget: Default::default(),
         }
     }
 }
@@ -174,6 +283,29 @@ impl VideoQoS {
         }
     }
 
+    pub fn set_quality_aggregation(&mut self, aggregation: QualityAggregation) {
+        if self.quality_aggregation != aggregation {
+            log::info!(
+                "quality aggregation: {:?} -> {:?}",
+                self.quality_aggregation,
+                aggregation
+            );
+            self.quality_aggregation = aggregation;
+            self.ratio = self.target_quality().ratio();
+        }
+    }
+
+    pub fn set_audio_bitrate_overhead(&mut self, kbps: u32) {
+        if self.budget.audio_overhead_kbps != kbps {
+            log::info!(
+                "audio bitrate overhead: {} -> {} kbps",
+                self.budget.audio_overhead_kbps,
+                kbps
+            );
+            self.budget.audio_overhead_kbps = kbps;
+        }
+    }
+
     // Check if variable bitrate encoding is supported and enabled
     pub fn in_vbr_state(&self) -> bool {
         self.abr_config && self.displays.iter().all(|e| e.1.support_changing_quality)
@@ -193,7 +325,40 @@ impl VideoQoS {
     pub fn on_connection_close(&mut self, id: i32) {
         self.users.remove(&id);
         if self.users.is_empty() {
+            let quality_aggregation = self.quality_aggregation;
+            let cache = std::mem::take(&mut self.cache);
+            let budget = self.budget;
             *self = Default::default();
+            self.quality_aggregation = quality_aggregation;
+            self.cache = cache;
+            self.budget = budget;
+        }
+    }
+
+    /// Keep the image quality and fps of the last `capacity` clients, 0 to disable it.
+    pub fn set_qos_cache_capacity(&mut self, capacity: usize) {
+        self.cache.set_capacity(capacity);
+    }
+
+    // Restore the preferences of the client until it sends them again.
+    // `key` identifies the client across the connections, e.g. its id.
+    pub fn restore_user_preferences(&mut self, id: i32, key: &str) {
+        if self.cache.capacity == 0 {
+            return;
+        }
+        let prefs = self.cache.get(key).cloned();
+        let Some(user) = self.users.get_mut(&id) else {
+            return;
+        };
+        user.cache_key = Some(key.to_owned());
+        let Some(prefs) = prefs else {
+            return;
+        };
+        log::info!("restore qos preferences of {}: {:?}", key, prefs);
+        user.custom_fps = prefs.custom_fps;
+        if let Some(quality) = prefs.quality {
+            user.quality = Some((hbb_common::get_time(), quality));
+            self.ratio = self.target_quality().ratio();
         }
     }
 
@@ -203,6 +368,9 @@ impl VideoQoS {
         }
         if let Some(user) = self.users.get_mut(&id) {
             user.custom_fps = Some(fps);
+            if let Some(key) = user.cache_key.as_ref() {
+                self.cache.update(key, |p| p.custom_fps = Some(fps));
+            }
         }
     }
 
@@ -215,26 +383,35 @@ impl VideoQoS {
         }
     }
 
-    pub fn user_image_quality(&mut self, id: i32, image_quality: i32) {
-        let convert_quality = |q: i32| -> Quality {
-            if q == ImageQuality::Balanced.value() {
-                Quality::Balanced
-            } else if q == ImageQuality::Low.value() {
-                Quality::Low
-            } else if q == ImageQuality::Best.value() {
-                Quality::Best
-            } else {
-                let b = ((q >> 8 & 0xFFF) * 2) as f32 / 100.0;
-                Quality::Custom(b.clamp(BR_MIN, BR_MAX))
+    pub fn user_image_quality(&mut self, id: i32, image_quality: i32) -> ResultType<()> {
+        let params = crate::common::quality_to_human_readable(image_quality);
+        if let Err(e) = params.validate() {
+            log::warn!("Ignore image quality {:#x} of {}: {}", image_quality, id, e);
+            return Err(e);
+        }
+        let convert_quality = |kind: ImageQualityKind| -> Quality {
+            match kind {
+                ImageQualityKind::Balanced => Quality::Balanced,
+                ImageQualityKind::Low => Quality::Low,
+                ImageQualityKind::Best => Quality::Best,
+                ImageQualityKind::Custom { bitrate_pct, .. } => {
+                    let b = (bitrate_pct as u32 * 2) as f32 / 100.0;
+                    Quality::Custom(b.clamp(BR_MIN, BR_MAX))
+                }
+                ImageQualityKind::NotSet => Quality::Custom(BR_MIN),
             }
         };
 
-        let quality = Some((hbb_common::get_time(), convert_quality(image_quality)));
+        let quality = convert_quality(ImageQualityKind::from_value(image_quality));
         if let Some(user) = self.users.get_mut(&id) {
-            user.quality = quality;
+            if let Some(key) = user.cache_key.as_ref() {
+                self.cache.update(key, |p| p.quality = Some(quality));
+            }
+            user.quality = Some((hbb_common::get_time(), quality));
             // update ratio directly
-            self.ratio = self.latest_quality().ratio();
+            self.ratio = self.target_quality().ratio();
         }
+        Ok(())
     }
 
     pub fn user_record(&mut self, id: i32, v: bool) {
@@ -243,9 +420,15 @@ impl VideoQoS {
         }
     }
 
+    pub fn user_audio(&mut self, id: i32, v: bool) {
+        if let Some(user) = self.users.get_mut(&id) {
+            user.audio = v;
+        }
+    }
+
     pub fn user_network_delay(&mut self, id: i32, delay: u32) {
         let highest_fps = self.highest_fps();
-        let target_ratio = self.latest_quality().ratio();
+        let target_ratio = self.target_quality().ratio();
 
         // For bad network, small fps means quick reaction and high quality
         let (min_fps, normal_fps) = if target_ratio >= BR_BEST {
@@ -332,6 +515,13 @@ impl VideoQoS {
         }
     }
 
+    // (p50, p95, p99) of the recent network delays of the user, in ms
+    pub fn latency_percentiles(&self, id: i32) -> Option<(u32, u32, u32)> {
+        self.users
+            .get(&id)
+            .and_then(|user| user.delay.histogram.percentiles())
+    }
+
     pub fn user_delay_response_elapsed(&mut self, id: i32, elapsed: u128) {
         if let Some(user) = self.users.get_mut(&id) {
             user.delay.response_delayed = elapsed > 2000;
@@ -353,10 +543,26 @@ impl VideoQoS {
         self.displays.remove(&display_idx);
     }
 
-    pub fn update_display_data(&mut self, display_idx: usize, send_counter: usize) {
+    // Called once per second by the video service.
+    // `ack_wait` is the average time spent waiting for clients to fetch a frame.
+    pub fn update_display_data(
+        &mut self,
+        display_idx: usize,
+        send_counter: usize,
+        ack_wait: Option<Duration>,
+    ) {
         if let Some(display) = self.displays.get_mut(&display_idx) {
             display.send_counter += send_counter;
+            if let Some(ack_wait) = ack_wait {
+                if display.ack_wait_history.len() >= HISTORY_ACK_WAIT_LEN {
+                    display.ack_wait_history.pop_front();
+                }
+                display
+                    .ack_wait_history
+                    .push_back(ack_wait.as_millis() as u32);
+            }
         }
+        self.adjust_ack_wait_fps();
         self.adjust_fps();
         let abr_enabled = self.in_vbr_state();
         if abr_enabled {
@@ -371,7 +577,7 @@ impl VideoQoS {
                 self.adjust_ratio(dynamic_screen);
             }
         } else {
-            self.ratio = self.latest_quality().ratio();
+            self.ratio = self.target_quality().ratio();
         }
     }
 
@@ -398,34 +604,65 @@ impl VideoQoS {
         fps.clamp(MIN_FPS, MAX_FPS)
     }
 
-    // Get latest quality settings from all users
-    pub fn latest_quality(&self) -> Quality {
-        self.users
-            .iter()
-            .map(|(_, u)| u.quality)
-            .filter(|q| *q != None)
-            .max_by(|a, b| a.unwrap_or_default().0.cmp(&b.unwrap_or_default().0))
-            .flatten()
-            .unwrap_or((0, Quality::Balanced))
-            .1
+    // Get the quality of all users according to the aggregation
+    pub fn target_quality(&self) -> Quality {
+        let qualities = self.users.iter().filter_map(|(_, u)| u.quality);
+        let by_ratio = |a: &(i64, Quality), b: &(i64, Quality)| a.1.ratio().total_cmp(&b.1.ratio());
+        let quality = match self.quality_aggregation {
+            QualityAggregation::Latest => qualities.max_by_key(|q| q.0),
+            QualityAggregation::Min => qualities.min_by(by_ratio),
+            QualityAggregation::Max => qualities.max_by(by_ratio),
+            QualityAggregation::Mean => {
+                let qualities: Vec<_> = qualities.collect();
+                if qualities.iter().all(|q| q.1 == qualities[0].1) {
+                    qualities.first().copied()
+                } else {
+                    let sum = qualities.iter().map(|q| q.1.ratio()).sum::<f32>();
+                    Some((0, Quality::Custom(sum / qualities.len() as f32)))
+                }
+            }
+        };
+        quality.map(|q| q.1).unwrap_or(Quality::Balanced)
+    }
+
+    // Get the network delay of all users according to the aggregation
+    fn target_delay(&self) -> Option<u32> {
+        let delays = self.users.iter().map(|u| u.1.delay.avg_delay());
+        match self.quality_aggregation {
+            QualityAggregation::Latest | QualityAggregation::Min => delays.max(),
+            QualityAggregation::Max => delays.min(),
+            QualityAggregation::Mean => {
+                let delays: Vec<_> = delays.collect();
+                if delays.is_empty() {
+                    None
+                } else {
+                    Some(delays.iter().sum::<u32>() / delays.len() as u32)
+                }
+            }
+        }
     }
 
     // Adjust quality ratio based on network delay and screen changes
     fn adjust_ratio(&mut self, dynamic_screen: bool) {
-        // Get maximum delay from all users
-        let max_delay = self.users.iter().map(|u| u.1.delay.avg_delay()).max();
-        let Some(max_delay) = max_delay else {
+        // Get the maximum delay from all users, or the aggregated one
+        let Some(max_delay) = self.target_delay() else {
             return;
         };
 
-        let target_quality = self.latest_quality();
-        let target_ratio = self.latest_quality().ratio();
+        let target_quality = self.target_quality();
+        let target_ratio = self.target_quality().ratio();
         let current_ratio = self.ratio;
         let current_bitrate = self.bitrate();
+        // The audio streams take their part of the 1Mbps baseline.
+        let audio = self.users.values().any(|u| u.audio);
+        let baseline_kbps = self.budget.video_kbps(1000, audio);
 
         // Calculate minimum ratio for high resolution (1Mbps baseline)
         let ratio_1mbps = if current_bitrate > 0 {
-            Some((current_ratio * 1000.0 / current_bitrate as f32).max(BR_MIN_HIGH_RESOLUTION))
+            Some(
+                (current_ratio * baseline_kbps as f32 / current_bitrate as f32)
+                    .max(BR_MIN_HIGH_RESOLUTION),
+            )
         } else {
             None
         };
@@ -461,7 +698,9 @@ impl VideoQoS {
             Quality::Low => BR_MIN_HIGH_RESOLUTION,
             Quality::Custom(_) => BR_MIN_HIGH_RESOLUTION,
         };
-        let max = target_ratio * MAX_BR_MULTIPLE;
+        // Halve the ceiling on slow networks and quarter it on bad networks,
+        // so that a high delay user recovers quickly instead of walking down 5% every period.
+        let max = (target_ratio * MAX_BR_MULTIPLE / Self::delay_ratio_divisor(max_delay)).max(min);
 
         let mut v = current_ratio;
 
@@ -502,6 +741,48 @@ impl VideoQoS {
         self.adjust_ratio_instant = Instant::now();
     }
 
+    // Cap fps when clients are consistently slow to fetch frames, e.g. the connection is saturated.
+    // It is called at most once per second, which keeps the cap from oscillating.
+    fn adjust_ack_wait_fps(&mut self) {
+        let Some(max_wait) = self
+            .displays
+            .iter()
+            .filter_map(|d| d.1.avg_ack_wait())
+            .max()
+        else {
+            return;
+        };
+        let cap = if max_wait >= ACK_WAIT_VERY_SLOW_MS {
+            Some(ACK_WAIT_VERY_SLOW_FPS)
+        } else if max_wait >= ACK_WAIT_SLOW_MS {
+            Some(ACK_WAIT_SLOW_FPS)
+        } else if max_wait < ACK_WAIT_RECOVER_MS {
+            None
+        } else {
+            self.ack_wait_fps
+        };
+        if cap != self.ack_wait_fps {
+            log::info!(
+                "frame ack wait {}ms, fps cap: {:?} -> {:?}",
+                max_wait,
+                self.ack_wait_fps,
+                cap
+            );
+            self.ack_wait_fps = cap;
+        }
+    }
+
+    #[inline]
+    fn delay_ratio_divisor(delay: u32) -> f32 {
+        if delay >= DELAY_THRESHOLD_300MS {
+            4.0
+        } else if delay >= DELAY_THRESHOLD_150MS {
+            2.0
+        } else {
+            1.0
+        }
+    }
+
     // Adjust fps based on network delay and user response time
     fn adjust_fps(&mut self) {
         let highest_fps = self.highest_fps();
@@ -526,11 +807,44 @@ impl VideoQoS {
             }
         }
 
+        if let Some(cap) = self.ack_wait_fps {
+            if fps > cap {
+                fps = cap;
+            }
+        }
+
         // Ensure fps stays within valid range
         self.fps = fps.clamp(MIN_FPS, highest_fps);
     }
 }
 
+#[derive(Default, Debug, Clone)]
+struct LatencyHistogram {
+    samples: VecDeque<u32>, // Last 100 delay samples
+}
+
+impl LatencyHistogram {
+    const WINDOW_SAMPLES: usize = 100;
+
+    fn add(&mut self, delay: u32) {
+        if self.samples.len() >= Self::WINDOW_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(delay);
+    }
+
+    // Nearest-rank percentiles
+    fn percentiles(&self) -> Option<(u32, u32, u32)> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<u32> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = |p: usize| sorted[((sorted.len() * p + 99) / 100).max(1) - 1];
+        Some((rank(50), rank(95), rank(99)))
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 struct RttCalculator {
     min_rtt: Option<u32>,        // Historical minimum RTT ever observed
@@ -18,17 +18,23 @@
 // to-do:
 // https://slhck.info/video/2017/03/01/rate-control.html
 
+pub use super::video_qos::QualityAggregation;
 use super::{
+    crossfade::CrossfadeBlender,
     display_service::{check_display_changed, get_display_info},
     service::ServiceTmpl,
+    video_metrics::VideoMetricsCollector,
     video_qos::VideoQoS,
+    watermark::{watermark_text, WatermarkRenderer},
     *,
 };
 #[cfg(target_os = "linux")]
 use crate::common::SimpleCallOnReturn;
 #[cfg(target_os = "linux")]
 use crate::platform::linux::is_x11;
-use crate::privacy_mode::{get_privacy_mode_conn_id, INVALID_PRIVACY_MODE_CONN_ID};
+use crate::privacy_mode::{
+    get_privacy_mode_conn_id, get_privacy_mode_conn_ids, INVALID_PRIVACY_MODE_CONN_ID,
+};
 #[cfg(windows)]
 use crate::{
     platform::windows::is_process_consent_running,
@@ -40,7 +46,7 @@ use hbb_common::{
     config,
     tokio::sync::{
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        Mutex as TokioMutex,
+        watch, Mutex as TokioMutex,
     },
 };
 #[cfg(feature = "hwcodec")]
@@ -51,84 +57,2349 @@ use scrap::vram::{VRamEncoder, VRamEncoderConfig};
 use scrap::Capturer;
 use scrap::{
     aom::AomEncoderConfig,
-    codec::{Encoder, EncoderCfg},
+    codec::{Encoder, EncoderCfg, RoiRect},
     record::{Recorder, RecorderContext},
     vpxcodec::{VpxEncoderConfig, VpxVideoCodecId},
-    CodecFormat, Display, EncodeInput, TraitCapturer,
+    CaptureError, CodecFormat, Display, EncodeInput, TraitCapturer,
 };
 #[cfg(windows)]
 use std::sync::Once;
 use std::{
-    collections::HashSet,
-    io::ErrorKind::WouldBlock,
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
     ops::{Deref, DerefMut},
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
     time::{self, Duration, Instant},
 };
 
-pub const NAME: &'static str = "video";
-pub const OPTION_REFRESH: &'static str = "refresh";
+pub const NAME: &'static str = "video";
+pub const OPTION_REFRESH: &'static str = "refresh";
+
+thread_local! {
+    // The encoder of the last run of the video service on this thread, kept on the display
+    // changes so that the next run can resize it instead of creating a new one.
+    static REUSABLE_ENCODER: RefCell<Option<(EncoderCfg, bool, Encoder)>> = RefCell::new(None);
+    static CAPTURER_POOL: RefCell<CapturerPool> = RefCell::new(CapturerPool::default());
+}
+const OPTION_MAX_QUANTIZER: &'static str = "max-quantizer";
+// Deinterlace the captured frames for VP9, for the interlaced content from capture cards.
+const OPTION_DEINTERLACE: &'static str = "deinterlace";
+// Force a keyframe at least every n seconds whatever the keyframe policy is, 0 disables it.
+const OPTION_FORCE_KEYFRAME_INTERVAL: &'static str = "force-keyframe-interval-secs";
+const DEFAULT_FORCE_KEYFRAME_INTERVAL_SECS: u64 = 10;
+
+// Written by the uac elevation check thread, read by the connections every second.
+pub static IS_UAC_RUNNING: AtomicBool = AtomicBool::new(false);
+pub static IS_FOREGROUND_WINDOW_ELEVATED: AtomicBool = AtomicBool::new(false);
+// The captured frames dropped by `is_frame_valid()`, since the process started.
+pub static INVALID_FRAMES_TOTAL: AtomicU64 = AtomicU64::new(0);
+// The display switches are deferred while it is not 0, see `lock_display_switch()`.
+static DISPLAY_SWITCH_LOCKS: AtomicU32 = AtomicU32::new(0);
+// The video services in `run()`, waited by `shutdown_gracefully()`.
+static RUNNING_VIDEO_SERVICES: AtomicUsize = AtomicUsize::new(0);
+
+// (connection id, session token, frame time)
+type FrameFetched = (i32, u64, Option<Instant>);
+type FrameFetchedNotifier = (
+    UnboundedSender<FrameFetched>,
+    Arc<TokioMutex<UnboundedReceiver<FrameFetched>>>,
+);
+
+lazy_static::lazy_static! {
+    static ref FRAME_FETCHED_NOTIFIER: FrameFetchedNotifier = {
+        let (tx, rx) = unbounded_channel();
+        (tx, Arc::new(TokioMutex::new(rx)))
+    };
+    // The session tokens of the open connections.
+    static ref SESSION_TOKENS: Mutex<HashMap<i32, u64>> = Default::default();
+    pub static ref VIDEO_QOS: Arc<Mutex<VideoQoS>> = Default::default();
+    static ref VIDEO_SERVICE_HANDLE: VideoServiceHandle = VideoServiceHandle::new_isolated();
+    static ref RECORDINGS: Mutex<HashMap<usize, Arc<RecordingSession>>> = Default::default();
+}
+
+/// The tunables and counters shared by the video services of all displays.
+///
+/// The video services use `VideoServiceHandle::global()`, the public functions of this
+/// module are shortcuts to it. Tests can use `new_isolated()` to avoid sharing state.
+pub struct VideoServiceHandle {
+    stats: (
+        watch::Sender<VideoServiceStats>,
+        watch::Receiver<VideoServiceStats>,
+    ),
+    encode_dropped_frames: AtomicU64,
+    display_poll_interval_ms: AtomicU64, // 0 disables polling the display changes
+    capture_wait_base_ms: AtomicI32,     // <= 0 to follow the refresh rate of the display
+    draw_cursor: AtomicBool,
+    dedup_threshold: AtomicU8, // 0 disables the frame deduplication
+    dedup_checked: AtomicU64,
+    dedup_skipped: AtomicU64,
+    keyframe_policy: Mutex<KeyframePolicy>,
+    paused_conn_ids: Mutex<HashSet<i32>>,
+    conn_priorities: Mutex<HashMap<i32, Priority>>, // Normal is not stored
+    // The skipped background connections waiting for a keyframe, and since when.
+    starved_conn_ids: Mutex<HashMap<i32, Instant>>,
+    roi_provider: Mutex<Option<Box<dyn RoiProvider>>>,
+    capture_region: Mutex<Option<CaptureRegion>>,
+    scaled_capture: Mutex<Option<(usize, usize)>>,
+    auto_scale_policy: Mutex<AutoScalePolicy>,
+    auto_scale_widths: Mutex<HashMap<usize, usize>>, // the max frame width of the displays
+    turbo_params: Mutex<TurboParams>,
+    motion_adaptive_fps: Mutex<MotionAdaptiveFps>,
+    slow_client_policy: Mutex<SlowClientPolicy>,
+    video_mode: Mutex<VideoMode>,
+    keyframe_buffer_depth: AtomicUsize, // 0 disables the replay to the new subscribers
+    keyframe_buffer_max_bytes: AtomicUsize,
+    slow_client_warnings: (
+        watch::Sender<Option<SlowClientWarning>>,
+        watch::Receiver<Option<SlowClientWarning>>,
+    ),
+    pending_refresh: Mutex<Option<Instant>>,
+    refresh_seq: AtomicU64, // increased on every debounced refresh
+    health: Mutex<HealthState>,
+    crossfade_frames: AtomicUsize, // 0 disables the crossfade on the display switches
+    conn_bitrates: Mutex<HashMap<i32, BitrateMeter>>,
+    capture_thread_affinity: Mutex<Option<Vec<usize>>>, // None to leave it to the os
+    encode_thread_priority: Mutex<ThreadPriority>,
+    drain_deadline: Mutex<Option<Instant>>, // set by `shutdown_gracefully()`
+    watermark: Mutex<WatermarkConfig>,
+}
+
+impl VideoServiceHandle {
+    #[inline]
+    pub fn global() -> &'static VideoServiceHandle {
+        &VIDEO_SERVICE_HANDLE
+    }
+
+    pub fn new_isolated() -> Self {
+        Self {
+            stats: watch::channel(Default::default()),
+            encode_dropped_frames: AtomicU64::new(0),
+            display_poll_interval_ms: AtomicU64::new(1000),
+            capture_wait_base_ms: AtomicI32::new(0),
+            draw_cursor: AtomicBool::new(false),
+            dedup_threshold: AtomicU8::new(0),
+            dedup_checked: AtomicU64::new(0),
+            dedup_skipped: AtomicU64::new(0),
+            keyframe_policy: Default::default(),
+            paused_conn_ids: Default::default(),
+            conn_priorities: Default::default(),
+            starved_conn_ids: Default::default(),
+            roi_provider: Default::default(),
+            capture_region: Default::default(),
+            scaled_capture: Default::default(),
+            auto_scale_policy: Default::default(),
+            auto_scale_widths: Default::default(),
+            turbo_params: Default::default(),
+            motion_adaptive_fps: Default::default(),
+            slow_client_policy: Default::default(),
+            video_mode: Default::default(),
+            keyframe_buffer_depth: AtomicUsize::new(KEYFRAME_BUFFER_DEPTH),
+            keyframe_buffer_max_bytes: AtomicUsize::new(KEYFRAME_BUFFER_MAX_BYTES),
+            slow_client_warnings: watch::channel(None),
+            pending_refresh: Default::default(),
+            refresh_seq: AtomicU64::new(0),
+            health: Default::default(),
+            crossfade_frames: AtomicUsize::new(CROSSFADE_FRAMES),
+            conn_bitrates: Default::default(),
+            capture_thread_affinity: Default::default(),
+            encode_thread_priority: Default::default(),
+            drain_deadline: Default::default(),
+            watermark: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn subscribe_stats(&self) -> watch::Receiver<VideoServiceStats> {
+        self.stats.1.clone()
+    }
+
+    fn publish_stats(
+        &self,
+        display_idx: usize,
+        encode_latency: Duration,
+        conn_ids: &HashSet<i32>,
+    ) -> VideoServiceStats {
+        let (mut actual_bitrate, mut peak_bitrate) = (0, 0);
+        let now = Instant::now();
+        let mut bitrates = self.conn_bitrates.lock().unwrap();
+        for id in conn_ids {
+            if let Some(meter) = bitrates.get_mut(id) {
+                actual_bitrate = actual_bitrate.max(meter.actual(now));
+                peak_bitrate = peak_bitrate.max(meter.peak(now));
+            }
+        }
+        drop(bitrates);
+        let stats = VideoServiceStats {
+            display_idx,
+            encode_latency,
+            dropped_frames: self.encode_dropped_frames.load(Ordering::Relaxed)
+                + self.dedup_skipped.load(Ordering::Relaxed),
+            fps: VIDEO_QOS.lock().unwrap().fps(),
+            connections: conn_ids.len(),
+            actual_bitrate,
+            peak_bitrate,
+        };
+        self.stats.0.send_replace(stats.clone());
+        stats
+    }
+
+    fn record_sent_bytes(&self, conn_ids: &HashSet<i32>, bytes: usize) {
+        let now = Instant::now();
+        let mut bitrates = self.conn_bitrates.lock().unwrap();
+        for id in conn_ids {
+            bitrates.entry(*id).or_default().record(now, bytes);
+        }
+    }
+
+    /// The bits per second sent to a connection in the last second.
+    pub fn get_actual_bitrate(&self, conn_id: i32) -> u64 {
+        self.conn_bitrates
+            .lock()
+            .unwrap()
+            .get_mut(&conn_id)
+            .map(|m| m.actual(Instant::now()))
+            .unwrap_or(0)
+    }
+
+    /// The bits per second of the peak 100 ms sent to a connection in the last second.
+    pub fn get_peak_bitrate(&self, conn_id: i32) -> u64 {
+        self.conn_bitrates
+            .lock()
+            .unwrap()
+            .get_mut(&conn_id)
+            .map(|m| m.peak(Instant::now()))
+            .unwrap_or(0)
+    }
+
+    /// Remove the states of a closed connection.
+    pub fn on_connection_close(&self, conn_id: i32) {
+        self.set_connection_priority(conn_id, Priority::Normal);
+        let mut bitrates = self.conn_bitrates.lock().unwrap();
+        bitrates.remove(&conn_id);
+        if bitrates.is_empty() {
+            // The next connections start from the full resolution.
+            self.auto_scale_widths.lock().unwrap().clear();
+        }
+    }
+
+    #[inline]
+    pub fn set_display_poll_interval(&self, ms: u64) {
+        self.display_poll_interval_ms.store(ms, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn display_poll_interval(&self) -> u64 {
+        self.display_poll_interval_ms.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_dedup_threshold(&self, bits: u8) {
+        self.dedup_threshold.store(bits.min(64), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn dedup_stats(&self) -> DedupStats {
+        DedupStats {
+            checked: self.dedup_checked.load(Ordering::Relaxed),
+            skipped: self.dedup_skipped.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn set_keyframe_policy(&self, p: KeyframePolicy) {
+        let mut lock = self.keyframe_policy.lock().unwrap();
+        if *lock != p {
+            log::info!("keyframe policy: {:?} -> {:?}", *lock, p);
+            *lock = p;
+        }
+    }
+
+    #[inline]
+    pub fn keyframe_policy(&self) -> KeyframePolicy {
+        *self.keyframe_policy.lock().unwrap()
+    }
+
+    /// Refresh the video services after `delay`, a new request within the delay replaces
+    /// the pending one.
+    pub fn refresh_debounced(&self, delay: Duration) {
+        *self.pending_refresh.lock().unwrap() = Some(Instant::now() + delay);
+    }
+
+    // The video services restart if the sequence is changed.
+    fn poll_refresh(&self) -> u64 {
+        let mut lock = self.pending_refresh.lock().unwrap();
+        if lock.map_or(false, |due| due <= Instant::now()) {
+            *lock = None;
+            self.refresh_seq.fetch_add(1, Ordering::Relaxed);
+        }
+        self.refresh_seq.load(Ordering::Relaxed)
+    }
+
+    pub fn pause_capture(&self, conn_id: i32) {
+        if self.paused_conn_ids.lock().unwrap().insert(conn_id) {
+            log::info!("pause capture for connection {conn_id}");
+        }
+    }
+
+    pub fn resume_capture(&self, conn_id: i32) {
+        if self.paused_conn_ids.lock().unwrap().remove(&conn_id) {
+            log::info!("resume capture for connection {conn_id}");
+        }
+    }
+
+    #[inline]
+    pub fn paused_conn_ids(&self) -> HashSet<i32> {
+        self.paused_conn_ids.lock().unwrap().clone()
+    }
+
+    pub fn is_healthy(&self) -> HealthStatus {
+        let health = self.health.lock().unwrap();
+        let last_frame_age_ms = health
+            .last_frame
+            .map(|t| t.elapsed().as_millis() as u64)
+            .unwrap_or(u64::MAX);
+        HealthStatus {
+            capturing: last_frame_age_ms <= HEALTH_MAX_FRAME_AGE_MS,
+            encoding: health.encode_error.is_none(),
+            last_frame_age_ms,
+            error: health
+                .capture_error
+                .clone()
+                .or_else(|| health.encode_error.clone()),
+        }
+    }
+
+    // A frame is captured, or the display has not changed.
+    fn on_captured(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.last_frame = Some(Instant::now());
+        health.capture_error = None;
+    }
+
+    fn on_capture_error(&self, e: &CaptureError) {
+        self.health.lock().unwrap().capture_error = Some(format!("capture: {e}"));
+    }
+
+    fn on_encoded(&self, error: Option<String>) {
+        self.health.lock().unwrap().encode_error = error.map(|e| format!("encode: {e}"));
+    }
+
+    #[inline]
+    pub fn set_crossfade_frames(&self, frames: usize) {
+        self.crossfade_frames.store(frames, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn crossfade_frames(&self) -> usize {
+        self.crossfade_frames.load(Ordering::Relaxed)
+    }
+
+    pub fn set_watermark_config(&self, config: WatermarkConfig) {
+        log::info!("watermark config: {:?}", config);
+        let enabled_changed = {
+            let mut lock = self.watermark.lock().unwrap();
+            let changed = lock.enabled != config.enabled;
+            *lock = config;
+            changed
+        };
+        // The vram encoders can't be drawn, the video services restart to choose the encoders.
+        if enabled_changed {
+            self.refresh_debounced(Duration::ZERO);
+        }
+    }
+
+    #[inline]
+    pub fn watermark_config(&self) -> WatermarkConfig {
+        self.watermark.lock().unwrap().clone()
+    }
+
+    pub fn set_capture_thread_affinity(&self, cpus: &[usize]) -> ResultType<()> {
+        let count = num_cpus::get();
+        if let Some(cpu) = cpus.iter().find(|cpu| **cpu >= count) {
+            bail!("cpu {} is out of range, the cpu count is {}", cpu, count);
+        }
+        // The threads are reused by the video services, reset to all the cpus.
+        let cpus = if cpus.is_empty() {
+            (0..count).collect()
+        } else {
+            cpus.to_vec()
+        };
+        log::info!("capture thread affinity: {:?}", cpus);
+        *self.capture_thread_affinity.lock().unwrap() = Some(cpus);
+        // Restart the video services to apply it on their threads.
+        self.refresh_debounced(Duration::ZERO);
+        Ok(())
+    }
+
+    fn apply_capture_thread_affinity(&self) {
+        let Some(_cpus) = self.capture_thread_affinity.lock().unwrap().clone() else {
+            return;
+        };
+        #[cfg(any(target_os = "linux", windows))]
+        if let Err(e) = crate::platform::set_current_thread_affinity(&_cpus) {
+            log::error!("Failed to set capture thread affinity: {e}");
+        }
+    }
+
+    pub fn set_encode_thread_priority(&self, priority: ThreadPriority) {
+        let mut lock = self.encode_thread_priority.lock().unwrap();
+        if *lock != priority {
+            log::info!("encode thread priority: {:?} -> {:?}", *lock, priority);
+            *lock = priority;
+            // Restart the video services to apply it on their threads.
+            self.refresh_debounced(Duration::ZERO);
+        }
+    }
+
+    // Also to `Normal`, the threads are reused by the video services.
+    fn apply_encode_thread_priority(&self) {
+        let _priority = *self.encode_thread_priority.lock().unwrap();
+        #[cfg(windows)]
+        let res = {
+            use winapi::um::winbase::{
+                THREAD_PRIORITY_ABOVE_NORMAL, THREAD_PRIORITY_HIGHEST, THREAD_PRIORITY_NORMAL,
+            };
+            crate::platform::set_current_thread_priority(match _priority {
+                ThreadPriority::Normal => THREAD_PRIORITY_NORMAL as _,
+                ThreadPriority::AboveNormal => THREAD_PRIORITY_ABOVE_NORMAL as _,
+                ThreadPriority::High => THREAD_PRIORITY_HIGHEST as _,
+            })
+        };
+        #[cfg(target_os = "linux")]
+        let res = crate::platform::set_current_thread_nice(match _priority {
+            ThreadPriority::Normal => 0,
+            ThreadPriority::AboveNormal => -10,
+            ThreadPriority::High => -15,
+        });
+        #[cfg(any(target_os = "linux", windows))]
+        if let Err(e) = res {
+            log::error!("Failed to set encode thread priority {:?}: {e}", _priority);
+        }
+    }
+
+    /// Stop the video services after the frames in flight are fetched by the connections,
+    /// and wait for them at most `timeout`. Returns false if they have not stopped in time,
+    /// they are stopped at the frame boundary then, without waiting for the fetches.
+    /// The video services don't start again, it's for the shutdown of the process.
+    pub fn shutdown_gracefully(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        *self.drain_deadline.lock().unwrap() = Some(deadline);
+        log::info!("drain the video services, timeout: {:?}", timeout);
+        while RUNNING_VIDEO_SERVICES.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                log::warn!("video services are not drained in {:?}", timeout);
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        log::info!("video services are drained");
+        true
+    }
+
+    #[inline]
+    fn draining(&self) -> bool {
+        self.drain_deadline.lock().unwrap().is_some()
+    }
+
+    #[inline]
+    fn drain_expired(&self) -> bool {
+        self.drain_deadline
+            .lock()
+            .unwrap()
+            .map_or(false, |deadline| deadline <= Instant::now())
+    }
+
+    pub fn set_connection_priority(&self, conn_id: i32, priority: Priority) {
+        let mut lock = self.conn_priorities.lock().unwrap();
+        let old = if priority == Priority::Normal {
+            lock.remove(&conn_id)
+        } else {
+            lock.insert(conn_id, priority)
+        };
+        if old.unwrap_or_default() != priority {
+            log::info!("connection {conn_id} priority: {:?}", priority);
+        }
+        if priority != Priority::Background {
+            self.starved_conn_ids.lock().unwrap().remove(&conn_id);
+        }
+    }
+
+    #[inline]
+    pub fn connection_priority(&self, conn_id: i32) -> Priority {
+        self.conn_priorities
+            .lock()
+            .unwrap()
+            .get(&conn_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Send the frame to the connections by priority. The background connections are skipped
+    // if `skip_background`, except on the keyframes, and then wait for a keyframe since they
+    // can't decode the following frames.
+    fn send_video_frame(
+        &self,
+        sp: &GenericService,
+        msg: Arc<Message>,
+        keyframe: bool,
+        skip_background: bool,
+    ) -> (HashSet<i32>, HashSet<i32>) {
+        let mut excluded = self.paused_conn_ids();
+        {
+            let mut starved = self.starved_conn_ids.lock().unwrap();
+            if keyframe {
+                starved.clear();
+            }
+            excluded.extend(starved.keys());
+        }
+        let priorities = self.conn_priorities.lock().unwrap().clone();
+        if priorities.is_empty() {
+            return sp.send_video_frame_shared_except(msg, &excluded);
+        }
+        let skip_background = skip_background && !keyframe;
+        let (sent, failed) = sp.send_video_frame_ranked(
+            msg,
+            &excluded,
+            |id| (priorities.get(&id).copied().unwrap_or_default(), id),
+            |(priority, _)| skip_background && *priority == Priority::Background,
+        );
+        if skip_background {
+            let mut starved = self.starved_conn_ids.lock().unwrap();
+            for (id, priority) in priorities.iter() {
+                if *priority == Priority::Background
+                    && !sent.contains(id)
+                    && !failed.contains(id)
+                    && !excluded.contains(id)
+                {
+                    tracing::debug!("skip background connection {id} until the next keyframe");
+                    starved.insert(*id, Instant::now());
+                }
+            }
+        }
+        (sent, failed)
+    }
+
+    // A background connection has been skipped for too long, a keyframe is needed.
+    fn background_starved(&self) -> bool {
+        self.starved_conn_ids
+            .lock()
+            .unwrap()
+            .values()
+            .any(|since| since.elapsed() >= MAX_BACKGROUND_STARVATION)
+    }
+
+    pub fn set_slow_client_policy(&self, p: SlowClientPolicy) {
+        let mut lock = self.slow_client_policy.lock().unwrap();
+        if *lock != p {
+            log::info!("slow client policy: {:?} -> {:?}", *lock, p);
+            *lock = p;
+        }
+    }
+
+    #[inline]
+    pub fn slow_client_policy(&self) -> SlowClientPolicy {
+        *self.slow_client_policy.lock().unwrap()
+    }
+
+    pub fn set_turbo_params(&self, p: TurboParams) {
+        let mut lock = self.turbo_params.lock().unwrap();
+        if *lock != p {
+            log::info!("turbo params: {:?} -> {:?}", *lock, p);
+            *lock = p;
+        }
+    }
+
+    #[inline]
+    pub fn turbo_params(&self) -> TurboParams {
+        *self.turbo_params.lock().unwrap()
+    }
+
+    pub fn set_motion_adaptive_fps(&self, m: MotionAdaptiveFps) {
+        let mut lock = self.motion_adaptive_fps.lock().unwrap();
+        if *lock != m {
+            log::info!("motion adaptive fps: {:?} -> {:?}", *lock, m);
+            *lock = m;
+        }
+    }
+
+    #[inline]
+    pub fn motion_adaptive_fps(&self) -> MotionAdaptiveFps {
+        *self.motion_adaptive_fps.lock().unwrap()
+    }
+
+    pub fn set_video_mode(&self, mode: VideoMode) {
+        let mut lock = self.video_mode.lock().unwrap();
+        if *lock != mode {
+            log::info!("video mode: {:?} -> {:?}", *lock, mode);
+            *lock = mode;
+            scrap::vpxcodec::set_lossless(mode == VideoMode::Lossless);
+        }
+    }
+
+    #[inline]
+    pub fn video_mode(&self) -> VideoMode {
+        *self.video_mode.lock().unwrap()
+    }
+
+    #[inline]
+    pub fn set_capture_wait_base(&self, ms: i32) {
+        self.capture_wait_base_ms.store(ms, Ordering::Relaxed);
+    }
+
+    // The timeout of waiting for a new frame from the capturer, at most one frame interval.
+    fn capture_wait(&self, refresh_rate: Option<u32>, spf: Duration) -> Duration {
+        let ms = self.capture_wait_base_ms.load(Ordering::Relaxed);
+        let wait = if ms > 0 {
+            Duration::from_millis(ms as _)
+        } else {
+            match refresh_rate {
+                Some(hz) if hz > 0 => Duration::from_secs(1) / hz,
+                _ => DEFAULT_CAPTURE_WAIT_BASE,
+            }
+        };
+        wait.min(spf)
+    }
+
+    #[inline]
+    pub fn set_draw_cursor(&self, draw: bool) {
+        self.draw_cursor.store(draw, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn draw_cursor(&self) -> bool {
+        self.draw_cursor.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_keyframe_buffer_depth(&self, frames: usize) {
+        self.keyframe_buffer_depth.store(frames, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn set_keyframe_buffer_max_bytes(&self, bytes: usize) {
+        self.keyframe_buffer_max_bytes
+            .store(bytes, Ordering::Relaxed);
+    }
+
+    /// The latest warning, `None` before any connection is slow.
+    #[inline]
+    pub fn subscribe_slow_client_warnings(&self) -> watch::Receiver<Option<SlowClientWarning>> {
+        self.slow_client_warnings.1.clone()
+    }
+
+    #[inline]
+    pub fn set_roi_provider(&self, provider: Option<Box<dyn RoiProvider>>) {
+        *self.roi_provider.lock().unwrap() = provider;
+    }
+
+    /// `None` to capture the whole displays.
+    pub fn set_capture_region(&self, region: Option<CaptureRegion>) {
+        *self.capture_region.lock().unwrap() = region;
+    }
+
+    #[inline]
+    pub fn capture_region(&self) -> Option<CaptureRegion> {
+        *self.capture_region.lock().unwrap()
+    }
+
+    /// The max size of the frames to encode, `None` to encode the frames in the captured size.
+    pub fn set_scaled_capture(&self, max_size: Option<(usize, usize)>) {
+        *self.scaled_capture.lock().unwrap() = max_size;
+    }
+
+    #[inline]
+    pub fn scaled_capture(&self) -> Option<(usize, usize)> {
+        *self.scaled_capture.lock().unwrap()
+    }
+
+    pub fn set_auto_scale_policy(&self, policy: AutoScalePolicy) {
+        log::info!("auto scale policy: {:?}", policy);
+        *self.auto_scale_policy.lock().unwrap() = policy;
+        // The scaled displays restart in the full resolution.
+        self.auto_scale_widths.lock().unwrap().clear();
+    }
+
+    #[inline]
+    pub fn auto_scale_policy(&self) -> AutoScalePolicy {
+        *self.auto_scale_policy.lock().unwrap()
+    }
+
+    #[inline]
+    fn auto_scale_width(&self, display_idx: usize) -> Option<usize> {
+        self.auto_scale_widths
+            .lock()
+            .unwrap()
+            .get(&display_idx)
+            .copied()
+    }
+
+    #[inline]
+    fn set_auto_scale_width(&self, display_idx: usize, width: usize) {
+        self.auto_scale_widths
+            .lock()
+            .unwrap()
+            .insert(display_idx, width);
+    }
+
+    fn get_roi(&self, display_w: u32, display_h: u32, cursor: (i32, i32)) -> Vec<RoiRect> {
+        match self.roi_provider.lock().unwrap().as_ref() {
+            Some(provider) => provider.get_roi(display_w, display_h, cursor.0, cursor.1),
+            None => vec![],
+        }
+    }
+}
+
+/// A rectangle in the virtual screen coordinates, the same as the display origins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureRegion {
+    pub x: i32,
+    pub y: i32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl CaptureRegion {
+    // The intersection with the display, relative to the display origin.
+    // The size is aligned to even numbers for the chroma subsampling.
+    fn crop(&self, origin: (i32, i32), width: usize, height: usize) -> Option<Self> {
+        let x0 = self.x.max(origin.0);
+        let y0 = self.y.max(origin.1);
+        let x1 = (self.x + self.w as i32).min(origin.0 + width as i32);
+        let y1 = (self.y + self.h as i32).min(origin.1 + height as i32);
+        let (w, h) = (((x1 - x0) & !1) as u32, ((y1 - y0) & !1) as u32);
+        if x1 <= x0 || y1 <= y0 || w == 0 || h == 0 {
+            return None;
+        }
+        Some(Self {
+            x: x0 - origin.0,
+            y: y0 - origin.1,
+            w,
+            h,
+        })
+    }
+}
+
+/// Capture only a rectangle of the screen, e.g. the window of an application.
+/// The displays which intersect the rectangle capture the intersection, others capture
+/// the whole display.
+///
+/// Only supported on Windows. The frames are cropped after capturing, so it works with
+/// both DXGI and the GDI fallback, but disables the texture encoding (vram).
+/// Changing the region restarts the video services, the clients get a keyframe.
+/// The mouse input is still mapped to the whole display.
+#[inline]
+pub fn set_capture_origin_and_size(x: i32, y: i32, w: u32, h: u32) {
+    VideoServiceHandle::global().set_capture_region(Some(CaptureRegion { x, y, w, h }));
+}
+
+#[inline]
+pub fn clear_capture_region() {
+    VideoServiceHandle::global().set_capture_region(None);
+}
+
+/// Down-sample the frames larger than `max_width` x `max_height` before encoding, e.g. to
+/// 1920x1080 on the 4K and 5K displays, where encoding is the bottleneck.
+/// The aspect ratio is kept, the frames are never up-sampled.
+///
+/// The display origin and size sent to the clients are not changed, the clients stretch the
+/// video to the display. The texture encoding (vram) is disabled while scaling.
+/// Changing it restarts the video services.
+#[inline]
+pub fn set_scaled_capture(max_width: usize, max_height: usize) {
+    VideoServiceHandle::global().set_scaled_capture(Some((max_width, max_height)));
+}
+
+#[inline]
+pub fn clear_scaled_capture() {
+    VideoServiceHandle::global().set_scaled_capture(None);
+}
+
+// The size to scale the frames of `size` to, `None` if they fit in `max_size`.
+// The size is aligned to even numbers for the chroma subsampling.
+fn scaled_size(size: (usize, usize), max_size: (usize, usize)) -> Option<(usize, usize)> {
+    let ((w, h), (max_w, max_h)) = (size, max_size);
+    if w == 0 || h == 0 || (w <= max_w && h <= max_h) {
+        return None;
+    }
+    let ratio = (max_w as f64 / w as f64).min(max_h as f64 / h as f64);
+    let scaled = |x: usize| (((x as f64 * ratio) as usize) & !1).max(2);
+    Some((scaled(w), scaled(h)))
+}
+
+// The max size of the frames to encode, of `set_scaled_capture()` and the auto scale.
+fn max_frame_size(
+    scaled_capture: Option<(usize, usize)>,
+    auto_scale_width: Option<usize>,
+) -> Option<(usize, usize)> {
+    match (scaled_capture, auto_scale_width) {
+        (Some((w, h)), Some(auto_w)) => Some((w.min(auto_w), h)),
+        (Some(size), None) => Some(size),
+        (None, Some(auto_w)) => Some((auto_w, usize::MAX)),
+        (None, None) => None,
+    }
+}
+
+/// Scale down the frames if the encoder can't meet the bitrate, instead of only raising the
+/// quantizer, e.g. 4K frames over a slow link are sharper after scaling to 1080p.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoScalePolicy {
+    pub enabled: bool,
+    /// Never scale the frames narrower than it.
+    pub min_width: u32,
+    /// The percentage of the width to scale to on each step, e.g. 50 for 3840 to 1920.
+    pub step_pct: u32,
+}
+
+impl Default for AutoScalePolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_width: 1280,
+            step_pct: 75,
+        }
+    }
+}
+
+/// Scale down the frames whose actual bitrate is over the configured one for a few seconds.
+/// It steps down on each check until `min_width`, the displays restart in the full
+/// resolution after all the connections are closed or the policy is changed.
+///
+/// Changing the frame size restarts the video service, the peers get a `SwitchDisplay` to
+/// reset their rendering surfaces. The display size sent to the peers is not changed, the
+/// same as `set_scaled_capture()`.
+#[inline]
+pub fn set_auto_scale_policy(policy: AutoScalePolicy) {
+    VideoServiceHandle::global().set_auto_scale_policy(policy);
+}
+
+// The actual bitrate over the configured one by this is over the budget.
+const AUTO_SCALE_TOLERANCE_PCT: u64 = 20;
+const AUTO_SCALE_OVER_BUDGET_SECS: u32 = 5;
+
+// Decide when to scale down the frames of a video service, checked every second.
+struct BandwidthController {
+    last_check: Instant,
+    over_budget_secs: u32,
+}
+
+impl BandwidthController {
+    fn new() -> Self {
+        Self {
+            last_check: Instant::now(),
+            over_budget_secs: 0,
+        }
+    }
+
+    // The width to scale the frames of `width` to, if the actual bitrate has been over the
+    // budget for `AUTO_SCALE_OVER_BUDGET_SECS`.
+    fn check(
+        &mut self,
+        now: Instant,
+        policy: &AutoScalePolicy,
+        actual_bps: u64,
+        budget_bps: u64,
+        width: usize,
+    ) -> Option<usize> {
+        if now.saturating_duration_since(self.last_check) < Duration::from_secs(1) {
+            return None;
+        }
+        self.last_check = now;
+        if !policy.enabled
+            || budget_bps == 0
+            || actual_bps <= budget_bps * (100 + AUTO_SCALE_TOLERANCE_PCT) / 100
+        {
+            self.over_budget_secs = 0;
+            return None;
+        }
+        self.over_budget_secs += 1;
+        if self.over_budget_secs < AUTO_SCALE_OVER_BUDGET_SECS {
+            return None;
+        }
+        self.over_budget_secs = 0;
+        let min_width = policy.min_width as usize;
+        let scaled = (width * policy.step_pct.clamp(10, 90) as usize / 100).max(min_width) & !1;
+        if scaled >= width {
+            return None;
+        }
+        Some(scaled)
+    }
+}
+
+/// Set the interval of checking the display changes while capturing, 0 to disable it,
+/// e.g. for embedded systems whose displays never change.
+/// A shorter interval reduces the black screen after plugging a monitor.
+/// If the display changes are pushed by `DisplayHotplugWatcher` (Windows), they are checked
+/// on its events, and every 5 seconds in case an event is missed.
+#[inline]
+pub fn set_display_poll_interval(ms: u64) {
+    VideoServiceHandle::global().set_display_poll_interval(ms);
+}
+
+// Diagnostics of the latest encoded frame, of any display.
+#[derive(Debug, Clone, Default)]
+pub struct VideoServiceStats {
+    pub display_idx: usize,
+    pub encode_latency: Duration,
+    // Frames failed to encode or skipped by deduplication, since the process started.
+    pub dropped_frames: u64,
+    pub fps: u32,
+    pub connections: usize,
+    // The highest of the connections the frame is sent to, in bits per second.
+    pub actual_bitrate: u64,
+    pub peak_bitrate: u64,
+}
+
+const BITRATE_WINDOW: Duration = Duration::from_secs(1);
+const PEAK_BITRATE_WINDOW: Duration = Duration::from_millis(100);
+
+// The frames sent to a connection in the last second.
+#[derive(Debug, Default)]
+struct BitrateMeter {
+    samples: VecDeque<(Instant, usize)>, // (time, bytes)
+}
+
+impl BitrateMeter {
+    fn record(&mut self, now: Instant, bytes: usize) {
+        self.samples.push_back((now, bytes));
+        self.evict(now);
+    }
+
+    fn evict(&mut self, now: Instant) {
+        while let Some((t, _)) = self.samples.front() {
+            if now.saturating_duration_since(*t) < BITRATE_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    fn actual(&mut self, now: Instant) -> u64 {
+        self.evict(now);
+        self.samples.iter().map(|(_, b)| *b as u64).sum::<u64>() * 8
+    }
+
+    // The most bytes in 100 ms, starting from each sample.
+    fn peak(&mut self, now: Instant) -> u64 {
+        self.evict(now);
+        let (mut peak, mut sum, mut end) = (0, 0, 0);
+        for (start, (t, _)) in self.samples.iter().enumerate() {
+            while end < self.samples.len()
+                && self.samples[end].0.saturating_duration_since(*t) < PEAK_BITRATE_WINDOW
+            {
+                sum += self.samples[end].1 as u64;
+                end += 1;
+            }
+            peak = peak.max(sum);
+            sum -= self.samples[start].1 as u64;
+        }
+        peak * 8 * (BITRATE_WINDOW.as_millis() / PEAK_BITRATE_WINDOW.as_millis()) as u64
+    }
+}
+
+/// The bits per second of the video frames sent to a connection in the last second.
+#[inline]
+pub fn get_actual_bitrate(conn_id: i32) -> u64 {
+    VideoServiceHandle::global().get_actual_bitrate(conn_id)
+}
+
+/// The bits per second of the peak 100 ms of the video frames sent to a connection
+/// in the last second.
+#[inline]
+pub fn get_peak_bitrate(conn_id: i32) -> u64 {
+    VideoServiceHandle::global().get_peak_bitrate(conn_id)
+}
+
+#[inline]
+pub fn on_connection_close(conn_id: i32) {
+    VideoServiceHandle::global().on_connection_close(conn_id);
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    last_frame: Option<Instant>,
+    capture_error: Option<String>,
+    encode_error: Option<String>,
+}
+
+/// The health of the video services, of any display, for the watchdogs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthStatus {
+    // False if the capturer has not returned for 5 seconds, or no display is captured.
+    pub capturing: bool,
+    // False if the last frame failed to encode.
+    pub encoding: bool,
+    // Since the last frame is captured or the display is found unchanged,
+    // u64::MAX if no display has been captured.
+    pub last_frame_age_ms: u64,
+    // The last capture error, or else the last encode error, cleared on success.
+    pub error: Option<String>,
+}
+
+/// Run the video services, capturing and encoding, only on the `cpus`, e.g. on one NUMA node
+/// to avoid the remote memory accesses of the frame buffers. Empty to run on all the cpus.
+/// Supported on Linux and Windows, the video services restart to apply it.
+///
+/// The encoder threads, `codec_thread_num()` up to half of all the cpus, are created by the
+/// video services. On Linux the ones created afterwards inherit the affinity and share the `cpus`,
+/// set enough cpus for them. On Windows they don't, only the capture thread is affected.
+#[inline]
+pub fn set_capture_thread_affinity(cpus: &[usize]) -> ResultType<()> {
+    VideoServiceHandle::global().set_capture_thread_affinity(cpus)
+}
+
+/// The OS priority of the threads capturing and encoding the frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThreadPriority {
+    #[default]
+    Normal,
+    /// `THREAD_PRIORITY_ABOVE_NORMAL` on Windows, nice -10 on Linux.
+    AboveNormal,
+    /// `THREAD_PRIORITY_HIGHEST` on Windows, nice -15 on Linux.
+    High,
+}
+
+/// Raise the priority of the video service threads, which capture and encode on the same
+/// thread, against the encode latency spikes when the system is busy.
+/// Only on Windows and Linux, where the server needs root to raise it.
+/// Changing it restarts the video services.
+#[inline]
+pub fn set_encode_thread_priority(priority: ThreadPriority) {
+    VideoServiceHandle::global().set_encode_thread_priority(priority);
+}
+
+/// Drain the video services for the shutdown, see `VideoServiceHandle::shutdown_gracefully()`.
+#[inline]
+pub fn shutdown_gracefully(timeout: Duration) -> bool {
+    VideoServiceHandle::global().shutdown_gracefully(timeout)
+}
+
+/// Crossfade from the last frame of the outgoing display to the new display in `frames` frames
+/// on the display switches, 0 to disable it. It also applies if the new display is opened
+/// within a second after the other display is closed, e.g. by another connection.
+#[inline]
+pub fn set_crossfade_frames(frames: usize) {
+    VideoServiceHandle::global().set_crossfade_frames(frames);
+}
+
+/// The health of the video services, which can be polled without tailing the logs.
+#[inline]
+pub fn is_healthy() -> HealthStatus {
+    VideoServiceHandle::global().is_healthy()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+/// The watermark with the peer ids of the connections, drawn on the frames before encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatermarkConfig {
+    pub enabled: bool,
+    pub opacity: f32, // 0.0 to 1.0, raised to 0.3 to keep readable after encoding
+    pub position: WatermarkPosition,
+}
+
+impl Default for WatermarkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            opacity: 0.5,
+            position: Default::default(),
+        }
+    }
+}
+
+/// Draw the peer ids of the connections of a display on its frames, for the audit trails of
+/// the screen recordings on the peers. The hardware encoders taking the textures are not used
+/// while it's enabled.
+#[inline]
+pub fn set_watermark_config(config: WatermarkConfig) {
+    VideoServiceHandle::global().set_watermark_config(config);
+}
+
+/// The order to send the frames to the connections of a display.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    // Skipped if waiting for it to fetch the last frame has made the frame late for the
+    // others, e.g. a recording connection. A skipped connection receives the frames again
+    // from the next keyframe, which is requested if it's skipped for 2 seconds.
+    Background,
+}
+
+const MAX_BACKGROUND_STARVATION: Duration = Duration::from_secs(2);
+
+/// Set the priority of a connection, `Priority::Normal` by default.
+#[inline]
+pub fn set_connection_priority(conn_id: i32, priority: Priority) {
+    VideoServiceHandle::global().set_connection_priority(conn_id, priority);
+}
+
+/// Request a frame rate for a connection, the same as the custom fps sent by the clients.
+///
+/// The frames are not skipped per connection. The inter frames reference the previous frames,
+/// a connection missing a frame can't decode until the next keyframe. The connections of a
+/// display share the encoder, which runs at the lowest frame rate requested.
+#[inline]
+pub fn set_connection_fps(conn_id: i32, fps: u32) {
+    VIDEO_QOS.lock().unwrap().user_custom_fps(conn_id, fps);
+}
+
+/// Restore the image quality and fps of the last `capacity` clients when they reconnect,
+/// until they send them again. The clients are identified by their ids. Disabled by default.
+#[inline]
+pub fn set_qos_cache_capacity(capacity: usize) {
+    VIDEO_QOS.lock().unwrap().set_qos_cache_capacity(capacity);
+}
+
+/// The bitrate of an audio stream, taken out of the bandwidth the video bitrate is computed
+/// from while a connection receives the audio, e.g. 64 ~ 128 kbps of opus. 0 by default.
+#[inline]
+pub fn set_audio_bitrate_overhead(kbps: u32) {
+    VIDEO_QOS.lock().unwrap().set_audio_bitrate_overhead(kbps);
+}
+
+#[inline]
+pub fn set_quality_aggregation(aggregation: QualityAggregation) {
+    VIDEO_QOS
+        .lock()
+        .unwrap()
+        .set_quality_aggregation(aggregation);
+}
+
+// (p50, p95, p99) of the recent network delays of a connection, in ms.
+pub fn get_latency_percentiles(id: i32) -> Option<(i64, i64, i64)> {
+    VIDEO_QOS
+        .lock()
+        .unwrap()
+        .latency_percentiles(id)
+        .map(|(p50, p95, p99)| (p50 as _, p95 as _, p99 as _))
+}
+
+#[inline]
+pub fn subscribe_stats() -> watch::Receiver<VideoServiceStats> {
+    VideoServiceHandle::global().subscribe_stats()
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DedupStats {
+    pub checked: u64,
+    pub skipped: u64,
+}
+
+/// Set the VPX `rc_max_quantizer` (0 - 63) used for the lowest quality,
+/// it overrides the `max-quantizer` option until the next video service start.
+#[inline]
+pub fn set_max_quantizer_floor(q: u32) {
+    scrap::vpxcodec::set_max_quantizer(q);
+}
+
+fn force_keyframe_interval_from_option() -> Duration {
+    let secs = Config::get_option(OPTION_FORCE_KEYFRAME_INTERVAL)
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_FORCE_KEYFRAME_INTERVAL_SECS);
+    Duration::from_secs(secs)
+}
+
+fn update_max_quantizer_from_option() {
+    let q = Config::get_option(OPTION_MAX_QUANTIZER)
+        .parse::<u32>()
+        .unwrap_or(scrap::vpxcodec::DEFAULT_MAX_QUANTIZER);
+    set_max_quantizer_floor(q);
+}
+
+/// Skip encoding a frame if the hamming distance between its luma hash and the last
+/// encoded one is less than `bits` (0 - 64). 0 disables the deduplication.
+///
+/// The hash samples one pixel of every 4x4 block of the Y plane, so it costs about 1/16
+/// of a full frame read, much cheaper than the crc32 of the whole frame used before.
+/// Small changes, e.g. a blinking caret, may be skipped with a large `bits`.
+///
+/// With the `frame_dedup_crc` feature, frames identical to the last encoded one are
+/// also skipped, by the xxh3 digest of the whole frame, regardless of `bits`.
+#[inline]
+pub fn set_dedup_threshold(bits: u8) {
+    VideoServiceHandle::global().set_dedup_threshold(bits);
+}
+
+#[inline]
+pub fn dedup_stats() -> DedupStats {
+    VideoServiceHandle::global().dedup_stats()
+}
+
+// 64 bits average hash of the 8x8 downscaled luma.
+fn luma_hash(yuv: &[u8], width: usize, height: usize, stride: usize) -> Option<u64> {
+    const N: usize = 8;
+    const STEP: usize = 4;
+    if width < N || height < N || yuv.len() < stride * height {
+        return None;
+    }
+    let mut cells = [0u64; N * N];
+    let mut counts = [0u64; N * N];
+    for y in (0..height).step_by(STEP) {
+        let row = &yuv[y * stride..y * stride + width];
+        let cy = y * N / height;
+        for x in (0..width).step_by(STEP) {
+            let i = cy * N + x * N / width;
+            cells[i] += row[x] as u64;
+            counts[i] += 1;
+        }
+    }
+    for i in 0..N * N {
+        cells[i] /= counts[i].max(1);
+    }
+    let mean = cells.iter().sum::<u64>() / (N * N) as u64;
+    Some(
+        cells
+            .iter()
+            .enumerate()
+            .fold(0u64, |h, (i, c)| if *c > mean { h | 1 << i } else { h }),
+    )
+}
+
+#[derive(Default)]
+struct FrameDedup {
+    last_hash: Option<u64>,
+    #[cfg(feature = "frame_dedup_crc")]
+    last_digest: Option<u64>,
+}
+
+impl FrameDedup {
+    // Returns true if the frame is similar to the last one and can be skipped.
+    fn check(
+        &mut self,
+        handle: &VideoServiceHandle,
+        input: &EncodeInput,
+        yuvfmt: &scrap::EncodeYuvFormat,
+    ) -> bool {
+        #[cfg(feature = "frame_dedup_crc")]
+        if let EncodeInput::YUV(yuv) = input {
+            let digest = xxhash_rust::xxh3::xxh3_64(yuv);
+            handle.dedup_checked.fetch_add(1, Ordering::Relaxed);
+            if self.last_digest.replace(digest) == Some(digest) {
+                handle.dedup_skipped.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+        let threshold = handle.dedup_threshold.load(Ordering::Relaxed);
+        if threshold == 0 {
+            self.last_hash = None;
+            return false;
+        }
+        let EncodeInput::YUV(yuv) = input else {
+            return false;
+        };
+        let Some(hash) = luma_hash(yuv, yuvfmt.w, yuvfmt.h, yuvfmt.stride[0]) else {
+            return false;
+        };
+        handle.dedup_checked.fetch_add(1, Ordering::Relaxed);
+        if let Some(last) = self.last_hash {
+            if (last ^ hash).count_ones() < threshold as u32 {
+                handle.dedup_skipped.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+        self.last_hash = Some(hash);
+        false
+    }
+}
+
+// The lossless frames are several times larger, limit the fps to stay within the bandwidth.
+const LOSSLESS_MIN_SPF: Duration = Duration::from_millis(100);
+// The consecutive frames fetched slower than the fps, before warning the peers.
+const LOSSLESS_SLOW_FRAMES: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VideoMode {
+    #[default]
+    Default,
+    /// For pixel-level work, e.g. graphic design. VP9 is encoded losslessly in I444 if the
+    /// peers can decode it, at most 10 fps. Other codecs are not affected.
+    Lossless,
+}
+
+/// Changing the mode restarts the video services.
+#[inline]
+pub fn set_video_mode(mode: VideoMode) {
+    VideoServiceHandle::global().set_video_mode(mode);
+}
+
+// One frame of a 60 Hz display, if the refresh rate is unknown.
+const DEFAULT_CAPTURE_WAIT_BASE: Duration = Duration::from_millis(17);
+
+/// The longest time to wait for a new frame from the capturer in each loop, in milliseconds.
+/// 0 or a negative value to use one frame interval of the display refresh rate, e.g.
+/// 7 ms for 144 Hz, 17 ms for 60 Hz or the rate is unknown, 33 ms for 30 Hz.
+/// It is capped by the frame interval of the current fps.
+///
+/// DXGI `AcquireNextFrame` blocks until the screen is updated or the wait times out.
+/// A longer wait than the refresh interval delays the loop when nothing changes,
+/// then the next change is captured and encoded late. A much shorter one returns
+/// timeouts before the compositor presents the frame, and the frame is picked up by the next loop.
+/// Only the Windows capturers report the refresh rate for now.
+#[inline]
+pub fn set_capture_wait_base(ms: i32) {
+    VideoServiceHandle::global().set_capture_wait_base(ms);
+}
+
+/// Draw the hardware cursor on the captured frames, off by default.
+///
+/// The peers draw the cursor from the cursor messages, turn it on only for the peers which
+/// don't, e.g. the recordings of the video stream, or if the cursor is missing on the peers.
+/// The DXGI desktop duplication excludes the hardware cursor from the frames,
+/// the cursor shape and position are drawn onto the frames before encoding.
+/// It takes effect on Windows only, not on the rotated displays or the vram encoding.
+#[inline]
+pub fn set_draw_cursor(draw: bool) {
+    VideoServiceHandle::global().set_draw_cursor(draw);
+}
+
+// The defaults of the keyframe buffer, about 2 seconds at 30 fps.
+const KEYFRAME_BUFFER_DEPTH: usize = 60;
+const KEYFRAME_BUFFER_MAX_BYTES: usize = 8 * 1024 * 1024;
+
+/// Keep at most `frames` encoded frames since the last keyframe, and replay them to the
+/// connections subscribing the video in the middle of a session, so they can join the
+/// live frames without restarting the video service. 0 disables the buffer.
+///
+/// The buffer is dropped if it grows over the limits before the next keyframe,
+/// the new connections restart the video service then, the same as without the buffer.
+#[inline]
+pub fn set_keyframe_buffer_depth(frames: usize) {
+    VideoServiceHandle::global().set_keyframe_buffer_depth(frames);
+}
+
+/// The memory limit of the buffer in `set_keyframe_buffer_depth`, 8 MB by default.
+#[inline]
+pub fn set_keyframe_buffer_max_bytes(bytes: usize) {
+    VideoServiceHandle::global().set_keyframe_buffer_max_bytes(bytes);
+}
+
+// The encoded frames since the last keyframe.
+#[derive(Default)]
+struct VideoFrameRingBuffer {
+    frames: VecDeque<Arc<Message>>,
+    bytes: usize,
+}
+
+impl VideoFrameRingBuffer {
+    fn push(&mut self, handle: &VideoServiceHandle, msg: Arc<Message>) {
+        let (key, bytes) = encoded_frame_info(&msg);
+        if key {
+            self.clear();
+        } else if self.frames.is_empty() {
+            // Nothing to decode from until the next keyframe.
+            return;
+        }
+        let depth = handle.keyframe_buffer_depth.load(Ordering::Relaxed);
+        let max_bytes = handle.keyframe_buffer_max_bytes.load(Ordering::Relaxed);
+        if self.frames.len() >= depth || self.bytes + bytes > max_bytes {
+            // Dropping the oldest frames would drop the keyframe.
+            self.clear();
+            return;
+        }
+        self.frames.push_back(msg);
+        self.bytes += bytes;
+    }
+
+    fn clear(&mut self) {
+        self.frames.clear();
+        self.bytes = 0;
+    }
+
+    // Send the buffered frames to the new subscribers, return false if there is no keyframe.
+    fn replay(&self, sps: &super::service::ServiceSwap<ConnInner>) -> bool {
+        if self.frames.is_empty() {
+            return false;
+        }
+        for msg in self.frames.iter() {
+            sps.send_shared(msg.clone());
+        }
+        true
+    }
+}
+
+// (contains a keyframe, encoded bytes)
+fn encoded_frame_info(msg: &Message) -> (bool, usize) {
+    let Some(message::Union::VideoFrame(vf)) = &msg.union else {
+        return (false, 0);
+    };
+    let frames = match &vf.union {
+        Some(video_frame::Union::Vp8s(f))
+        | Some(video_frame::Union::Vp9s(f))
+        | Some(video_frame::Union::Av1s(f))
+        | Some(video_frame::Union::H264s(f))
+        | Some(video_frame::Union::H265s(f)) => &f.frames,
+        _ => return (false, 0),
+    };
+    (
+        frames.iter().any(|f| f.key),
+        frames.iter().map(|f| f.data.len()).sum(),
+    )
+}
+
+// Warn the peers once if they can't fetch the lossless frames in time.
+#[derive(Default)]
+struct LosslessBandwidthCheck {
+    slow_frames: u32,
+    warned: bool,
+}
+
+impl LosslessBandwidthCheck {
+    // Called after waiting for the connections to fetch the sent frame.
+    fn check(&mut self, sp: &GenericService, fetch_wait: Duration, spf: Duration) {
+        if self.warned {
+            return;
+        }
+        if fetch_wait <= spf {
+            self.slow_frames = 0;
+            return;
+        }
+        self.slow_frames += 1;
+        if self.slow_frames >= LOSSLESS_SLOW_FRAMES {
+            log::warn!("the bandwidth is too low for the lossless mode");
+            self.warned = true;
+            let mut msg_out = Message::new();
+            msg_out.set_message_box(MessageBox {
+                msgtype: "custom-nook-nocancel-hasclose".to_owned(),
+                title: "Lossless mode".to_owned(),
+                text: "The network is too slow for the lossless mode, the screen may lag."
+                    .to_owned(),
+                link: "".to_owned(),
+                ..Default::default()
+            });
+            sp.send(msg_out);
+        }
+    }
+}
+
+/// When to insert a keyframe besides the first frame of an encoder.
+///
+/// A client that misses a keyframe can't decode until the next one. More keyframes
+/// shorten the recovery, but a keyframe is several times larger than a delta frame,
+/// so they cost bandwidth on exactly the links that lose frames.
+/// Hardware encoders don't support forcing a keyframe and ignore the policy.
+///
+/// Besides the policy, a keyframe is forced every `force-keyframe-interval-secs` seconds,
+/// 10 by default, so the clients which join late or lose a frame recover in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyframePolicy {
+    /// Only when the encoder is created, e.g. on display switch or refresh.
+    #[default]
+    OnSwitchOnly,
+    /// Every n seconds, 0 is the same as `OnSwitchOnly`.
+    EveryNSeconds(u32),
+    /// After a sent frame is not fetched by all connections in time.
+    OnPacketLoss,
+}
+
+#[inline]
+pub fn set_keyframe_policy(p: KeyframePolicy) {
+    VideoServiceHandle::global().set_keyframe_policy(p);
+}
+
+/// How long to wait for the connections to fetch a frame, and what to do with the ones
+/// which keep stalling.
+///
+/// The next frame is captured after all connections fetch the sent one or the timeout,
+/// so a hung connection slows down the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowClientPolicy {
+    /// Stop waiting for the connections which haven't fetched the frame after it.
+    /// It is only used before any network delay is measured if `adaptive_timeout` is on.
+    pub primary_timeout_ms: u64,
+    /// Adapt the timeout to the connections, twice of the smoothed fetch time,
+    /// which starts from the p99 network delay and backs off on the timeouts, 50 - 5000 ms.
+    pub adaptive_timeout: bool,
+    /// The consecutive stalled frames before a connection is reported slow.
+    pub max_stalls: u32,
+    /// Close the slow connections instead of only reporting them.
+    pub disconnect: bool,
+}
+
+impl Default for SlowClientPolicy {
+    fn default() -> Self {
+        Self {
+            primary_timeout_ms: 3_000,
+            adaptive_timeout: true,
+            max_stalls: 10,
+            disconnect: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowClientWarning {
+    pub conn_id: i32,
+    pub display_idx: usize,
+    pub stalls: u32,
+}
+
+#[inline]
+pub fn set_slow_client_policy(p: SlowClientPolicy) {
+    VideoServiceHandle::global().set_slow_client_policy(p);
+}
+
+// About 100 ms at 30 fps.
+const CROSSFADE_FRAMES: usize = 3;
+// Try dxgi again after capturing with gdi for this long, since dxgi lost the access.
+#[cfg(windows)]
+const DXGI_RETRY_AFTER_ACCESS_LOST: Duration = Duration::from_secs(30);
+// The capturer is considered stuck if it has not returned for this long.
+const HEALTH_MAX_FRAME_AGE_MS: u64 = 5_000;
+const ACK_TIMEOUT_MIN_MS: f64 = 50.;
+const ACK_TIMEOUT_MAX_MS: f64 = 5_000.;
+const ACK_TIME_EWMA_ALPHA: f64 = 0.125;
+
+// The timeout of waiting for the connections to fetch a frame.
+#[derive(Default)]
+struct AdaptiveAckTimeout {
+    estimate_ms: Option<f64>, // the smoothed time for all connections to fetch a frame
+}
+
+impl AdaptiveAckTimeout {
+    // `p99_delay_ms` is the highest p99 network delay of the connections.
+    fn timeout_ms(&mut self, p99_delay_ms: Option<u32>, fallback_ms: u64) -> u64 {
+        if self.estimate_ms.is_none() {
+            self.estimate_ms = p99_delay_ms.map(|d| d as f64);
+        }
+        match self.estimate_ms {
+            Some(estimate) => (2. * estimate).clamp(ACK_TIMEOUT_MIN_MS, ACK_TIMEOUT_MAX_MS) as _,
+            None => fallback_ms,
+        }
+    }
+
+    // `all_fetched` is false if the wait timed out.
+    fn update(&mut self, elapsed: Duration, all_fetched: bool) {
+        let Some(estimate) = self.estimate_ms.as_mut() else {
+            return;
+        };
+        if all_fetched {
+            *estimate += ACK_TIME_EWMA_ALPHA * (elapsed.as_secs_f64() * 1000. - *estimate);
+        } else {
+            // Double the timeout actually used, not the estimate under the floor.
+            *estimate = (estimate.max(ACK_TIMEOUT_MIN_MS / 2.) * 2.).min(ACK_TIMEOUT_MAX_MS / 2.);
+        }
+    }
+}
+
+#[derive(Default)]
+struct StallTracker {
+    stalls: HashMap<i32, u32>,
+}
+
+impl StallTracker {
+    // Called after waiting for the connections to fetch the sent frame.
+    fn update(
+        &mut self,
+        handle: &VideoServiceHandle,
+        sp: &GenericService,
+        display_idx: usize,
+        sent: &HashSet<i32>,
+        fetched: &HashSet<i32>,
+    ) {
+        let policy = handle.slow_client_policy();
+        self.stalls.retain(|id, _| sent.contains(id));
+        for id in sent {
+            if fetched.contains(id) {
+                self.stalls.remove(id);
+                continue;
+            }
+            let stalls = self.stalls.entry(*id).or_default();
+            *stalls += 1;
+            if *stalls != policy.max_stalls {
+                continue;
+            }
+            log::warn!(
+                "connection {} stalled {} frames of display {}",
+                id,
+                stalls,
+                display_idx
+            );
+            handle
+                .slow_client_warnings
+                .0
+                .send_replace(Some(SlowClientWarning {
+                    conn_id: *id,
+                    display_idx,
+                    stalls: *stalls,
+                }));
+            if policy.disconnect {
+                log::warn!("close slow connection {}", id);
+                let mut misc = Misc::new();
+                misc.set_stop_service(true);
+                let mut msg = Message::new();
+                msg.set_misc(misc);
+                sp.send_to(msg, *id);
+                self.stalls.remove(id);
+            }
+        }
+    }
+}
+
+struct KeyframeScheduler {
+    last: Instant,
+    lost: bool,
+    force_interval: Duration, // zero to follow the policy only
+}
+
+impl KeyframeScheduler {
+    fn new(force_interval: Duration) -> Self {
+        Self {
+            last: Instant::now(),
+            lost: false,
+            force_interval,
+        }
+    }
+
+    fn on_frame_lost(&mut self) {
+        self.lost = true;
+    }
+
+    // Force a keyframe now, whatever the policy is.
+    fn request(&mut self, encoder: &mut Encoder) -> bool {
+        self.last = Instant::now();
+        encoder.request_keyframe()
+    }
+
+    // Called before encoding a frame.
+    fn check(&mut self, handle: &VideoServiceHandle, encoder: &mut Encoder) {
+        let need = match handle.keyframe_policy() {
+            KeyframePolicy::OnSwitchOnly => false,
+            KeyframePolicy::EveryNSeconds(n) => {
+                n > 0 && self.last.elapsed() >= Duration::from_secs(n as _)
+            }
+            KeyframePolicy::OnPacketLoss => self.lost,
+        } || (!self.force_interval.is_zero()
+            && self.last.elapsed() >= self.force_interval);
+        self.lost = false;
+        if need {
+            self.last = Instant::now();
+            if !encoder.request_keyframe() {
+                tracing::debug!("encoder does not support requesting keyframe");
+            }
+        }
+    }
+}
+
+/// Provides the regions of a display to encode with higher quality.
+/// The cursor position is relative to the display.
+pub trait RoiProvider: Send {
+    fn get_roi(&self, display_w: u32, display_h: u32, cursor_x: i32, cursor_y: i32)
+        -> Vec<RoiRect>;
+}
+
+/// A square around the cursor, where the user is most likely looking.
+/// The rate control keeps the bitrate, so the rest of the frame gets a lower quality.
+pub struct CursorRoiProvider {
+    pub size: u32,
+    pub delta_q: i32,
+}
+
+impl Default for CursorRoiProvider {
+    fn default() -> Self {
+        Self {
+            size: 256,
+            delta_q: -15,
+        }
+    }
+}
+
+impl RoiProvider for CursorRoiProvider {
+    fn get_roi(
+        &self,
+        display_w: u32,
+        display_h: u32,
+        cursor_x: i32,
+        cursor_y: i32,
+    ) -> Vec<RoiRect> {
+        if cursor_x < 0
+            || cursor_y < 0
+            || cursor_x as u32 >= display_w
+            || cursor_y as u32 >= display_h
+        {
+            return vec![];
+        }
+        let x = (cursor_x as u32).saturating_sub(self.size / 2);
+        let y = (cursor_y as u32).saturating_sub(self.size / 2);
+        vec![RoiRect {
+            x,
+            y,
+            w: self.size.min(display_w - x),
+            h: self.size.min(display_h - y),
+            delta_q: self.delta_q,
+        }]
+    }
+}
+
+/// Set the provider of the regions of interest, `None` to disable. Only VP9 supports it.
+#[inline]
+pub fn set_roi_provider(provider: Option<Box<dyn RoiProvider>>) {
+    VideoServiceHandle::global().set_roi_provider(provider);
+}
+
+#[inline]
+fn cursor_pos() -> Option<(i32, i32)> {
+    #[cfg(not(target_os = "android"))]
+    return crate::get_cursor_pos();
+    #[cfg(target_os = "android")]
+    None
+}
+
+// The mean absolute difference of the sampled luma, above which a frame is a scene change.
+const SCENE_CHANGE_MAD: u64 = 12;
+// The frames encoded with more bits after a scene change.
+const SCENE_CHANGE_BURST_FRAMES: usize = 2;
+
+// Let the encoder spend more bits on the frames after a scene change, e.g. a video starts
+// playing, which look blocky with the bitrate budget of a static UI.
+// It's per frame, on top of the quality of the connections.
+struct SceneChangeDetector {
+    last_luma: Vec<u8>,
+    last_mad: Option<u64>, // of the last frame, taken by `MotionFpsBooster`
+    burst_left: usize,
+    bursting: bool,
+    supported: bool,
+}
+
+impl SceneChangeDetector {
+    fn new() -> Self {
+        Self {
+            last_luma: vec![],
+            last_mad: None,
+            burst_left: 0,
+            bursting: false,
+            supported: true,
+        }
+    }
+
+    // Called before encoding a frame.
+    fn update(&mut self, encoder: &mut Encoder, input: &EncodeInput) {
+        if let EncodeInput::YUV(yuv) = input {
+            let fmt = encoder.yuvfmt();
+            self.last_mad = self.luma_mad(yuv, fmt.w, fmt.h, fmt.stride[0]);
+        }
+        if !self.supported {
+            return;
+        }
+        if self.last_mad.map_or(false, |mad| mad > SCENE_CHANGE_MAD) {
+            self.burst_left = SCENE_CHANGE_BURST_FRAMES;
+        }
+        let burst = self.burst_left > 0;
+        self.burst_left = self.burst_left.saturating_sub(1);
+        if burst == self.bursting {
+            return;
+        }
+        match encoder.set_burst(burst) {
+            Ok(true) => self.bursting = burst,
+            Ok(false) => self.supported = false,
+            Err(e) => log::error!("failed to set burst {}: {:?}", burst, e),
+        }
+    }
+
+    // Compare one pixel of every 4x4 block of the Y plane with the last frame,
+    // `None` for the first frame.
+    fn luma_mad(&mut self, yuv: &[u8], width: usize, height: usize, stride: usize) -> Option<u64> {
+        const STEP: usize = 4;
+        if width == 0 || height == 0 || yuv.len() < stride * height {
+            return None;
+        }
+        let n = ((width + STEP - 1) / STEP) * ((height + STEP - 1) / STEP);
+        let first = self.last_luma.len() != n;
+        self.last_luma.resize(n, 0);
+        let mut sum = 0u64;
+        let mut i = 0;
+        for y in (0..height).step_by(STEP) {
+            let row = &yuv[y * stride..y * stride + width];
+            for x in (0..width).step_by(STEP) {
+                sum += row[x].abs_diff(self.last_luma[i]) as u64;
+                self.last_luma[i] = row[x];
+                i += 1;
+            }
+        }
+        (!first).then(|| sum / n as u64)
+    }
+}
+
+/// Raise the fps while the screen is moving, e.g. scrolling or dragging a window, and lower it
+/// on the static screens to save the bandwidth. The motion is the mean absolute difference of
+/// the sampled luma, the same as the scene change detection but with a lower threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotionAdaptiveFps {
+    pub enabled: bool,
+    pub high_fps: u32,
+    pub low_fps: u32,
+}
+
+impl Default for MotionAdaptiveFps {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            high_fps: 60,
+            low_fps: 30,
+        }
+    }
+}
+
+/// Capture at `high_fps` for at least 500 ms after a motion, back to `low_fps` after 1 second
+/// without motion. It replaces the fps of the quality settings, except the fps below
+/// `low_fps` on the slow networks.
+#[inline]
+pub fn set_motion_adaptive_fps(enabled: bool, high_fps: u32, low_fps: u32) {
+    let low_fps = low_fps.clamp(video_qos::MIN_FPS, video_qos::MAX_FPS);
+    VideoServiceHandle::global().set_motion_adaptive_fps(MotionAdaptiveFps {
+        enabled,
+        high_fps: high_fps.clamp(low_fps, video_qos::MAX_FPS),
+        low_fps,
+    });
+}
+
+// The mean absolute difference of the sampled luma, above which a frame is moving.
+const MOTION_MAD: u64 = 2;
+const MOTION_BOOST_MIN_DURATION: Duration = Duration::from_millis(500);
+const MOTION_IDLE_DURATION: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct MotionFpsBooster {
+    boost_start: Option<Instant>,
+    last_motion: Option<Instant>,
+}
+
+impl MotionFpsBooster {
+    // `mad` is `None` if no frame is encoded, e.g. the duplicate frames are skipped.
+    fn on_frame(&mut self, now: Instant, mad: Option<u64>) {
+        if mad.map_or(false, |mad| mad > MOTION_MAD) {
+            if !self.boosted(now) {
+                self.boost_start = Some(now);
+            }
+            self.last_motion = Some(now);
+        }
+    }
+
+    fn boosted(&self, now: Instant) -> bool {
+        let within = |t: Option<Instant>, d: Duration| {
+            t.map_or(false, |t| now.saturating_duration_since(t) < d)
+        };
+        within(self.boost_start, MOTION_BOOST_MIN_DURATION)
+            || within(self.last_motion, MOTION_IDLE_DURATION)
+    }
+
+    fn spf(&self, now: Instant, m: &MotionAdaptiveFps, qos_spf: Duration) -> Duration {
+        if !m.enabled {
+            return qos_spf;
+        }
+        let spf = |fps: u32| Duration::from_micros(1_000_000 / fps.max(1) as u64);
+        let low_spf = spf(m.low_fps);
+        if qos_spf > low_spf {
+            return qos_spf;
+        }
+        if self.boosted(now) {
+            spf(m.high_fps)
+        } else {
+            low_spf
+        }
+    }
+}
+
+/// Raise the bitrate after a keyframe, e.g. after connecting or a display switch, the frames
+/// look blocky until the encoder has built up the reference frames, text is hard to read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TurboParams {
+    /// The bitrate multiplier of the frame after the keyframe, 1.0 disables the turbo.
+    pub multiplier: f32,
+    /// The frames to ramp the multiplier down to 1.0 linearly.
+    pub ramp_frames: u32,
+}
+
+impl Default for TurboParams {
+    fn default() -> Self {
+        Self {
+            multiplier: 3.0,
+            ramp_frames: 60, // ~2 seconds at 30 fps
+        }
+    }
+}
+
+#[inline]
+pub fn set_turbo_params(multiplier: f32, ramp_frames: u32) {
+    VideoServiceHandle::global().set_turbo_params(TurboParams {
+        multiplier: multiplier.max(1.0),
+        ramp_frames,
+    });
+}
+
+// The minimal change of the multiplier to reconfigure the encoder, not on every frame.
+const TURBO_MULTIPLIER_STEP: f32 = 0.1;
+
+struct TurboPhase {
+    frames_left: u32,
+    applied: f32, // the multiplier the encoder is configured with
+    supported: bool,
+}
+
+impl TurboPhase {
+    fn new() -> Self {
+        Self {
+            frames_left: 0,
+            applied: 1.0,
+            supported: true,
+        }
+    }
+
+    fn on_keyframe(&mut self, p: TurboParams) {
+        if p.multiplier > 1.0 {
+            self.frames_left = p.ramp_frames;
+        }
+    }
+
+    fn next_multiplier(&mut self, p: TurboParams) -> f32 {
+        if self.frames_left == 0 || p.ramp_frames == 0 {
+            self.frames_left = 0;
+            return 1.0;
+        }
+        let t = self.frames_left.min(p.ramp_frames) as f32 / p.ramp_frames as f32;
+        self.frames_left -= 1;
+        1.0 + (p.multiplier.max(1.0) - 1.0) * t
+    }
+
+    // Called before encoding a frame.
+    fn update(&mut self, handle: &VideoServiceHandle, encoder: &mut Encoder) {
+        if !self.supported {
+            return;
+        }
+        let m = self.next_multiplier(handle.turbo_params());
+        if (m - self.applied).abs() < TURBO_MULTIPLIER_STEP && (m != 1.0 || self.applied == 1.0) {
+            return;
+        }
+        if !encoder.support_changing_quality() {
+            self.supported = false;
+            return;
+        }
+        let ratio = VIDEO_QOS.lock().unwrap().ratio();
+        match encoder.set_quality(ratio * m) {
+            Ok(()) => self.applied = m,
+            Err(e) => {
+                log::error!("failed to set turbo multiplier {}: {:?}", m, e);
+                self.supported = false;
+            }
+        }
+    }
+}
+
+struct RoiUpdater {
+    origin: (i32, i32),
+    width: usize,
+    height: usize,
+    scale: f64, // the frame size to the captured size
+    last: Vec<RoiRect>,
+    supported: bool,
+}
+
+impl RoiUpdater {
+    fn new(c: &CapturerInfo) -> Self {
+        let (width, height) = c.frame_size();
+        let offset = c.region.map_or((0, 0), |r| (r.x, r.y));
+        Self {
+            origin: (c.origin.0 + offset.0, c.origin.1 + offset.1),
+            width,
+            height,
+            scale: width as f64 / c.capture_size().0.max(1) as f64,
+            last: vec![],
+            supported: true,
+        }
+    }
+
+    // Called before encoding a frame.
+    fn update(&mut self, handle: &VideoServiceHandle, encoder: &mut Encoder) {
+        if !self.supported {
+            return;
+        }
+        let rects = match cursor_pos() {
+            Some((x, y)) => handle.get_roi(
+                self.width as _,
+                self.height as _,
+                (
+                    ((x - self.origin.0) as f64 * self.scale) as _,
+                    ((y - self.origin.1) as f64 * self.scale) as _,
+                ),
+            ),
+            None => vec![],
+        };
+        if rects == self.last {
+            return;
+        }
+        match encoder.set_roi(&rects) {
+            Ok(true) => self.last = rects,
+            Ok(false) => self.supported = false,
+            Err(e) => {
+                log::error!("failed to set roi: {e:?}");
+                self.supported = false;
+            }
+        }
+    }
+}
+
+/// Check the captured frame for the driver bugs seen in the wild:
+/// 1. the length doesn't match the stride and the height,
+/// 2. the frame is entirely zero, even the alpha,
+/// 3. the first scan line is filled with a debug fill pattern, e.g. 0xCDCDCDCD.
+///
+/// `width` is in pixels, `stride` in bytes.
+pub fn is_frame_valid(frame: &[u8], width: usize, height: usize, stride: usize) -> bool {
+    const FILL_PATTERNS: [[u8; 4]; 3] = [
+        [0xCD, 0xCD, 0xCD, 0xCD],
+        [0x0D, 0xF0, 0xAD, 0xBA], // 0xBAADF00D
+        [0xEF, 0xBE, 0xAD, 0xDE], // 0xDEADBEEF
+    ];
+    if width == 0 || height == 0 || stride < width || frame.len() < stride * height {
+        return false;
+    }
+    if frame.iter().all(|b| *b == 0) {
+        return false;
+    }
+    let line = &frame[..stride];
+    !FILL_PATTERNS
+        .iter()
+        .any(|p| line.chunks_exact(4).all(|c| c == p))
+}
+
+// A black screen can be entirely zero on some platforms, e.g. X11 without alpha,
+// the invalid frames are only dropped until the limit, then passed on as before.
+const MAX_CONSECUTIVE_INVALID_FRAMES: usize = 10;
+
+#[derive(Default)]
+struct FrameValidityChecker {
+    invalid_streak: usize,
+}
+
+impl FrameValidityChecker {
+    // Whether to encode the frame, the texture frames are not checked.
+    fn check(&mut self, frame: &scrap::Frame) -> bool {
+        use scrap::TraitPixelBuffer;
+
+        let scrap::Frame::PixelBuffer(pb) = frame else {
+            return true;
+        };
+        if is_frame_valid(pb.data(), pb.width(), pb.height(), pb.stride()[0]) {
+            self.invalid_streak = 0;
+            return true;
+        }
+        if self.invalid_streak >= MAX_CONSECUTIVE_INVALID_FRAMES {
+            return true;
+        }
+        self.invalid_streak += 1;
+        let total = INVALID_FRAMES_TOTAL.fetch_add(1, Ordering::Relaxed) + 1;
+        log::warn!("drop invalid frame, total: {}", total);
+        false
+    }
+}
+
+/// Defer the restarts of the video services on the display changes until the guard is dropped,
+/// e.g. while a large file transfer is in progress, which has to reconnect after a restart.
+/// The display changes are checked again when the last guard is dropped, not lost.
+pub fn lock_display_switch(conn_id: i32) -> DisplayLockGuard {
+    let locks = DISPLAY_SWITCH_LOCKS.fetch_add(1, Ordering::SeqCst) + 1;
+    log::info!("display switch locked by {}, locks: {}", conn_id, locks);
+    DisplayLockGuard(conn_id)
+}
 
-lazy_static::lazy_static! {
-    static ref FRAME_FETCHED_NOTIFIER: (UnboundedSender<(i32, Option<Instant>)>, Arc<TokioMutex<UnboundedReceiver<(i32, Option<Instant>)>>>) = {
-        let (tx, rx) = unbounded_channel();
-        (tx, Arc::new(TokioMutex::new(rx)))
-    };
-    pub static ref VIDEO_QOS: Arc<Mutex<VideoQoS>> = Default::default();
-    pub static ref IS_UAC_RUNNING: Arc<Mutex<bool>> = Default::default();
-    pub static ref IS_FOREGROUND_WINDOW_ELEVATED: Arc<Mutex<bool>> = Default::default();
+#[must_use]
+pub struct DisplayLockGuard(i32);
+
+impl Drop for DisplayLockGuard {
+    fn drop(&mut self) {
+        let locks = DISPLAY_SWITCH_LOCKS.fetch_sub(1, Ordering::SeqCst) - 1;
+        log::info!("display switch unlocked by {}, locks: {}", self.0, locks);
+    }
+}
+
+#[derive(Default)]
+struct DeferredDisplaySwitch {
+    pending: bool,
+}
+
+impl DeferredDisplaySwitch {
+    // Whether to check the display changes now, the requests while locked are kept.
+    fn ready(&mut self, requested: bool) -> bool {
+        self.pending |= requested;
+        if DISPLAY_SWITCH_LOCKS.load(Ordering::SeqCst) > 0 {
+            return false;
+        }
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Stop sending video frames to a connection, without tearing down the capturer or encoder.
+/// If all connections of a display are paused, the display is still captured once a second
+/// to detect display changes, but nothing is encoded.
+#[inline]
+pub fn pause_capture(conn_id: i32) {
+    VideoServiceHandle::global().pause_capture(conn_id);
+}
+
+/// Resume sending video frames to a connection, starting with a keyframe.
+#[inline]
+pub fn resume_capture(conn_id: i32) {
+    VideoServiceHandle::global().resume_capture(conn_id);
+}
+
+/// A random token for the lifetime of a connection, sent with its frame acks, to tell them
+/// from the acks of a closed connection with the same id.
+pub fn new_session_token(conn_id: i32) -> u64 {
+    let token = hbb_common::rand::random::<u64>();
+    SESSION_TOKENS.lock().unwrap().insert(conn_id, token);
+    token
+}
+
+/// Release the token when the connection is closed, unless the id is reused already.
+pub fn release_session_token(conn_id: i32, token: u64) {
+    let mut lock = SESSION_TOKENS.lock().unwrap();
+    if lock.get(&conn_id) == Some(&token) {
+        lock.remove(&conn_id);
+    }
 }
 
 #[inline]
-pub fn notify_video_frame_fetched(conn_id: i32, frame_tm: Option<Instant>) {
-    FRAME_FETCHED_NOTIFIER.0.send((conn_id, frame_tm)).ok();
+pub fn notify_video_frame_fetched(conn_id: i32, session_token: u64, frame_tm: Option<Instant>) {
+    FRAME_FETCHED_NOTIFIER
+        .0
+        .send((conn_id, session_token, frame_tm))
+        .ok();
 }
 
 struct VideoFrameController {
     cur: Instant,
     send_conn_ids: HashSet<i32>,
+    // The session tokens of `send_conn_ids` when the frame is sent.
+    send_tokens: HashMap<i32, u64>,
+    last_encode_us: u64, // the time to encode the last frame
+    // Waiting for the background connections to fetch the last frame made it late.
+    background_late: bool,
+    ack_timeout: AdaptiveAckTimeout,
+    notifier: FrameFetchedNotifier,
+    // Chosen on the first wait.
+    ack_waiter: Option<AckWaiter>,
+    #[cfg(feature = "tracing_frames")]
+    timing: super::frame_timing::FrameTiming,
+}
+
+// How `VideoFrameController` blocks on the acks.
+enum AckWaiter {
+    // The current multi-thread runtime, blocked on in place.
+    Handle(tokio::runtime::Handle),
+    // Inside a current-thread runtime, which can't be blocked on in place or from another
+    // thread without driving its timers. Each wait runs on a new thread with its own runtime.
+    Thread,
+    // Outside any runtime, created instead of a runtime for every wait.
+    Runtime(tokio::runtime::Runtime),
+}
+
+impl AckWaiter {
+    fn new() -> ResultType<Self> {
+        use tokio::runtime::{Handle, RuntimeFlavor};
+        Ok(match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                Self::Handle(handle)
+            }
+            Ok(_) => Self::Thread,
+            Err(_) => Self::Runtime(
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_time()
+                    .build()?,
+            ),
+        })
+    }
+
+    fn block_on<F: std::future::Future + Send>(&self, fut: F) -> Option<F::Output>
+    where
+        F::Output: Send,
+    {
+        match self {
+            Self::Handle(handle) => Some(tokio::task::block_in_place(|| handle.block_on(fut))),
+            Self::Thread => std::thread::scope(|s| {
+                s.spawn(|| {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_time()
+                        .build()
+                        .map(|rt| rt.block_on(fut))
+                        .ok()
+                })
+                .join()
+                .ok()
+                .flatten()
+            }),
+            Self::Runtime(rt) => Some(rt.block_on(fut)),
+        }
+    }
 }
 
 impl VideoFrameController {
     fn new() -> Self {
+        Self::with_notifier(FRAME_FETCHED_NOTIFIER.clone())
+    }
+
+    fn with_notifier(notifier: FrameFetchedNotifier) -> Self {
         Self {
             cur: Instant::now(),
             send_conn_ids: HashSet::new(),
+            send_tokens: HashMap::new(),
+            last_encode_us: 0,
+            background_late: false,
+            ack_timeout: Default::default(),
+            notifier,
+            ack_waiter: None,
+            #[cfg(feature = "tracing_frames")]
+            timing: Default::default(),
+        }
+    }
+
+    fn ack_timeout_ms(&mut self, policy: &SlowClientPolicy) -> u64 {
+        if !policy.adaptive_timeout {
+            return policy.primary_timeout_ms;
         }
+        let p99_delay = {
+            let video_qos = VIDEO_QOS.lock().unwrap();
+            self.send_conn_ids
+                .iter()
+                .filter_map(|id| video_qos.latency_percentiles(*id).map(|(_, _, p99)| p99))
+                .max()
+        };
+        self.ack_timeout
+            .timeout_ms(p99_delay, policy.primary_timeout_ms)
     }
 
     fn reset(&mut self) {
         self.send_conn_ids.clear();
+        self.send_tokens.clear();
+        self.background_late = false;
     }
 
     fn set_send(&mut self, tm: Instant, conn_ids: HashSet<i32>) {
+        let conn_ids = validate_conn_ids(&conn_ids);
         if !conn_ids.is_empty() {
             self.cur = tm;
+            let tokens = SESSION_TOKENS.lock().unwrap();
+            self.send_tokens = conn_ids
+                .iter()
+                .filter_map(|id| tokens.get(id).map(|token| (*id, *token)))
+                .collect();
             self.send_conn_ids = conn_ids;
         }
     }
 
-    #[tokio::main(flavor = "current_thread")]
-    async fn try_wait_next(&mut self, fetched_conn_ids: &mut HashSet<i32>, timeout_millis: u64) {
+    /// Discard the acks of the connections that are gone, e.g. closed without acking the last
+    /// frame, or they would be taken as the acks of the next frame. The acks of a closed
+    /// connection whose id is reused are discarded by the session token.
+    /// The channel is shared by the video services of all displays,
+    /// the acks of the active connections are sent back.
+    fn drain_stale_acks(&self, active_conn_ids: &HashSet<i32>) {
+        // Another display is waiting for the acks.
+        let Ok(mut rx) = self.notifier.1.try_lock() else {
+            return;
+        };
+        let mut active = vec![];
+        let mut stale = 0;
+        let tokens = SESSION_TOKENS.lock().unwrap().clone();
+        while let Ok((id, token, instant)) = rx.try_recv() {
+            if active_conn_ids.contains(&id) && tokens.get(&id) == Some(&token) {
+                active.push((id, token, instant));
+            } else {
+                stale += 1;
+            }
+        }
+        drop(rx);
+        for ack in active {
+            self.notifier.0.send(ack).ok();
+        }
+        if stale > 0 {
+            tracing::debug!("Drained {} stale frame acks", stale);
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, fetched_conn_ids))]
+    fn try_wait_next(&mut self, fetched_conn_ids: &mut HashSet<i32>, timeout_millis: u64) {
         if self.send_conn_ids.is_empty() {
             return;
         }
+        if self.ack_waiter.is_none() {
+            match AckWaiter::new() {
+                Ok(waiter) => self.ack_waiter = Some(waiter),
+                Err(e) => {
+                    log::error!("Failed to create runtime to wait frame fetched: {e}");
+                    return;
+                }
+            }
+        }
+        let Some(waiter) = self.ack_waiter.as_ref() else {
+            return;
+        };
+        let ack = waiter
+            .block_on(Self::wait_next(&self.notifier.1, timeout_millis))
+            .flatten();
+        if let Some((id, token)) = ack {
+            if self.send_tokens.get(&id) == Some(&token) {
+                fetched_conn_ids.insert(id);
+            } else {
+                tracing::debug!("Ignored the frame ack of a closed connection {}", id);
+            }
+        }
+    }
 
+    async fn wait_next(
+        rx: &TokioMutex<UnboundedReceiver<FrameFetched>>,
+        timeout_millis: u64,
+    ) -> Option<(i32, u64)> {
         let timeout_dur = Duration::from_millis(timeout_millis as u64);
-        match tokio::time::timeout(timeout_dur, FRAME_FETCHED_NOTIFIER.1.lock().await.recv()).await
-        {
+        match tokio::time::timeout(timeout_dur, rx.lock().await.recv()).await {
             Err(_) => {
                 // break if timeout
                 // log::error!("blocking wait frame receiving timeout {}", timeout_millis);
+                None
             }
-            Ok(Some((id, instant))) => {
+            Ok(Some((id, token, instant))) => {
                 if let Some(tm) = instant {
-                    log::trace!("Channel recv latency: {}", tm.elapsed().as_secs_f32());
+                    tracing::trace!("Channel recv latency: {}", tm.elapsed().as_secs_f32());
                 }
-                fetched_conn_ids.insert(id);
+                Some((id, token))
             }
             Ok(None) => {
                 // this branch would never be reached
+                None
+            }
+        }
+    }
+}
+
+// The connections which are still open, by their session tokens. A closed connection never
+// acks the frame, waiting for it would take the whole timeout on every frame.
+fn validate_conn_ids(conn_ids: &HashSet<i32>) -> HashSet<i32> {
+    let tokens = SESSION_TOKENS.lock().unwrap();
+    conn_ids
+        .iter()
+        .filter(|id| {
+            let open = tokens.contains_key(id);
+            if !open {
+                log::warn!("Not waiting for the frame ack of the closed connection {id}");
             }
+            open
+        })
+        .copied()
+        .collect()
+}
+
+impl Drop for VideoFrameController {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks, which panics if it's dropped inside another runtime.
+        if let Some(AckWaiter::Runtime(rt)) = self.ack_waiter.take() {
+            rt.shutdown_background();
         }
     }
 }
@@ -162,11 +2433,16 @@ pub fn new(idx: usize) -> GenericService {
         sp: GenericService::new(get_service_name(idx), true),
         idx,
     };
-    GenericService::run(&vs, run);
+    GenericService::run_with_idle(
+        &vs,
+        |vs| run(vs, VideoServiceHandle::global()),
+        || CAPTURER_POOL.with(|p| p.borrow_mut().evict_idle()),
+    );
     vs.sp
 }
 
 // Capturer object is expensive, avoiding to create it frequently.
+#[tracing::instrument(skip(display))]
 fn create_capturer(
     privacy_mode_id: i32,
     display: Display,
@@ -196,7 +2472,17 @@ fn create_capturer(
         None => {
             #[cfg(windows)]
             {
-                log::debug!("Create capturer dxgi|gdi");
+                tracing::debug!("Create capturer dxgi|gdi");
+                // No peers declare the HDR support, the frames are tone-mapped to SDR.
+                #[cfg(feature = "hdr")]
+                if let Some(hdr) = display.hdr_metadata() {
+                    log::info!(
+                        "display {} is HDR10, {} - {} nits, tone-mapped to SDR",
+                        _current,
+                        hdr.min_luminance,
+                        hdr.max_luminance
+                    );
+                }
                 return crate::portable_service::client::create_capturer(
                     _current,
                     display,
@@ -205,7 +2491,7 @@ fn create_capturer(
             }
             #[cfg(not(windows))]
             {
-                log::debug!("Create capturer from scrap");
+                tracing::debug!("Create capturer from scrap");
                 return Ok(Box::new(
                     Capturer::new(display).with_context(|| "Failed to create capturer")?,
                 ));
@@ -267,6 +2553,161 @@ fn check_uac_switch(privacy_mode_id: i32, capturer_privacy_mode_id: i32) -> Resu
     Ok(())
 }
 
+// Serve the pre-loaded frames, `CaptureError::Timeout` when empty, for the tests without a display.
+// The next `get_capturer()` on the thread returns it, see `set_test_capturer()`.
+#[cfg(all(test, any(windows, target_os = "linux")))]
+struct TestCapturer {
+    frames: VecDeque<Vec<u8>>,
+    width: usize,
+    height: usize,
+    current: Vec<u8>,
+}
+
+#[cfg(all(test, any(windows, target_os = "linux")))]
+thread_local! {
+    static TEST_CAPTURER: RefCell<Option<TestCapturer>> = RefCell::new(None);
+}
+
+#[cfg(all(test, any(windows, target_os = "linux")))]
+impl TestCapturer {
+    // The frames are BGRA without padding.
+    fn new(frames: Vec<Vec<u8>>, width: usize, height: usize) -> Self {
+        Self {
+            frames: frames.into(),
+            width,
+            height,
+            current: vec![],
+        }
+    }
+
+    fn into_info(self, current: usize) -> CapturerInfo {
+        CapturerInfo {
+            origin: (0, 0),
+            width: self.width,
+            height: self.height,
+            ndisplay: 1,
+            current,
+            privacy_mode_id: INVALID_PRIVACY_MODE_CONN_ID,
+            _capturer_privacy_mode_id: INVALID_PRIVACY_MODE_CONN_ID,
+            capturer: Box::new(self),
+            region: None,
+            refresh_rate: None,
+            scale: None,
+            display_scale: 1.0,
+            pool_key: None,
+        }
+    }
+}
+
+#[cfg(all(test, any(windows, target_os = "linux")))]
+fn set_test_capturer(c: TestCapturer) {
+    TEST_CAPTURER.with(|t| *t.borrow_mut() = Some(c));
+}
+
+#[cfg(all(test, any(windows, target_os = "linux")))]
+impl TraitCapturer for TestCapturer {
+    fn frame<'a>(&'a mut self, _timeout: Duration) -> Result<scrap::Frame<'a>, CaptureError> {
+        self.current = self.frames.pop_front().ok_or(CaptureError::Timeout)?;
+        #[cfg(windows)]
+        let pixelbuffer = scrap::PixelBuffer::new(&self.current, self.width, self.height);
+        #[cfg(target_os = "linux")]
+        let pixelbuffer =
+            scrap::PixelBuffer::new(&self.current, scrap::Pixfmt::BGRA, self.width, self.height);
+        Ok(scrap::Frame::PixelBuffer(pixelbuffer))
+    }
+
+    #[cfg(windows)]
+    fn is_gdi(&self) -> bool {
+        false
+    }
+
+    #[cfg(windows)]
+    fn set_gdi(&mut self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "vram")]
+    fn device(&self) -> scrap::AdapterDevice {
+        Default::default()
+    }
+
+    #[cfg(feature = "vram")]
+    fn set_output_texture(&mut self, _texture: bool) {}
+}
+
+// Crop the frames of the inner capturer to the region.
+#[cfg(windows)]
+struct RegionCapturer {
+    inner: Box<dyn TraitCapturer>,
+    region: CaptureRegion,
+    data: Vec<u8>,
+}
+
+#[cfg(windows)]
+impl TraitCapturer for RegionCapturer {
+    fn frame<'a>(&'a mut self, timeout: Duration) -> Result<scrap::Frame<'a>, CaptureError> {
+        use scrap::TraitPixelBuffer;
+        use std::io::{Error, ErrorKind};
+
+        let r = self.region;
+        let (x, y, w, h) = (r.x as usize, r.y as usize, r.w as usize, r.h as usize);
+        match self.inner.frame(timeout)? {
+            scrap::Frame::PixelBuffer(pixelbuffer) => {
+                if x + w > pixelbuffer.width() || y + h > pixelbuffer.height() {
+                    return Err(CaptureError::Other(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "region {:?} is out of the frame ({}, {})",
+                            r,
+                            pixelbuffer.width(),
+                            pixelbuffer.height()
+                        ),
+                    )));
+                }
+                let bpp = pixelbuffer.pixfmt().bytes_per_pixel();
+                let stride = pixelbuffer.stride()[0];
+                let src = pixelbuffer.data();
+                self.data.clear();
+                for row in y..y + h {
+                    let start = row * stride + x * bpp;
+                    self.data.extend_from_slice(&src[start..start + w * bpp]);
+                }
+            }
+            scrap::Frame::Texture(_) => {
+                return Err(CaptureError::Other(Error::new(
+                    ErrorKind::Unsupported,
+                    "texture frames can't be cropped",
+                )));
+            }
+        }
+        Ok(scrap::Frame::PixelBuffer(scrap::PixelBuffer::new(
+            &self.data, w, h,
+        )))
+    }
+
+    fn is_gdi(&self) -> bool {
+        self.inner.is_gdi()
+    }
+
+    fn set_gdi(&mut self) -> bool {
+        self.inner.set_gdi()
+    }
+
+    fn set_draw_cursor(&mut self, draw: bool) {
+        self.inner.set_draw_cursor(draw);
+    }
+
+    #[cfg(feature = "vram")]
+    fn device(&self) -> scrap::AdapterDevice {
+        self.inner.device()
+    }
+
+    #[cfg(feature = "vram")]
+    fn set_output_texture(&mut self, _texture: bool) {
+        self.inner.set_output_texture(false);
+    }
+}
+
 pub(super) struct CapturerInfo {
     pub origin: (i32, i32),
     pub width: usize,
@@ -276,6 +2717,86 @@ pub(super) struct CapturerInfo {
     pub privacy_mode_id: i32,
     pub _capturer_privacy_mode_id: i32,
     pub capturer: Box<dyn TraitCapturer>,
+    // Relative to the origin, the frames are cropped to it if set.
+    pub region: Option<CaptureRegion>,
+    pub refresh_rate: Option<u32>,
+    // The size to scale the frames to before encoding, after cropping to the region.
+    pub scale: Option<(usize, usize)>,
+    // The dpi scale of the display, 2.0 for 200%.
+    pub display_scale: f64,
+    // The capturer can be kept in `CapturerPool` if set.
+    pub pool_key: Option<CapturerKey>,
+}
+
+impl CapturerInfo {
+    // The size of the captured frames, after cropping to the region.
+    #[inline]
+    fn capture_size(&self) -> (usize, usize) {
+        self.region
+            .map_or((self.width, self.height), |r| (r.w as _, r.h as _))
+    }
+
+    // The size of the frames to encode.
+    #[inline]
+    fn frame_size(&self) -> (usize, usize) {
+        self.scale.unwrap_or_else(|| self.capture_size())
+    }
+
+    // The ratio of the frame size to the logical size of the display, the encoders compute the
+    // bitrate with it. The frames scaled down to the logical size or smaller are not scaled again.
+    fn bitrate_scale(&self) -> f64 {
+        let logical_width = self.capture_size().0 as f64 / self.display_scale.max(1.0);
+        (self.frame_size().0 as f64 / logical_width.max(1.0)).max(1.0)
+    }
+}
+
+// The capturer can only be reused if nothing it is created with has changed.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct CapturerKey {
+    name: String,
+    origin: (i32, i32),
+    width: usize,
+    height: usize,
+    privacy_mode_id: i32,
+    portable_service_running: bool,
+    region: Option<CaptureRegion>,
+}
+
+const CAPTURER_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Keep the capturer after the video service stops, creating a DXGI or X11 capturer takes
+// 50-200 ms, switching back to a display shortly after switching away is common.
+//
+// The capturers can't be sent between threads, the pool is per video service thread, which
+// only captures its own display. The idle capturer is evicted by the idle loop of the service.
+#[derive(Default)]
+struct CapturerPool {
+    capturer: Option<(CapturerInfo, Instant)>,
+}
+
+impl CapturerPool {
+    fn evict_idle(&mut self) {
+        let Some((_, last_used)) = &self.capturer else {
+            return;
+        };
+        if last_used.elapsed() >= CAPTURER_POOL_IDLE_TIMEOUT {
+            log::info!("Drop the idle capturer");
+            self.capturer = None;
+        }
+    }
+
+    // The pooled capturer is dropped if the display or the key doesn't match.
+    fn take(&mut self, current: usize, key: &CapturerKey) -> Option<CapturerInfo> {
+        self.evict_idle();
+        let (c, _) = self.capturer.take()?;
+        (c.current == current && c.pool_key.as_ref() == Some(key)).then_some(c)
+    }
+
+    fn put(&mut self, c: CapturerInfo) {
+        if c.pool_key.is_some() {
+            self.capturer = Some((c, Instant::now()));
+        }
+    }
 }
 
 impl Deref for CapturerInfo {
@@ -292,11 +2813,19 @@ impl DerefMut for CapturerInfo {
     }
 }
 
-fn get_capturer(current: usize, portable_service_running: bool) -> ResultType<CapturerInfo> {
+fn get_capturer(
+    current: usize,
+    portable_service_running: bool,
+    region: Option<CaptureRegion>,
+) -> ResultType<CapturerInfo> {
+    #[cfg(all(test, any(windows, target_os = "linux")))]
+    if let Some(c) = TEST_CAPTURER.with(|t| t.borrow_mut().take()) {
+        return Ok(c.into_info(current));
+    }
     #[cfg(target_os = "linux")]
     {
         if !is_x11() {
-            return super::wayland::get_capturer();
+            return super::wayland::get_capturer(current);
         }
     }
 
@@ -322,14 +2851,23 @@ fn get_capturer(current: usize, portable_service_running: bool) -> ResultType<Ca
     }
 
     let (origin, width, height) = (display.origin(), display.width(), display.height());
+    #[cfg(any(windows, target_os = "macos"))]
+    let display_scale = display.scale();
+    #[cfg(not(any(windows, target_os = "macos")))]
+    let display_scale = 1.0;
+    #[cfg(windows)]
+    let refresh_rate = display.refresh_rate();
+    #[cfg(not(windows))]
+    let refresh_rate = None;
     let name = display.name();
-    log::debug!(
-        "#displays={}, current={}, origin: {:?}, width={}, height={}, cpus={}/{}, name:{}",
+    tracing::debug!(
+        "#displays={}, current={}, origin: {:?}, width={}, height={}, refresh rate: {:?}, cpus={}/{}, name:{}",
         ndisplay,
         current,
         &origin,
         width,
         height,
+        refresh_rate,
         num_cpus::get_physical(),
         num_cpus::get(),
         &name,
@@ -352,7 +2890,7 @@ fn get_capturer(current: usize, portable_service_running: bool) -> ResultType<Ca
             }
         }
     }
-    log::debug!(
+    tracing::debug!(
         "Try create capturer with capturer privacy mode id {}",
         capturer_privacy_mode_id,
     );
@@ -364,12 +2902,53 @@ fn get_capturer(current: usize, portable_service_running: bool) -> ResultType<Ca
             log::info!("In privacy mode, the peer side cannot watch the screen");
         }
     }
-    let capturer = create_capturer(
+    #[cfg(windows)]
+    let region = region.and_then(|r| r.crop(origin, width, height));
+    #[cfg(not(windows))]
+    let region = {
+        if region.is_some() {
+            log::warn!("Capture region is not supported on this platform");
+        }
+        None
+    };
+    let pool_key = CapturerKey {
+        name,
+        origin,
+        width,
+        height,
+        privacy_mode_id: capturer_privacy_mode_id,
+        portable_service_running,
+        region,
+    };
+    if let Some(mut c) = CAPTURER_POOL.with(|p| p.borrow_mut().take(current, &pool_key)) {
+        log::info!("Reuse the capturer of display {}", current);
+        c.ndisplay = ndisplay;
+        c.privacy_mode_id = privacy_mode_id;
+        return Ok(c);
+    }
+    #[allow(unused_mut)]
+    let mut capturer = match create_capturer(
         capturer_privacy_mode_id,
         display,
         current,
         portable_service_running,
-    )?;
+    ) {
+        Ok(capturer) => capturer,
+        Err(e) => {
+            #[cfg(windows)]
+            display_service::check_stale_displays();
+            return Err(e);
+        }
+    };
+    #[cfg(windows)]
+    if let Some(region) = region {
+        log::info!("Capture region {:?} of display {}", region, current);
+        capturer = Box::new(RegionCapturer {
+            inner: capturer,
+            region,
+            data: vec![],
+        });
+    }
     Ok(CapturerInfo {
         origin,
         width,
@@ -379,11 +2958,22 @@ fn get_capturer(current: usize, portable_service_running: bool) -> ResultType<Ca
         privacy_mode_id,
         _capturer_privacy_mode_id: capturer_privacy_mode_id,
         capturer,
+        region,
+        refresh_rate,
+        scale: None,
+        display_scale,
+        pool_key: Some(pool_key),
     })
 }
 
-fn run(vs: VideoService) -> ResultType<()> {
+#[tracing::instrument(skip_all, fields(display = vs.idx))]
+fn run(vs: VideoService, handle: &VideoServiceHandle) -> ResultType<()> {
+    if handle.draining() {
+        return Ok(());
+    }
     let _raii = Raii::new(vs.idx);
+    handle.apply_capture_thread_affinity();
+    handle.apply_encode_thread_priority();
     // Wayland only support one video capturer for now. It is ok to call ensure_inited() here.
     //
     // ensure_inited() is needed because clear() may be called.
@@ -406,12 +2996,26 @@ fn run(vs: VideoService) -> ResultType<()> {
 
     let display_idx = vs.idx;
     let sp = vs.sp;
-    let mut c = get_capturer(display_idx, last_portable_service_running)?;
+    let capture_region = handle.capture_region();
+    let scaled_capture = handle.scaled_capture();
+    let auto_scale_width = handle.auto_scale_width(display_idx);
+    let video_mode = handle.video_mode();
+    let mut c = get_capturer(display_idx, last_portable_service_running, capture_region)?;
+    c.scale = max_frame_size(scaled_capture, auto_scale_width)
+        .and_then(|max_size| scaled_size(c.capture_size(), max_size));
+    if let Some((w, h)) = c.scale {
+        log::info!("Scale the frames of display {display_idx} to {w}x{h}");
+    }
+    crate::display_service::update_current_display_info(display_idx, c.origin, c.width, c.height);
     #[cfg(windows)]
     if !scrap::codec::enable_directx_capture() && !c.is_gdi() {
         log::info!("disable dxgi with option, fall back to gdi");
         c.set_gdi();
     }
+    #[cfg(windows)]
+    let mut draw_cursor = handle.draw_cursor();
+    #[cfg(windows)]
+    c.set_draw_cursor(draw_cursor);
     let mut video_qos = VIDEO_QOS.lock().unwrap();
     let mut spf = video_qos.spf();
     let mut quality = video_qos.ratio();
@@ -421,7 +3025,9 @@ fn run(vs: VideoService) -> ResultType<()> {
     );
     let client_record = video_qos.record();
     drop(video_qos);
-    let (mut encoder, encoder_cfg, codec_format, use_i444, recorder) = match setup_encoder(
+    update_max_quantizer_from_option();
+    let (mut encoder, encoder_cfg, codec_format, use_i444, recorder, reused) = match setup_encoder(
+        handle,
         &c,
         display_idx,
         quality,
@@ -433,13 +3039,16 @@ fn run(vs: VideoService) -> ResultType<()> {
         Err(err) => {
             log::error!("Failed to create encoder: {err:?}, fallback to VP9");
             Encoder::set_fallback(&EncoderCfg::VPX(VpxEncoderConfig {
-                width: c.width as _,
-                height: c.height as _,
+                width: c.frame_size().0 as _,
+                height: c.frame_size().1 as _,
                 quality,
                 codec: VpxVideoCodecId::VP9,
                 keyframe_interval: None,
+                deinterlace: false,
+                display_scale: c.bitrate_scale(),
             }));
             setup_encoder(
+                handle,
                 &c,
                 display_idx,
                 quality,
@@ -463,17 +3072,39 @@ fn run(vs: VideoService) -> ResultType<()> {
         .set_support_changing_quality(display_idx, encoder.support_changing_quality());
     log::info!("initial quality: {quality:?}");
 
-    if sp.is_option_true(OPTION_REFRESH) {
-        sp.set_option_bool(OPTION_REFRESH, false);
-    }
+    // Refreshes requested while restarting are done by this restart.
+    sp.take_option_true(OPTION_REFRESH);
 
     let mut frame_controller = VideoFrameController::new();
+    if reused {
+        // The peers reset their decoders on the display changes.
+        encoder.request_keyframe();
+    } else {
+        warm_up_encoder(&mut encoder);
+    }
+    let mut encoder_recovery = EncoderRecovery::new(encoder_cfg.clone(), use_i444);
+    let mut keyframe_scheduler = KeyframeScheduler::new(force_keyframe_interval_from_option());
+    let mut roi_updater = RoiUpdater::new(&c);
+    let mut scene_detector = SceneChangeDetector::new();
+    let mut motion_fps = MotionFpsBooster::default();
+    let mut turbo = TurboPhase::new();
+    let mut display_switch = DeferredDisplaySwitch::default();
+    let mut frame_checker = FrameValidityChecker::default();
+    let mut frame_buffer = VideoFrameRingBuffer::default();
+    let mut pts_smoother = PtsSmoother::default();
+    let refresh_seq = handle.poll_refresh();
+    let mut crossfade = CrossfadeBlender::new(display_idx, handle.crossfade_frames());
+    let mut watermark = WatermarkRenderer::default();
 
     let start = time::Instant::now();
     let mut last_check_displays = time::Instant::now();
+    // Check the display changes on the events if supported, with a slow fallback poll.
+    let hotplug = super::display_hotplug::DisplayHotplugWatcher::spawn();
     #[cfg(windows)]
     let mut try_gdi = 1;
     #[cfg(windows)]
+    let mut dxgi_access_lost: Option<Instant> = None;
+    #[cfg(windows)]
     log::info!("gdi: {}", c.is_gdi());
     #[cfg(windows)]
     start_uac_elevation_check();
@@ -486,11 +3117,26 @@ fn run(vs: VideoService) -> ResultType<()> {
     let repeat_encode_max = 10;
     let mut encode_fail_counter = 0;
     let mut first_frame = true;
-    let capture_width = c.width;
-    let capture_height = c.height;
+    let mut dedup = FrameDedup::default();
+    let (capture_width, capture_height) = c.frame_size();
+    // The frames are converted in the captured size, then scaled to the encoder format.
+    let src_yuvfmt = match c.scale {
+        Some(_) => {
+            let (w, h) = c.capture_size();
+            Some(scrap::packed_yuvfmt(encoder.yuvfmt().pixfmt, w, h)?)
+        }
+        None => None,
+    };
+    let mut src_yuv = Vec::new();
     let (mut second_instant, mut send_counter) = (Instant::now(), 0);
+    let mut ack_wait = (Duration::ZERO, 0);
+    let mut stall_tracker = StallTracker::default();
+    let mut lossless_check = LosslessBandwidthCheck::default();
+    let mut bandwidth_controller = BandwidthController::new();
+    let mut last_paused = HashSet::new();
 
-    while sp.ok() {
+    // The frame in progress is finished and fetched before draining.
+    while sp.ok() && !handle.draining() {
         #[cfg(windows)]
         check_uac_switch(c.privacy_mode_id, c._capturer_privacy_mode_id)?;
         check_qos(
@@ -500,13 +3146,47 @@ fn run(vs: VideoService) -> ResultType<()> {
             client_record,
             &mut send_counter,
             &mut second_instant,
+            &mut ack_wait,
             display_idx,
         )?;
-        if sp.is_option_true(OPTION_REFRESH) {
+        spf = motion_fps.spf(Instant::now(), &handle.motion_adaptive_fps(), spf);
+        if video_mode == VideoMode::Lossless {
+            spf = spf.max(LOSSLESS_MIN_SPF);
+        } else {
+            let actual_bitrate = sp
+                .subscriber_ids()
+                .iter()
+                .map(|id| handle.get_actual_bitrate(*id))
+                .max()
+                .unwrap_or(0);
+            if let Some(width) = bandwidth_controller.check(
+                Instant::now(),
+                &handle.auto_scale_policy(),
+                actual_bitrate,
+                encoder.bitrate() as u64 * 1000,
+                c.frame_size().0,
+            ) {
+                log::info!(
+                    "actual bitrate {actual_bitrate} is over the budget, scale display {display_idx} to width {width}"
+                );
+                handle.set_auto_scale_width(display_idx, width);
+            }
+        }
+        if handle.video_mode() != video_mode {
+            log::info!("switch due to video mode changed");
+            bail!("SWITCH");
+        }
+        if sp.take_option_true(OPTION_REFRESH) {
             let _ = try_broadcast_display_changed(&sp, display_idx, &c, true);
             log::info!("switch to refresh");
             bail!("SWITCH");
         }
+        if handle.poll_refresh() != refresh_seq {
+            refresh();
+            let _ = try_broadcast_display_changed(&sp, display_idx, &c, true);
+            log::info!("switch to refresh, debounced");
+            bail!("SWITCH");
+        }
         if codec_format != Encoder::negotiated_codec() {
             log::info!(
                 "switch due to codec changed, {:?} -> {:?}",
@@ -540,36 +3220,151 @@ fn run(vs: VideoService) -> ResultType<()> {
             }
         }
         let now = time::Instant::now();
-        if last_check_displays.elapsed().as_millis() > 1000 {
+        let check_displays = match &hotplug {
+            // The events are missed on another desktop or if the watcher thread exits.
+            Some(rx) => {
+                rx.try_iter().count() > 0
+                    || last_check_displays.elapsed()
+                        > super::display_service::DISPLAYS_FALLBACK_POLL_INTERVAL
+            }
+            None => {
+                let poll_interval = handle.display_poll_interval();
+                poll_interval > 0 && last_check_displays.elapsed().as_millis() > poll_interval as _
+            }
+        };
+        if handle.capture_region() != capture_region {
+            log::info!("switch due to capture region changed");
+            bail!("SWITCH");
+        }
+        if handle.scaled_capture() != scaled_capture {
+            log::info!("switch due to scaled capture changed");
+            bail!("SWITCH");
+        }
+        if handle.auto_scale_width(display_idx) != auto_scale_width {
+            log::info!("switch due to auto scale changed");
+            broadcast_frame_size_changed(&sp, display_idx)?;
+            bail!("SWITCH");
+        }
+        #[cfg(windows)]
+        if handle.draw_cursor() != draw_cursor {
+            draw_cursor = !draw_cursor;
+            c.set_draw_cursor(draw_cursor);
+        }
+        if check_displays {
             last_check_displays = now;
+        }
+        if display_switch.ready(check_displays) {
             // This check may be redundant, but it is better to be safe.
-            // The previous check in `sp.is_option_true(OPTION_REFRESH)` block may be enough.
-            try_broadcast_display_changed(&sp, display_idx, &c, false)?;
+            // The previous check in `sp.take_option_true(OPTION_REFRESH)` block may be enough.
+            if let Err(e) = try_broadcast_display_changed(&sp, display_idx, &c, false) {
+                REUSABLE_ENCODER
+                    .with(|r| *r.borrow_mut() = Some((encoder_cfg.clone(), use_i444, encoder)));
+                return Err(e);
+            }
+        }
+
+        let paused = handle.paused_conn_ids();
+        let subscribers = sp.subscriber_ids();
+        if last_paused
+            .iter()
+            .any(|id| !paused.contains(id) && subscribers.contains(id))
+        {
+            // The resumed connections have missed frames, they can only decode from a keyframe.
+            if !keyframe_scheduler.request(&mut encoder) {
+                log::info!("switch due to capture resumed");
+                bail!("SWITCH");
+            }
         }
+        let all_paused = !subscribers.is_empty() && subscribers.is_subset(&paused);
+        last_paused = paused;
+        let tick = if all_paused {
+            spf.max(Duration::from_secs(1))
+        } else {
+            spf
+        };
 
         frame_controller.reset();
+        let active_conn_ids: HashSet<i32> =
+            AUTHED_CONNS.lock().unwrap().iter().map(|c| c.0).collect();
+        frame_controller.drain_stale_acks(&active_conn_ids);
 
         let time = now - start;
         let ms = (time.as_secs() * 1000 + time.subsec_millis() as u64) as i64;
-        let res = match c.frame(spf) {
+        let wait = handle.capture_wait(c.refresh_rate, spf);
+        #[cfg(feature = "tracing_frames")]
+        {
+            frame_controller.timing.capture_start = Some(Instant::now());
+        }
+        let res = match c.frame(wait) {
             Ok(frame) => {
                 repeat_encode_counter = 0;
-                if frame.valid() {
-                    let frame = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data)?;
-                    let send_conn_ids = handle_one_frame(
-                        display_idx,
-                        &sp,
-                        frame,
-                        ms,
-                        &mut encoder,
-                        recorder.clone(),
-                        &mut encode_fail_counter,
-                        &mut first_frame,
-                        capture_width,
-                        capture_height,
-                    )?;
-                    frame_controller.set_send(now, send_conn_ids);
-                    send_counter += 1;
+                if frame.valid() && frame_checker.check(&frame) {
+                    let yuvfmt = encoder.yuvfmt();
+                    let watermark_config = handle.watermark_config();
+                    let watermark_text = if watermark_config.enabled {
+                        watermark_text(&subscribers)
+                    } else {
+                        String::new()
+                    };
+                    let frame = match &src_yuvfmt {
+                        Some(src_fmt) => {
+                            if let EncodeInput::Texture(_) =
+                                frame.to(src_fmt.clone(), &mut src_yuv, &mut mid_data)?
+                            {
+                                bail!("texture frames can't be scaled");
+                            }
+                            super::annotation::composite(display_idx, &mut src_yuv, src_fmt);
+                            scrap::scale_yuv(&src_yuv, src_fmt, &mut yuv, &yuvfmt)?;
+                            crossfade.blend(&mut yuv, &yuvfmt);
+                            watermark.draw(&mut yuv, &yuvfmt, &watermark_config, &watermark_text);
+                            EncodeInput::YUV(&yuv)
+                        }
+                        None => match frame.to(yuvfmt.clone(), &mut yuv, &mut mid_data)? {
+                            EncodeInput::YUV(_) => {
+                                super::annotation::composite(display_idx, &mut yuv, &yuvfmt);
+                                crossfade.blend(&mut yuv, &yuvfmt);
+                                watermark.draw(
+                                    &mut yuv,
+                                    &yuvfmt,
+                                    &watermark_config,
+                                    &watermark_text,
+                                );
+                                EncodeInput::YUV(&yuv)
+                            }
+                            input => input,
+                        },
+                    };
+                    // New subscribers are handled in `handle_one_frame()`, do not skip the frame.
+                    let skip = all_paused
+                        || (!first_frame
+                            && !sp.has_new_subscribes()
+                            && dedup.check(handle, &frame, &yuvfmt));
+                    if !skip {
+                        let send_conn_ids = handle_one_frame(
+                            handle,
+                            display_idx,
+                            &sp,
+                            frame,
+                            pts_smoother.next(ms, spf),
+                            &mut encoder,
+                            &mut encoder_recovery,
+                            &mut keyframe_scheduler,
+                            &mut roi_updater,
+                            &mut scene_detector,
+                            &mut turbo,
+                            &mut frame_buffer,
+                            &mut frame_controller,
+                            recorder.clone(),
+                            &mut encode_fail_counter,
+                            &mut first_frame,
+                            capture_width,
+                            capture_height,
+                        )?;
+                        frame_controller.set_send(now, send_conn_ids);
+                        send_counter += 1;
+                    } else if !all_paused {
+                        VideoMetricsCollector::global().record_dropped(display_idx, codec_format);
+                    }
                 }
                 #[cfg(windows)]
                 {
@@ -584,8 +3379,12 @@ fn run(vs: VideoService) -> ResultType<()> {
             Err(err) => Err(err),
         };
 
+        match &res {
+            Ok(()) | Err(CaptureError::Timeout) => handle.on_captured(),
+            Err(e) => handle.on_capture_error(e),
+        }
         match res {
-            Err(ref e) if e.kind() == WouldBlock => {
+            Err(CaptureError::Timeout) => {
                 #[cfg(windows)]
                 if try_gdi > 0 && !c.is_gdi() {
                     if try_gdi > 3 {
@@ -611,16 +3410,24 @@ fn run(vs: VideoService) -> ResultType<()> {
                         }
                     }
                 }
-                if !encoder.latency_free() && yuv.len() > 0 {
+                if !encoder.latency_free() && yuv.len() > 0 && !all_paused {
                     // yun.len() > 0 means the frame is not texture.
                     if repeat_encode_counter < repeat_encode_max {
                         repeat_encode_counter += 1;
                         let send_conn_ids = handle_one_frame(
+                            handle,
                             display_idx,
                             &sp,
                             EncodeInput::YUV(&yuv),
-                            ms,
+                            pts_smoother.next(ms, spf),
                             &mut encoder,
+                            &mut encoder_recovery,
+                            &mut keyframe_scheduler,
+                            &mut roi_updater,
+                            &mut scene_detector,
+                            &mut turbo,
+                            &mut frame_buffer,
+                            &mut frame_controller,
                             recorder.clone(),
                             &mut encode_fail_counter,
                             &mut first_frame,
@@ -634,13 +3441,28 @@ fn run(vs: VideoService) -> ResultType<()> {
             }
             Err(err) => {
                 // This check may be redundant, but it is better to be safe.
-                // The previous check in `sp.is_option_true(OPTION_REFRESH)` block may be enough.
+                // The previous check in `sp.take_option_true(OPTION_REFRESH)` block may be enough.
                 try_broadcast_display_changed(&sp, display_idx, &c, true)?;
 
                 #[cfg(windows)]
                 if !c.is_gdi() {
                     c.set_gdi();
-                    log::info!("dxgi error, fall back to gdi: {:?}", err);
+                    match err {
+                        // Access lost: e.g. a UAC prompt or a full-screen exclusive application.
+                        // Device removed: e.g. a driver update, the new capturer after the retry
+                        // duplicates on the current adapter.
+                        CaptureError::AccessLost | CaptureError::DeviceRemoved => {
+                            log::warn!("{}, fall back to gdi", err);
+                            if matches!(err, CaptureError::DeviceRemoved) {
+                                display_service::check_stale_displays();
+                            }
+                            try_gdi = 0;
+                            dxgi_access_lost = Some(Instant::now());
+                        }
+                        CaptureError::Timeout | CaptureError::Other(_) => {
+                            log::info!("dxgi error, fall back to gdi: {:?}", err);
+                        }
+                    }
                     continue;
                 }
                 return Err(err.into());
@@ -650,29 +3472,95 @@ fn run(vs: VideoService) -> ResultType<()> {
                 {
                     would_block_count = 0;
                 }
+                #[cfg(windows)]
+                if dxgi_access_lost.map_or(false, |t| t.elapsed() >= DXGI_RETRY_AFTER_ACCESS_LOST)
+                    && c.is_gdi()
+                {
+                    // The new capturer uses dxgi, it falls back to gdi again if still lost.
+                    log::info!("switch to try dxgi again after the access lost");
+                    bail!("SWITCH");
+                }
             }
         }
 
+        motion_fps.on_frame(Instant::now(), scene_detector.last_mad.take());
+
         let mut fetched_conn_ids = HashSet::new();
-        let timeout_millis = 3_000u64;
+        let timeout_millis = if frame_controller.send_conn_ids.is_empty() {
+            0
+        } else {
+            frame_controller.ack_timeout_ms(&handle.slow_client_policy())
+        };
+        // The connections the background ones are skipped for.
+        let (priority_conn_ids, background_conn_ids): (HashSet<i32>, HashSet<i32>) =
+            frame_controller
+                .send_conn_ids
+                .iter()
+                .copied()
+                .partition(|id| handle.connection_priority(*id) != Priority::Background);
+        let mut priority_fetch_wait = None;
         let wait_begin = Instant::now();
-        while wait_begin.elapsed().as_millis() < timeout_millis as _ {
+        while wait_begin.elapsed().as_millis() < timeout_millis as _ && !handle.drain_expired() {
             check_privacy_mode_changed(&sp, display_idx, &c)?;
-            frame_controller.try_wait_next(&mut fetched_conn_ids, 300);
+            let left = timeout_millis.saturating_sub(wait_begin.elapsed().as_millis() as _);
+            frame_controller.try_wait_next(&mut fetched_conn_ids, left.min(300));
+            if priority_fetch_wait.is_none() && priority_conn_ids.is_subset(&fetched_conn_ids) {
+                priority_fetch_wait = Some(wait_begin.elapsed());
+            }
             // break if all connections have received current frame
             if fetched_conn_ids.len() >= frame_controller.send_conn_ids.len() {
                 break;
             }
         }
+        // Skip the background connections if the frame would be in time without waiting for
+        // them, not if the encoding or the other connections are slow.
+        let encode = Duration::from_micros(frame_controller.last_encode_us);
+        let wait = wait_begin.elapsed();
+        frame_controller.background_late = !background_conn_ids.is_empty()
+            && priority_fetch_wait.map_or(false, |w| encode + w <= spf && encode + wait > spf);
+        #[cfg(feature = "tracing_frames")]
+        frame_controller.timing.finish(
+            (fetched_conn_ids.len() >= frame_controller.send_conn_ids.len()).then(Instant::now),
+        );
+        if !frame_controller.send_conn_ids.is_empty() {
+            let all_fetched = fetched_conn_ids.len() >= frame_controller.send_conn_ids.len();
+            frame_controller
+                .ack_timeout
+                .update(wait_begin.elapsed(), all_fetched);
+            ack_wait.0 += wait_begin.elapsed();
+            ack_wait.1 += 1;
+            if !all_fetched {
+                keyframe_scheduler.on_frame_lost();
+            }
+            stall_tracker.update(
+                handle,
+                &sp,
+                display_idx,
+                &frame_controller.send_conn_ids,
+                &fetched_conn_ids,
+            );
+            if video_mode == VideoMode::Lossless {
+                lossless_check.check(&sp, wait_begin.elapsed(), spf);
+            }
+        }
 
         let elapsed = now.elapsed();
         // may need to enable frame(timeout)
-        log::trace!("{:?} {:?}", time::Instant::now(), elapsed);
-        if elapsed < spf {
-            std::thread::sleep(spf - elapsed);
+        tracing::trace!(
+            "{:?} {:?}, encode: {}us",
+            time::Instant::now(),
+            elapsed,
+            frame_controller.last_encode_us
+        );
+        if elapsed < tick {
+            std::thread::sleep(tick - elapsed);
         }
     }
 
+    // No subscribers, keep the last frame in case it is a display switch,
+    // and keep the capturer in case the display is watched again soon.
+    super::crossfade::keep_outgoing_frame(display_idx, &encoder.yuvfmt(), yuv);
+    CAPTURER_POOL.with(|p| p.borrow_mut().put(c));
     Ok(())
 }
 
@@ -680,6 +3568,7 @@ struct Raii(usize);
 
 impl Raii {
     fn new(display_idx: usize) -> Self {
+        RUNNING_VIDEO_SERVICES.fetch_add(1, Ordering::SeqCst);
         VIDEO_QOS.lock().unwrap().new_display(display_idx);
         Raii(display_idx)
     }
@@ -692,10 +3581,12 @@ impl Drop for Raii {
         #[cfg(feature = "vram")]
         Encoder::update(scrap::codec::EncodingUpdate::Check);
         VIDEO_QOS.lock().unwrap().remove_display(self.0);
+        RUNNING_VIDEO_SERVICES.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
 fn setup_encoder(
+    handle: &VideoServiceHandle,
     c: &CapturerInfo,
     display_idx: usize,
     quality: f32,
@@ -708,8 +3599,10 @@ fn setup_encoder(
     CodecFormat,
     bool,
     Arc<Mutex<Option<Recorder>>>,
+    bool,
 )> {
     let encoder_cfg = get_encoder_config(
+        handle,
         &c,
         display_idx,
         quality,
@@ -720,11 +3613,69 @@ fn setup_encoder(
     let codec_format = Encoder::negotiated_codec();
     let recorder = get_recorder(record_incoming, display_idx);
     let use_i444 = Encoder::use_i444(&encoder_cfg);
-    let encoder = Encoder::new(encoder_cfg.clone(), use_i444)?;
-    Ok((encoder, encoder_cfg, codec_format, use_i444, recorder))
+    let (encoder, reused) = match take_reusable_encoder(&encoder_cfg, use_i444) {
+        Some(mut encoder) => {
+            let reused = reset_encoder_for_display(&encoder_cfg, use_i444, &mut encoder)?;
+            (encoder, reused)
+        }
+        None => (Encoder::new(encoder_cfg.clone(), use_i444)?, false),
+    };
+    Ok((
+        encoder,
+        encoder_cfg,
+        codec_format,
+        use_i444,
+        recorder,
+        reused,
+    ))
+}
+
+// Only the frame size of the encoder may differ, the other settings need a new encoder.
+fn take_reusable_encoder(cfg: &EncoderCfg, i444: bool) -> Option<Encoder> {
+    let (last_cfg, last_i444, encoder) = REUSABLE_ENCODER.with(|r| r.borrow_mut().take())?;
+    match (&last_cfg, cfg) {
+        (EncoderCfg::VPX(last), EncoderCfg::VPX(cfg))
+            if last.codec == cfg.codec
+                && last.keyframe_interval == cfg.keyframe_interval
+                && last.deinterlace == cfg.deinterlace
+                && last_i444 == i444 =>
+        {
+            Some(encoder)
+        }
+        _ => None,
+    }
+}
+
+/// Reconfigure the encoder for the new display in place, creating a VP9 encoder takes ~10 ms.
+/// Fall back to a new encoder if the encoder can't be resized.
+///
+/// Returns true if the encoder is reused.
+fn reset_encoder_for_display(
+    cfg: &EncoderCfg,
+    i444: bool,
+    encoder: &mut Encoder,
+) -> ResultType<bool> {
+    let (width, height, quality) = match cfg {
+        EncoderCfg::VPX(c) => (c.width, c.height, c.quality),
+        _ => (0, 0, 0.),
+    };
+    match encoder.set_resolution(width, height) {
+        Ok(true) => {
+            log::info!("reuse encoder for {width}x{height}");
+            if encoder.support_changing_quality() {
+                allow_err!(encoder.set_quality(quality));
+            }
+            return Ok(true);
+        }
+        Ok(false) => {}
+        Err(e) => log::warn!("failed to resize encoder to {width}x{height}: {e:?}"),
+    }
+    *encoder = Encoder::new(cfg.clone(), i444)?;
+    Ok(false)
 }
 
 fn get_encoder_config(
+    _handle: &VideoServiceHandle,
     c: &CapturerInfo,
     _display_idx: usize,
     quality: f32,
@@ -732,14 +3683,39 @@ fn get_encoder_config(
     _portable_service: bool,
 ) -> EncoderCfg {
     #[cfg(all(windows, feature = "vram"))]
-    if _portable_service || c.is_gdi() {
-        log::info!("gdi:{}, portable:{}", c.is_gdi(), _portable_service);
-        VRamEncoder::set_not_use(_display_idx, true);
+    {
+        // The tone-mapped frames are not output as textures.
+        #[cfg(feature = "hdr")]
+        let hdr = c.is_hdr();
+        #[cfg(not(feature = "hdr"))]
+        let hdr = false;
+        if _portable_service
+            || c.is_gdi()
+            || c.region.is_some()
+            || c.scale.is_some()
+            || hdr
+            || _handle.watermark_config().enabled
+        {
+            log::info!(
+                "gdi:{}, portable:{}, region:{}, scale:{}, hdr:{}, watermark:{}",
+                c.is_gdi(),
+                _portable_service,
+                c.region.is_some(),
+                c.scale.is_some(),
+                hdr,
+                _handle.watermark_config().enabled
+            );
+            VRamEncoder::set_not_use(_display_idx, true);
+        }
     }
+    let (width, height) = c.frame_size();
+    let display_scale = c.bitrate_scale();
     #[cfg(feature = "vram")]
     Encoder::update(scrap::codec::EncodingUpdate::Check);
     // https://www.wowza.com/community/t/the-correct-keyframe-interval-in-obs-studio/95162
     let keyframe_interval = if record { Some(240) } else { None };
+    let deinterlace =
+        config::option2bool(OPTION_DEINTERLACE, &Config::get_option(OPTION_DEINTERLACE));
     let negotiated_codec = Encoder::negotiated_codec();
     match negotiated_codec {
         CodecFormat::H264 | CodecFormat::H265 => {
@@ -747,11 +3723,12 @@ fn get_encoder_config(
             if let Some(feature) = VRamEncoder::try_get(&c.device(), negotiated_codec) {
                 return EncoderCfg::VRAM(VRamEncoderConfig {
                     device: c.device(),
-                    width: c.width,
-                    height: c.height,
+                    width,
+                    height,
                     quality,
                     feature,
                     keyframe_interval,
+                    display_scale,
                 });
             }
             #[cfg(feature = "hwcodec")]
@@ -759,23 +3736,32 @@ fn get_encoder_config(
                 return EncoderCfg::HWRAM(HwRamEncoderConfig {
                     name: hw.name,
                     mc_name: hw.mc_name,
-                    width: c.width,
-                    height: c.height,
+                    width,
+                    height,
                     quality,
                     keyframe_interval,
+                    display_scale,
                 });
             }
+            // H264/H265 are only negotiated when a hardware encoder is usable,
+            // but the encoder may be gone since then, e.g. cleared after a failure.
+            log::warn!(
+                "no {:?} encoder available, fall back to VP9",
+                negotiated_codec
+            );
             EncoderCfg::VPX(VpxEncoderConfig {
-                width: c.width as _,
-                height: c.height as _,
+                width: width as _,
+                height: height as _,
                 quality,
                 codec: VpxVideoCodecId::VP9,
                 keyframe_interval,
+                deinterlace,
+                display_scale,
             })
         }
         format @ (CodecFormat::VP8 | CodecFormat::VP9) => EncoderCfg::VPX(VpxEncoderConfig {
-            width: c.width as _,
-            height: c.height as _,
+            width: width as _,
+            height: height as _,
             quality,
             codec: if format == CodecFormat::VP8 {
                 VpxVideoCodecId::VP8
@@ -783,19 +3769,24 @@ fn get_encoder_config(
                 VpxVideoCodecId::VP9
             },
             keyframe_interval,
+            deinterlace,
+            display_scale,
         }),
         CodecFormat::AV1 => EncoderCfg::AOM(AomEncoderConfig {
-            width: c.width as _,
-            height: c.height as _,
+            width: width as _,
+            height: height as _,
             quality,
             keyframe_interval,
+            display_scale,
         }),
         _ => EncoderCfg::VPX(VpxEncoderConfig {
-            width: c.width as _,
-            height: c.height as _,
+            width: width as _,
+            height: height as _,
             quality,
             codec: VpxVideoCodecId::VP9,
             keyframe_interval,
+            deinterlace,
+            display_scale,
         }),
     }
 }
@@ -830,6 +3821,57 @@ fn get_recorder(record_incoming: bool, display: usize) -> Arc<Mutex<Option<Recor
     recorder
 }
 
+struct RecordingSession {
+    recorder: Mutex<Recorder>,
+    need_keyframe: AtomicBool,
+}
+
+/// The recording started by `start_recording()`, stopped when dropped.
+pub struct RecordingHandle {
+    display: usize,
+}
+
+impl RecordingHandle {
+    /// Stop the recording, the file is finalized after the frame being written.
+    pub fn stop(self) {}
+}
+
+impl Drop for RecordingHandle {
+    fn drop(&mut self) {
+        RECORDINGS.lock().unwrap().remove(&self.display);
+        log::info!("stop recording display {}", self.display);
+    }
+}
+
+/// Record the encoded frames of the display to `dir` on the server, independent of the
+/// "allow-auto-record-incoming" option, e.g. for compliance.
+///
+/// VP8/VP9/AV1 frames are saved as WebM, H264/H265 as MP4.
+/// The encoder restarts with the pts from zero on display switches or codec changes,
+/// the recording continues in a new file then, starting with a keyframe.
+pub fn start_recording(dir: &Path, display: usize) -> ResultType<RecordingHandle> {
+    let mut recordings = RECORDINGS.lock().unwrap();
+    if recordings.contains_key(&display) {
+        bail!("display {} is already being recorded", display);
+    }
+    let recorder = Recorder::new(RecorderContext {
+        server: true,
+        id: Config::get_id(),
+        dir: dir.to_string_lossy().to_string(),
+        display,
+        tx: None,
+    })?;
+    recordings.insert(
+        display,
+        Arc::new(RecordingSession {
+            recorder: Mutex::new(recorder),
+            need_keyframe: AtomicBool::new(true),
+        }),
+    );
+    log::info!("start recording display {} to {:?}", display, dir);
+    Ok(RecordingHandle { display })
+}
+
 #[cfg(target_os = "android")]
 fn check_change_scale(hardware: bool) -> ResultType<()> {
     use hbb_common::config::keys::OPTION_ENABLE_ANDROID_SOFTWARE_ENCODING_HALF_SCALE as SCALE_SOFT;
@@ -880,7 +3922,9 @@ fn check_privacy_mode_changed(
                 back_notification::PrivacyModeState::PrvOnByOther,
                 "".to_owned(),
             );
-            sp.send_to_others(msg_out, privacy_mode_id_2);
+            // `PrvOnByOther` is for the connections which did not turn on privacy mode.
+            let excluded: Vec<i32> = get_privacy_mode_conn_ids().into_iter().collect();
+            sp.send_except(msg_out, &excluded);
         }
         log::info!("switch due to privacy mode changed");
         try_broadcast_display_changed(&sp, display_idx, ci, true).ok();
@@ -889,46 +3933,267 @@ fn check_privacy_mode_changed(
     Ok(())
 }
 
+// Snap the frame timestamps to the frame duration grid, so the scheduler jitter of the
+// capture loop doesn't reach the decoder. A gap of several frame durations is kept as
+// dropped frames, and the grid is re-anchored if it drifts away from the real time.
+#[derive(Default)]
+struct PtsSmoother {
+    last: Option<i64>,
+    dropped: u64,
+}
+
+impl PtsSmoother {
+    fn next(&mut self, ms: i64, spf: Duration) -> i64 {
+        let nominal = (spf.as_millis() as i64).max(1);
+        let Some(last) = self.last else {
+            self.last = Some(ms);
+            return ms;
+        };
+        let delta = ms - last;
+        let frames = ((delta + nominal / 2) / nominal).max(1);
+        if delta * 2 > nominal * 3 {
+            self.dropped += (frames - 1) as u64;
+            tracing::debug!(
+                "pts delta {delta}ms is over 1.5 * {nominal}ms, {} frames dropped",
+                frames - 1
+            );
+        }
+        let mut pts = last + frames * nominal;
+        if (pts - ms).abs() > nominal {
+            pts = ms.max(last + 1);
+            tracing::debug!("pts re-anchored to {pts}");
+        }
+        self.last = Some(pts);
+        pts
+    }
+}
+
+// The black frames encoded and discarded before the first real frame.
+const WARM_UP_FRAMES: i64 = 3;
+
+// Let the software encoder tune its internal state on black frames, so the first real
+// frames after a display switch don't stutter.
+// The first real frame is forced to be a keyframe, because the clients don't have the
+// warm-up frames. Hardware encoders can't force a keyframe and are skipped.
+fn warm_up_encoder(encoder: &mut Encoder) {
+    if encoder.is_hardware() || !encoder.latency_free() {
+        return;
+    }
+    let begin = Instant::now();
+    let yuv = black_yuv(&encoder.yuvfmt());
+    // Negative pts, before the real frames.
+    for pts in -WARM_UP_FRAMES..0 {
+        if let Err(e) = encoder.encode_to_message(EncodeInput::YUV(&yuv), pts) {
+            log::warn!("failed to warm up encoder: {e:?}");
+            break;
+        }
+    }
+    if !encoder.request_keyframe() {
+        log::error!("encoder warmed up but can't force a keyframe");
+    }
+    tracing::debug!("encoder warm-up took {:?}", begin.elapsed());
+}
+
+fn black_yuv(fmt: &scrap::EncodeYuvFormat) -> Vec<u8> {
+    use scrap::Pixfmt;
+
+    let chroma_h = if fmt.pixfmt == Pixfmt::I444 {
+        fmt.h
+    } else {
+        (fmt.h + 1) / 2
+    };
+    let len = match fmt.pixfmt {
+        Pixfmt::NV12 => fmt.u + fmt.stride[1] * chroma_h,
+        _ => fmt.v + fmt.stride[2] * chroma_h,
+    };
+    // BT.601 limited range black.
+    let mut yuv = vec![16u8; len];
+    yuv[fmt.u..].fill(128);
+    yuv
+}
+
+// Recreate the software encoder in place if its context is corrupted,
+// instead of restarting the whole video service.
+struct EncoderRecovery {
+    cfg: EncoderCfg,
+    i444: bool,
+    times: usize,
+}
+
+impl EncoderRecovery {
+    const MAX_TIMES: usize = 3;
+
+    fn new(cfg: EncoderCfg, i444: bool) -> Self {
+        Self {
+            cfg,
+            i444,
+            times: 0,
+        }
+    }
+
+    fn recreate(&mut self, encoder: &mut Encoder) -> ResultType<()> {
+        if self.times >= Self::MAX_TIMES {
+            bail!("encoder has been recreated {} times", self.times);
+        }
+        self.times += 1;
+        *encoder = Encoder::new(self.cfg.clone(), self.i444)?;
+        if encoder.support_changing_quality() {
+            let ratio = VIDEO_QOS.lock().unwrap().ratio();
+            allow_err!(encoder.set_quality(ratio));
+        }
+        Ok(())
+    }
+}
+
+// The errors of the snapshot for the new subscribers. Other errors of the callback are
+// `Fatal`, they are returned from `run()` as they are instead of restarting as a switch.
+#[derive(Debug)]
+enum SnapshotError {
+    // Restart so that the new subscribers start from a keyframe of the new encoder.
+    Switch,
+    Fatal(hbb_common::anyhow::Error),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Switch => write!(f, "SWITCH"),
+            Self::Fatal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
 #[inline]
 fn handle_one_frame(
+    handle: &VideoServiceHandle,
     display: usize,
     sp: &GenericService,
     frame: EncodeInput,
     ms: i64,
     encoder: &mut Encoder,
+    encoder_recovery: &mut EncoderRecovery,
+    keyframe_scheduler: &mut KeyframeScheduler,
+    roi_updater: &mut RoiUpdater,
+    scene_detector: &mut SceneChangeDetector,
+    turbo: &mut TurboPhase,
+    frame_buffer: &mut VideoFrameRingBuffer,
+    frame_controller: &mut VideoFrameController,
     recorder: Arc<Mutex<Option<Recorder>>>,
     encode_fail_counter: &mut usize,
     first_frame: &mut bool,
     width: usize,
     height: usize,
 ) -> ResultType<HashSet<i32>> {
-    sp.snapshot(|sps| {
+    let _span = tracing::info_span!("video_frame", frame_ms = ms, display).entered();
+    let snapshot = sp.snapshot(|sps| {
         // so that new sub and old sub share the same encoder after switch
         if sps.has_subscribes() {
+            if frame_buffer.replay(&sps) {
+                log::info!(
+                    "replay {} buffered frames to new subscriber",
+                    frame_buffer.frames.len()
+                );
+                return Ok(());
+            }
             log::info!("switch due to new subscriber");
-            bail!("SWITCH");
+            return Err(SnapshotError::Switch.into());
         }
         Ok(())
-    })?;
+    });
+    if let Err(e) = snapshot {
+        match e.downcast().unwrap_or_else(SnapshotError::Fatal) {
+            SnapshotError::Switch => bail!("SWITCH"),
+            SnapshotError::Fatal(e) => {
+                log::error!("Failed to snapshot display {display} for the new subscribers: {e:?}");
+                return Err(e);
+            }
+        }
+    }
 
     let mut send_conn_ids: HashSet<i32> = Default::default();
     let first = *first_frame;
     *first_frame = false;
-    match encoder.encode_to_message(frame, ms) {
-        Ok(mut vf) => {
+    if !first {
+        keyframe_scheduler.check(handle, encoder);
+        if handle.background_starved() && !keyframe_scheduler.request(encoder) {
+            log::info!("switch to send a keyframe to the skipped background connections");
+            bail!("SWITCH");
+        }
+    }
+    roi_updater.update(handle, encoder);
+    turbo.update(handle, encoder);
+    scene_detector.update(encoder, &frame);
+    let recording = RECORDINGS.lock().unwrap().get(&display).cloned();
+    if let Some(recording) = &recording {
+        // The recorder drops the frames until a keyframe.
+        if recording.need_keyframe.swap(false, Ordering::Relaxed)
+            && !first
+            && !keyframe_scheduler.request(encoder)
+        {
+            log::info!("switch to start the recording with a keyframe");
+            bail!("SWITCH");
+        }
+    }
+    let encode_begin = Instant::now();
+    #[cfg(feature = "tracing_frames")]
+    {
+        frame_controller.timing.encode_start = Some(encode_begin);
+    }
+    match encode_frame(encoder, frame, ms) {
+        Ok(vf) => {
+            let encode_latency = encode_begin.elapsed();
+            #[cfg(feature = "tracing_frames")]
+            {
+                frame_controller.timing.encode_end = Some(Instant::now());
+            }
+            frame_controller.last_encode_us = encode_latency.as_micros() as _;
             *encode_fail_counter = 0;
-            vf.display = display as _;
-            let mut msg = Message::new();
-            msg.set_video_frame(vf);
+            handle.on_encoded(None);
+            let codec = CodecFormat::from(&vf);
+            let msg = build_video_message(vf, display);
             recorder
                 .lock()
                 .unwrap()
                 .as_mut()
                 .map(|r| r.write_message(&msg, width, height));
-            send_conn_ids = sp.send_video_frame(msg);
+            if let Some(recording) = &recording {
+                recording
+                    .recorder
+                    .lock()
+                    .unwrap()
+                    .write_message(&msg, width, height);
+            }
+            let msg = Arc::new(msg);
+            let (keyframe, bytes) = encoded_frame_info(&msg);
+            if keyframe {
+                turbo.on_keyframe(handle.turbo_params());
+            }
+            let metrics = VideoMetricsCollector::global();
+            metrics.record_encoded(display, codec, bytes, encode_latency);
+            frame_buffer.push(handle, msg.clone());
+            #[cfg(feature = "tracing_frames")]
+            {
+                frame_controller.timing.send_start = Some(Instant::now());
+            }
+            send_conn_ids = broadcast_video_message(
+                handle,
+                sp,
+                msg,
+                keyframe,
+                frame_controller.background_late,
+            );
+            handle.record_sent_bytes(&send_conn_ids, bytes);
+            let stats = handle.publish_stats(display, encode_latency, &send_conn_ids);
+            metrics.set_connections(display, codec, send_conn_ids.len());
+            metrics.set_bitrate(display, codec, stats.actual_bitrate, stats.peak_bitrate);
         }
         Err(e) => {
             *encode_fail_counter += 1;
+            handle.on_encoded(Some(e.to_string()));
+            handle.encode_dropped_frames.fetch_add(1, Ordering::Relaxed);
+            VideoMetricsCollector::global().record_dropped(display, Encoder::negotiated_codec());
             // Encoding errors are not frequent except on Android
             if !cfg!(target_os = "android") {
                 log::error!("encode fail: {e:?}, times: {}", *encode_fail_counter,);
@@ -947,6 +4212,16 @@ fn handle_one_frame(
                     log::error!("switch due to encoding fails, first frame: {first}, error: {e:?}");
                     bail!("SWITCH");
                 }
+                // The new encoder starts with a keyframe, so clients can recover from it.
+                log::warn!(
+                    "recreate encoder due to encoding fails, first frame: {first}, error: {e:?}"
+                );
+                encoder_recovery.recreate(encoder)?;
+                roi_updater.last.clear();
+                frame_buffer.clear();
+                scene_detector.bursting = false;
+                turbo.applied = 1.0;
+                return Ok(send_conn_ids);
             }
             match e.to_string().as_str() {
                 scrap::codec::ENCODE_NEED_SWITCH => {
@@ -961,12 +4236,56 @@ fn handle_one_frame(
     Ok(send_conn_ids)
 }
 
+// The stages of `handle_one_frame()`: encode, build the message, and send it.
+// The post-encode processing of the frames goes between them.
+
+#[inline]
+fn encode_frame(encoder: &mut Encoder, frame: EncodeInput, ms: i64) -> ResultType<VideoFrame> {
+    encoder.encode_to_message(frame, ms)
+}
+
+fn build_video_message(mut vf: VideoFrame, display: usize) -> Message {
+    vf.display = display as _;
+    let mut msg = Message::new();
+    msg.set_video_frame(vf);
+    msg
+}
+
+// Returns the ids of the connections the message is sent to.
+fn broadcast_video_message(
+    handle: &VideoServiceHandle,
+    sp: &GenericService,
+    msg: Arc<Message>,
+    keyframe: bool,
+    skip_background: bool,
+) -> HashSet<i32> {
+    let (sent_conn_ids, failed_conn_ids) =
+        handle.send_video_frame(sp, msg, keyframe, skip_background);
+    if !failed_conn_ids.is_empty() {
+        // The connections are closed, do not wait for them to remove the qos states.
+        let mut video_qos = VIDEO_QOS.lock().unwrap();
+        for id in failed_conn_ids {
+            video_qos.on_connection_close(id);
+            handle.on_connection_close(id);
+        }
+    }
+    sent_conn_ids
+}
+
 #[inline]
 pub fn refresh() {
     #[cfg(target_os = "android")]
     Display::refresh_size();
 }
 
+/// Refresh all the video services after `delay_ms`, each restart takes ~200 ms.
+/// The requests within the delay are merged into one restart, e.g. while dragging a slider.
+/// Use `refresh()` if the refresh should take effect immediately.
+#[inline]
+pub fn refresh_debounced(delay_ms: u64) {
+    VideoServiceHandle::global().refresh_debounced(Duration::from_millis(delay_ms));
+}
+
 #[cfg(windows)]
 fn start_uac_elevation_check() {
     static START: Once = Once::new();
@@ -975,11 +4294,11 @@ fn start_uac_elevation_check() {
             std::thread::spawn(|| loop {
                 std::thread::sleep(std::time::Duration::from_secs(1));
                 if let Ok(uac) = is_process_consent_running() {
-                    *IS_UAC_RUNNING.lock().unwrap() = uac;
+                    IS_UAC_RUNNING.store(uac, Ordering::Relaxed);
                 }
                 if !crate::platform::is_elevated(None).unwrap_or(false) {
                     if let Ok(elevated) = crate::platform::is_foreground_window_elevated() {
-                        *IS_FOREGROUND_WINDOW_ELEVATED.lock().unwrap() = elevated;
+                        IS_FOREGROUND_WINDOW_ELEVATED.store(elevated, Ordering::Relaxed);
                     }
                 }
             });
@@ -1004,11 +4323,18 @@ fn try_broadcast_display_changed(
         (cap.origin.0, cap.origin.1, cap.width, cap.height),
     ) {
         log::info!("Display {} changed", display);
+        VideoMetricsCollector::global()
+            .record_display_switch(display_idx, Encoder::negotiated_codec());
         if let Some(msg_out) = make_display_changed_msg(display_idx, Some(display)) {
+            // The peers update all the displays before switching, e.g. a monitor is plugged
+            // in, the display service may sync them later.
+            let displays_msg = Arc::new(display_service::sync_displays_msg());
             let msg_out = Arc::new(msg_out);
+            sp.send_shared(displays_msg.clone());
             sp.send_shared(msg_out.clone());
             // switch display may occur before the first video frame, add snapshot to send to new subscribers
             sp.snapshot(move |sps| {
+                sps.send_shared(displays_msg.clone());
                 sps.send_shared(msg_out.clone());
                 Ok(())
             })?;
@@ -1018,6 +4344,20 @@ fn try_broadcast_display_changed(
     Ok(())
 }
 
+// There is no message for the frame size changes only, the peers reset their rendering
+// surfaces on `SwitchDisplay`, which has the unchanged display size.
+fn broadcast_frame_size_changed(sp: &GenericService, display_idx: usize) -> ResultType<()> {
+    if let Some(msg_out) = make_display_changed_msg(display_idx, None) {
+        let msg_out = Arc::new(msg_out);
+        sp.send_shared(msg_out.clone());
+        sp.snapshot(move |sps| {
+            sps.send_shared(msg_out.clone());
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
 pub fn make_display_changed_msg(
     display_idx: usize,
     opt_display: Option<DisplayInfo>,
@@ -1027,6 +4367,8 @@ pub fn make_display_changed_msg(
         None => get_display_info(display_idx)?,
     };
     let mut misc = Misc::new();
+    // `SwitchDisplay` has no name or scale, the peers look them up in `PeerInfo.displays`
+    // by `display`, which is synced by the display service on changes.
     misc.set_switch_display(SwitchDisplay {
         display: display_idx as _,
         x: display.x,
@@ -1059,6 +4401,7 @@ fn check_qos(
     client_record: bool,
     send_counter: &mut usize,
     second_instant: &mut Instant,
+    ack_wait: &mut (Duration, u32),
     display_idx: usize,
 ) -> ResultType<()> {
     let mut video_qos = VIDEO_QOS.lock().unwrap();
@@ -1070,7 +4413,7 @@ fn check_qos(
             video_qos.store_bitrate(encoder.bitrate());
         } else {
             // Now only vaapi doesn't support changing quality
-            if !video_qos.in_vbr_state() && !video_qos.latest_quality().is_custom() {
+            if !video_qos.in_vbr_state() && !video_qos.target_quality().is_custom() {
                 log::info!("switch to change quality");
                 bail!("SWITCH");
             }
@@ -1082,9 +4425,718 @@ fn check_qos(
     }
     if second_instant.elapsed() > Duration::from_secs(1) {
         *second_instant = Instant::now();
-        video_qos.update_display_data(display_idx, *send_counter);
+        let avg_ack_wait = if ack_wait.1 > 0 {
+            Some(ack_wait.0 / ack_wait.1)
+        } else {
+            None
+        };
+        video_qos.update_display_data(display_idx, *send_counter, avg_ack_wait);
         *send_counter = 0;
+        *ack_wait = (Duration::ZERO, 0);
     }
     drop(video_qos);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAKE_CONN_ID: i32 = 1;
+
+    // A service with one connection recording all the messages sent to it, to exercise the
+    // functions taking `&GenericService` without running the service.
+    // `GenericService::new()` starts no threads, they are started by `run()` or `repeat()`.
+    struct FakeGenericService {
+        sp: GenericService,
+        rx: UnboundedReceiver<(Instant, Arc<Message>)>,
+        rx_video: UnboundedReceiver<(Instant, Arc<Message>)>,
+        sent: Vec<Arc<Message>>,
+    }
+
+    impl FakeGenericService {
+        fn new(name: &str) -> Self {
+            let sp = GenericService::new(name.to_owned(), false);
+            let (tx, rx) = unbounded_channel();
+            let (tx_video, rx_video) = unbounded_channel();
+            sp.on_subscribe(ConnInner::new(FAKE_CONN_ID, Some(tx), Some(tx_video)));
+            Self {
+                sp,
+                rx,
+                rx_video,
+                sent: vec![],
+            }
+        }
+
+        // The messages sent so far, the video channel ones (video frames and display switches)
+        // after the others.
+        fn sent(&mut self) -> &[Arc<Message>] {
+            for rx in [&mut self.rx, &mut self.rx_video] {
+                while let Ok((_, msg)) = rx.try_recv() {
+                    self.sent.push(msg);
+                }
+            }
+            &self.sent
+        }
+    }
+
+    impl Deref for FakeGenericService {
+        type Target = GenericService;
+
+        fn deref(&self) -> &Self::Target {
+            &self.sp
+        }
+    }
+
+    #[test]
+    #[cfg(any(windows, target_os = "linux"))]
+    fn test_handle_one_frame_vp9() {
+        let (w, h) = (64, 64);
+        let frames = (0..10)
+            .map(|i| (0..w * h * 4).map(|j| ((i * 7 + j) % 251) as u8).collect())
+            .collect();
+        set_test_capturer(TestCapturer::new(frames, w, h));
+        let mut c = get_capturer(0, false, None).unwrap();
+        let cfg = EncoderCfg::VPX(VpxEncoderConfig {
+            width: w as _,
+            height: h as _,
+            quality: 1.0,
+            codec: VpxVideoCodecId::VP9,
+            keyframe_interval: None,
+            deinterlace: false,
+            display_scale: 1.0,
+        });
+        let mut encoder = Encoder::new(cfg.clone(), false).unwrap();
+        let handle = VideoServiceHandle::new_isolated();
+        let mut sp = FakeGenericService::new("test_video");
+        let mut encoder_recovery = EncoderRecovery::new(cfg, false);
+        let mut keyframe_scheduler = KeyframeScheduler::new(Duration::ZERO);
+        let mut roi_updater = RoiUpdater::new(&c);
+        let mut scene_detector = SceneChangeDetector::new();
+        let mut turbo = TurboPhase::new();
+        let mut frame_buffer = VideoFrameRingBuffer::default();
+        let mut frame_controller = VideoFrameController::new();
+        let (mut yuv, mut mid_data) = (vec![], vec![]);
+        let (mut encode_fail_counter, mut first_frame) = (0, true);
+        for i in 0..10 {
+            let frame = c.frame(Duration::ZERO).unwrap();
+            let input = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data).unwrap();
+            handle_one_frame(
+                &handle,
+                0,
+                &sp,
+                input,
+                i * 33,
+                &mut encoder,
+                &mut encoder_recovery,
+                &mut keyframe_scheduler,
+                &mut roi_updater,
+                &mut scene_detector,
+                &mut turbo,
+                &mut frame_buffer,
+                &mut frame_controller,
+                Default::default(),
+                &mut encode_fail_counter,
+                &mut first_frame,
+                w,
+                h,
+            )
+            .unwrap();
+        }
+        assert!(matches!(
+            c.frame(Duration::ZERO),
+            Err(CaptureError::Timeout)
+        ));
+        assert_eq!(encode_fail_counter, 0);
+        assert_eq!(frame_buffer.frames.len(), 10);
+        assert!(encoded_frame_info(&frame_buffer.frames[0]).0);
+        assert!(frame_buffer
+            .frames
+            .iter()
+            .all(|m| m.video_frame().has_vp9s()));
+        assert_eq!(sp.sent().len(), 10);
+        assert!(sp.sent().iter().all(|m| m.has_video_frame()));
+    }
+
+    #[test]
+    #[cfg(any(windows, target_os = "linux"))]
+    fn test_check_privacy_mode_and_display_changed() {
+        let (w, h) = (64, 64);
+        let mut sp = FakeGenericService::new("test_video_changed");
+        let mut ci = TestCapturer::new(vec![], w, h).into_info(0);
+        // No privacy mode in the tests.
+        assert!(check_privacy_mode_changed(&sp, 0, &ci).is_ok());
+        ci.privacy_mode_id = FAKE_CONN_ID;
+        let res = check_privacy_mode_changed(&sp, 0, &ci);
+        assert_eq!(res.err().map(|e| e.to_string()), Some("SWITCH".to_owned()));
+        // `PrvOnByOther` is only sent if another connection turns on the privacy mode.
+        assert!(sp.sent().is_empty());
+
+        let display = display_service::MockDisplay {
+            width: w,
+            height: h,
+            primary: true,
+            ..Default::default()
+        };
+        display_service::set_display_provider(Box::new(display_service::VecDisplayProvider(vec![
+            display.clone(),
+        ])));
+        assert!(try_broadcast_display_changed(&sp, 0, &ci, true).is_ok());
+        assert!(sp.sent().is_empty());
+        display_service::set_display_provider(Box::new(display_service::VecDisplayProvider(vec![
+            display_service::MockDisplay {
+                width: 2 * w,
+                ..display
+            },
+        ])));
+        let res = try_broadcast_display_changed(&sp, 0, &ci, true);
+        display_service::clear_display_provider();
+        assert_eq!(res.err().map(|e| e.to_string()), Some("SWITCH".to_owned()));
+        let sent = sp.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].peer_info().displays.len(), 1);
+        assert_eq!(sent[0].peer_info().displays[0].width, 2 * w as i32);
+        assert_eq!(sent[1].misc().switch_display().width, 2 * w as i32);
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        let (w, h) = (64, 64);
+        let cfg = EncoderCfg::VPX(VpxEncoderConfig {
+            width: w,
+            height: h,
+            quality: 1.0,
+            codec: VpxVideoCodecId::VP9,
+            keyframe_interval: None,
+            deinterlace: false,
+            display_scale: 1.0,
+        });
+        let mut encoder = Encoder::new(cfg, false).unwrap();
+        let yuv = black_yuv(&encoder.yuvfmt());
+        let vf = encode_frame(&mut encoder, EncodeInput::YUV(&yuv), 0).unwrap();
+        assert!(vf.has_vp9s());
+        assert!(encode_frame(&mut encoder, EncodeInput::YUV(&yuv[..yuv.len() / 2]), 33).is_err());
+    }
+
+    #[test]
+    fn test_build_video_message() {
+        let mut vf = VideoFrame::new();
+        vf.set_vp9s(EncodedVideoFrames {
+            frames: vec![EncodedVideoFrame {
+                data: vec![0u8; 10].into(),
+                key: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        let msg = build_video_message(vf, 2);
+        assert_eq!(msg.video_frame().display, 2);
+        assert_eq!(encoded_frame_info(&msg), (true, 10));
+    }
+
+    #[test]
+    fn test_broadcast_video_message() {
+        let handle = VideoServiceHandle::new_isolated();
+        let sp = GenericService::new("test_broadcast".to_owned(), false);
+        let (tx, mut rx) = unbounded_channel();
+        sp.on_subscribe(ConnInner::new(1, None, Some(tx)));
+        // The receiver is dropped.
+        sp.on_subscribe(ConnInner::new(2, None, Some(unbounded_channel().0)));
+        sp.on_subscribe(ConnInner::new(3, None, None));
+        handle.pause_capture(3);
+        let msg = Arc::new(build_video_message(VideoFrame::new(), 0));
+        let sent = broadcast_video_message(&handle, &sp, msg, false, false);
+        assert_eq!(sent, [1].into());
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_pts_smoother() {
+        let spf = Duration::from_millis(33);
+        let jitters = [0, 7, -5, 9, -8, 3, -2, 8, -7, 1];
+        let mut smoother = PtsSmoother::default();
+        let mut last = None;
+        for i in 0..100i64 {
+            // the 50th frame is not captured
+            if i == 50 {
+                continue;
+            }
+            let ideal = 1000 + i * 33;
+            let ms = ideal + jitters[i as usize % jitters.len()];
+            let pts = smoother.next(ms, spf);
+            if let Some(last) = last {
+                assert!(pts > last, "pts {pts} is not after {last}");
+            }
+            assert!((pts - ideal).abs() <= 10, "pts {pts}, ideal {ideal}");
+            last = Some(pts);
+        }
+        assert_eq!(smoother.dropped, 1);
+    }
+
+    #[test]
+    fn test_concurrent_refresh_switch_once() {
+        let sp = GenericService::new(get_service_name(0), true);
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let sp = sp.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    sp.set_option_bool(OPTION_REFRESH, true);
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let switches = (0..10)
+            .filter(|_| sp.take_option_true(OPTION_REFRESH))
+            .count();
+        assert_eq!(switches, 1);
+    }
+
+    #[test]
+    fn test_isolated_handle() {
+        let handle = VideoServiceHandle::new_isolated();
+        handle.pause_capture(1);
+        handle.set_keyframe_policy(KeyframePolicy::OnPacketLoss);
+        assert!(handle.paused_conn_ids().contains(&1));
+        assert!(!VideoServiceHandle::global().paused_conn_ids().contains(&1));
+        assert_eq!(
+            VideoServiceHandle::global().keyframe_policy(),
+            KeyframePolicy::OnSwitchOnly
+        );
+        handle.resume_capture(1);
+        assert!(handle.paused_conn_ids().is_empty());
+    }
+
+    #[test]
+    fn test_is_healthy() {
+        let handle = VideoServiceHandle::new_isolated();
+        let status = handle.is_healthy();
+        assert!(!status.capturing && status.encoding);
+        assert_eq!(status.last_frame_age_ms, u64::MAX);
+        handle.on_captured();
+        handle.on_encoded(Some("failed".to_owned()));
+        let status = handle.is_healthy();
+        assert!(status.capturing && !status.encoding);
+        assert!(status.last_frame_age_ms <= HEALTH_MAX_FRAME_AGE_MS);
+        assert_eq!(status.error.as_deref(), Some("encode: failed"));
+        handle.on_encoded(None);
+        handle.health.lock().unwrap().last_frame =
+            Instant::now().checked_sub(Duration::from_millis(HEALTH_MAX_FRAME_AGE_MS + 1));
+        let status = handle.is_healthy();
+        assert!(!status.capturing && status.encoding && status.error.is_none());
+    }
+
+    #[test]
+    fn test_bitrate_meter() {
+        let mut meter = BitrateMeter::default();
+        let begin = Instant::now();
+        let ms = |ms| begin + Duration::from_millis(ms);
+        for i in 0..10 {
+            meter.record(ms(i * 100), 1000);
+        }
+        // A burst.
+        meter.record(ms(950), 2000);
+        meter.record(ms(960), 2000);
+        assert_eq!(meter.actual(ms(990)), 14_000 * 8);
+        assert_eq!(meter.peak(ms(990)), 5000 * 8 * 10);
+        // The first two samples are out of the window.
+        assert_eq!(meter.actual(ms(1150)), 12_000 * 8);
+        assert_eq!(meter.actual(ms(3000)), 0);
+        assert_eq!(meter.peak(ms(3000)), 0);
+    }
+
+    #[test]
+    fn test_capture_thread_affinity() {
+        let handle = VideoServiceHandle::new_isolated();
+        let count = num_cpus::get();
+        assert!(handle.set_capture_thread_affinity(&[count]).is_err());
+        assert!(handle.capture_thread_affinity.lock().unwrap().is_none());
+        handle.set_capture_thread_affinity(&[0]).unwrap();
+        assert_eq!(
+            *handle.capture_thread_affinity.lock().unwrap(),
+            Some(vec![0])
+        );
+        handle.set_capture_thread_affinity(&[]).unwrap();
+        assert_eq!(
+            *handle.capture_thread_affinity.lock().unwrap(),
+            Some((0..count).collect())
+        );
+    }
+
+    #[test]
+    fn test_shutdown_gracefully() {
+        let handle = VideoServiceHandle::new_isolated();
+        assert!(!handle.draining());
+        RUNNING_VIDEO_SERVICES.fetch_add(1, Ordering::SeqCst);
+        assert!(!handle.shutdown_gracefully(Duration::from_millis(20)));
+        assert!(handle.draining() && handle.drain_expired());
+        let t = std::thread::spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            RUNNING_VIDEO_SERVICES.fetch_sub(1, Ordering::SeqCst);
+        });
+        assert!(handle.shutdown_gracefully(Duration::from_secs(5)));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_connection_priority() {
+        let handle = VideoServiceHandle::new_isolated();
+        handle.set_connection_priority(1, Priority::High);
+        handle.set_connection_priority(2, Priority::Background);
+        assert_eq!(handle.connection_priority(1), Priority::High);
+        assert_eq!(handle.connection_priority(3), Priority::Normal);
+        assert!(Priority::High < Priority::Normal && Priority::Normal < Priority::Background);
+        handle
+            .starved_conn_ids
+            .lock()
+            .unwrap()
+            .insert(2, Instant::now());
+        handle.set_connection_priority(2, Priority::Normal);
+        assert!(handle.conn_priorities.lock().unwrap().get(&2).is_none());
+        assert!(handle.starved_conn_ids.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_frame_valid() {
+        let (w, h, stride) = (4, 2, 16);
+        let mut frame = vec![0u8; stride * h];
+        assert!(!is_frame_valid(&frame, w, h, stride));
+        frame[20] = 0xFF;
+        assert!(is_frame_valid(&frame, w, h, stride));
+        assert!(!is_frame_valid(&frame[..stride], w, h, stride));
+        assert!(!is_frame_valid(&frame, w, h, 2));
+        frame[..stride].fill(0xCD);
+        assert!(!is_frame_valid(&frame, w, h, stride));
+    }
+
+    #[test]
+    fn test_deferred_display_switch() {
+        let mut switch = DeferredDisplaySwitch::default();
+        assert!(!switch.ready(false));
+        assert!(switch.ready(true));
+        let guard = lock_display_switch(1);
+        assert!(!switch.ready(true));
+        assert!(!switch.ready(false));
+        drop(guard);
+        // Deferred, not lost.
+        assert!(switch.ready(false));
+        assert!(!switch.ready(false));
+    }
+
+    #[test]
+    fn test_turbo_phase() {
+        let p = TurboParams {
+            multiplier: 3.0,
+            ramp_frames: 4,
+        };
+        let mut turbo = TurboPhase::new();
+        assert_eq!(turbo.next_multiplier(p), 1.0);
+        turbo.on_keyframe(p);
+        let ms: Vec<f32> = (0..6).map(|_| turbo.next_multiplier(p)).collect();
+        assert_eq!(ms, vec![3.0, 2.5, 2.0, 1.5, 1.0, 1.0]);
+        // Disabled.
+        turbo.on_keyframe(TurboParams {
+            multiplier: 1.0,
+            ramp_frames: 4,
+        });
+        assert_eq!(turbo.next_multiplier(p), 1.0);
+    }
+
+    #[test]
+    fn test_scaled_size() {
+        assert_eq!(scaled_size((1920, 1080), (1920, 1080)), None);
+        assert_eq!(scaled_size((3840, 2160), (1920, 1080)), Some((1920, 1080)));
+        assert_eq!(scaled_size((5120, 2880), (1920, 1080)), Some((1920, 1080)));
+        // The aspect ratio is kept.
+        assert_eq!(scaled_size((3440, 1440), (1920, 1080)), Some((1920, 802)));
+        assert_eq!(scaled_size((2160, 3840), (1920, 1080)), Some((606, 1080)));
+    }
+
+    #[test]
+    fn test_bandwidth_controller() {
+        let policy = AutoScalePolicy {
+            enabled: true,
+            min_width: 1280,
+            step_pct: 50,
+        };
+        let mut controller = BandwidthController::new();
+        let mut now = Instant::now();
+        let mut check = |actual_bps: u64, width: usize| {
+            now += Duration::from_secs(1);
+            controller.check(now, &policy, actual_bps, 1_000_000, width)
+        };
+        // Within the tolerance.
+        for _ in 0..10 {
+            assert_eq!(check(1_200_000, 3840), None);
+        }
+        for _ in 0..AUTO_SCALE_OVER_BUDGET_SECS - 1 {
+            assert_eq!(check(2_000_000, 3840), None);
+        }
+        assert_eq!(check(2_000_000, 3840), Some(1920));
+        // Restarted counting, and not below the min width.
+        for _ in 0..AUTO_SCALE_OVER_BUDGET_SECS - 1 {
+            assert_eq!(check(2_000_000, 1920), None);
+        }
+        assert_eq!(check(2_000_000, 1920), Some(1280));
+        for _ in 0..AUTO_SCALE_OVER_BUDGET_SECS {
+            assert_eq!(check(2_000_000, 1280), None);
+        }
+        assert_eq!(
+            scaled_size((3840, 2160), max_frame_size(None, Some(1920)).unwrap()),
+            Some((1920, 1080))
+        );
+        assert_eq!(
+            max_frame_size(Some((2560, 1440)), Some(1920)),
+            Some((1920, 1440))
+        );
+    }
+
+    #[test]
+    fn test_capture_region_crop() {
+        let region = CaptureRegion {
+            x: 1900,
+            y: -100,
+            w: 301,
+            h: 500,
+        };
+        // The right part of the region is on the second display.
+        assert_eq!(
+            region.crop((0, 0), 1920, 1080),
+            Some(CaptureRegion {
+                x: 1900,
+                y: 0,
+                w: 20,
+                h: 400
+            })
+        );
+        assert_eq!(
+            region.crop((1920, 0), 1920, 1080),
+            Some(CaptureRegion {
+                x: 0,
+                y: 0,
+                w: 280,
+                h: 400
+            })
+        );
+        assert_eq!(region.crop((0, 1080), 1920, 1080), None);
+    }
+
+    #[test]
+    fn test_scene_change() {
+        let (w, h) = (64, 32);
+        let dark = vec![16u8; w * h];
+        let mut bright = dark.clone();
+        bright[..w * h / 2].fill(200);
+        let mut detector = SceneChangeDetector::new();
+        assert_eq!(detector.luma_mad(&dark, w, h, w), None);
+        assert_eq!(detector.luma_mad(&dark, w, h, w), Some(0));
+        assert!(detector.luma_mad(&bright, w, h, w).unwrap() > SCENE_CHANGE_MAD);
+        assert_eq!(detector.luma_mad(&bright, w, h, w), Some(0));
+    }
+
+    #[test]
+    fn test_motion_fps_booster() {
+        let m = MotionAdaptiveFps {
+            enabled: true,
+            high_fps: 50,
+            low_fps: 25,
+        };
+        let (high, low) = (Duration::from_millis(20), Duration::from_millis(40));
+        let qos_spf = Duration::from_millis(33);
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut booster = MotionFpsBooster::default();
+        assert_eq!(booster.spf(at(0), &m, qos_spf), low);
+        booster.on_frame(at(0), Some(MOTION_MAD + 1));
+        assert_eq!(booster.spf(at(0), &m, qos_spf), high);
+        booster.on_frame(at(600), Some(MOTION_MAD + 1));
+        booster.on_frame(at(800), None);
+        booster.on_frame(at(1000), Some(0));
+        assert_eq!(booster.spf(at(1500), &m, qos_spf), high);
+        assert_eq!(booster.spf(at(1600), &m, qos_spf), low);
+        // The fps lowered by the slow network is kept.
+        assert_eq!(
+            booster.spf(at(0), &m, Duration::from_millis(100)),
+            Duration::from_millis(100)
+        );
+        let disabled = MotionAdaptiveFps::default();
+        assert_eq!(booster.spf(at(0), &disabled, qos_spf), qos_spf);
+    }
+
+    #[test]
+    fn test_stall_tracker() {
+        let handle = VideoServiceHandle::new_isolated();
+        handle.set_slow_client_policy(SlowClientPolicy {
+            max_stalls: 3,
+            ..Default::default()
+        });
+        let sp = GenericService::new("test".to_owned(), false);
+        let warnings = handle.subscribe_slow_client_warnings();
+        let sent: HashSet<i32> = [1, 2].into();
+        let fetched: HashSet<i32> = [1].into();
+        let mut tracker = StallTracker::default();
+        tracker.update(&handle, &sp, 0, &sent, &fetched);
+        tracker.update(&handle, &sp, 0, &sent, &sent);
+        for _ in 0..2 {
+            tracker.update(&handle, &sp, 0, &sent, &fetched);
+        }
+        // The stalls are consecutive, reset by the fetched frame.
+        assert_eq!(*warnings.borrow(), None);
+        tracker.update(&handle, &sp, 0, &sent, &fetched);
+        assert_eq!(
+            *warnings.borrow(),
+            Some(SlowClientWarning {
+                conn_id: 2,
+                display_idx: 0,
+                stalls: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_keyframe_buffer() {
+        let frame = |key: bool, len: usize| {
+            let mut vf = VideoFrame::new();
+            vf.set_vp9s(EncodedVideoFrames {
+                frames: vec![EncodedVideoFrame {
+                    data: vec![0u8; len].into(),
+                    key,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            });
+            let mut msg = Message::new();
+            msg.set_video_frame(vf);
+            Arc::new(msg)
+        };
+        let handle = VideoServiceHandle::new_isolated();
+        handle.set_keyframe_buffer_depth(3);
+        handle.set_keyframe_buffer_max_bytes(100);
+        let mut buffer = VideoFrameRingBuffer::default();
+        // Wait for a keyframe.
+        buffer.push(&handle, frame(false, 10));
+        assert!(buffer.frames.is_empty());
+        buffer.push(&handle, frame(true, 10));
+        buffer.push(&handle, frame(false, 10));
+        assert_eq!((buffer.frames.len(), buffer.bytes), (2, 20));
+        buffer.push(&handle, frame(true, 10));
+        assert_eq!(buffer.frames.len(), 1);
+        // Over the depth.
+        buffer.push(&handle, frame(false, 10));
+        buffer.push(&handle, frame(false, 10));
+        buffer.push(&handle, frame(false, 10));
+        assert!(buffer.frames.is_empty());
+        // Over the bytes.
+        buffer.push(&handle, frame(true, 60));
+        buffer.push(&handle, frame(false, 50));
+        assert!(buffer.frames.is_empty());
+    }
+
+    #[test]
+    fn test_drain_stale_acks() {
+        let (active, closed, reused) = (1_000_001, 1_000_002, 1_000_004);
+        let active_token = new_session_token(active);
+        let closed_token = new_session_token(closed);
+        release_session_token(closed, closed_token);
+        let old_token = new_session_token(reused);
+        release_session_token(reused, old_token);
+        let reused_token = new_session_token(reused);
+        notify_video_frame_fetched(active, active_token, None);
+        notify_video_frame_fetched(closed, closed_token, None);
+        notify_video_frame_fetched(reused, old_token, None);
+        VideoFrameController::new().drain_stale_acks(&HashSet::from([active, reused]));
+        let mut rx = FRAME_FETCHED_NOTIFIER.1.try_lock().unwrap();
+        let mut acks = vec![];
+        while let Ok((id, token, _)) = rx.try_recv() {
+            acks.push((id, token));
+        }
+        assert!(acks.contains(&(active, active_token)));
+        assert!(!acks.iter().any(|(id, _)| *id == closed || *id == reused));
+        release_session_token(active, active_token);
+        release_session_token(reused, reused_token);
+    }
+
+    #[tokio::test]
+    async fn test_frame_controller_in_runtime() {
+        check_frame_controller_acks(1_000_003);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_frame_controller_in_multi_thread_runtime() {
+        check_frame_controller_acks(1_000_004);
+    }
+
+    #[test]
+    fn test_frame_controller_without_runtime() {
+        check_frame_controller_acks(1_000_005);
+    }
+
+    fn check_frame_controller_acks(id: i32) {
+        let old_token = new_session_token(id);
+        let token = new_session_token(id);
+        let (tx, rx) = unbounded_channel();
+        let mut controller =
+            VideoFrameController::with_notifier((tx.clone(), Arc::new(TokioMutex::new(rx))));
+        controller.set_send(Instant::now(), HashSet::from([id]));
+        let mut fetched = HashSet::new();
+        tx.send((id, old_token, None)).unwrap();
+        controller.try_wait_next(&mut fetched, 100);
+        assert!(fetched.is_empty());
+        tx.send((id, token, None)).unwrap();
+        controller.try_wait_next(&mut fetched, 100);
+        assert!(fetched.contains(&id));
+        // Timed out without acks.
+        fetched.clear();
+        controller.try_wait_next(&mut fetched, 10);
+        assert!(fetched.is_empty());
+        release_session_token(id, token);
+        // Not waiting for the closed connection.
+        controller.reset();
+        controller.set_send(Instant::now(), HashSet::from([id]));
+        assert!(controller.send_conn_ids.is_empty());
+        // Dropped inside the runtime if any.
+        drop(controller);
+    }
+
+    #[test]
+    fn test_refresh_debounced() {
+        let handle = VideoServiceHandle::new_isolated();
+        let seq = handle.poll_refresh();
+        handle.refresh_debounced(Duration::from_millis(100));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(handle.poll_refresh(), seq);
+        // Replaces the pending one.
+        handle.refresh_debounced(Duration::from_millis(100));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(handle.poll_refresh(), seq);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(handle.poll_refresh(), seq + 1);
+        assert_eq!(handle.poll_refresh(), seq + 1);
+    }
+
+    #[test]
+    fn test_adaptive_ack_timeout() {
+        let mut timeout = AdaptiveAckTimeout::default();
+        // No network delays measured.
+        assert_eq!(timeout.timeout_ms(None, 3_000), 3_000);
+        timeout.update(Duration::from_millis(10), true);
+        assert_eq!(timeout.timeout_ms(None, 3_000), 3_000);
+        assert_eq!(timeout.timeout_ms(Some(100), 3_000), 200);
+        // LAN
+        for _ in 0..100 {
+            timeout.update(Duration::from_millis(5), true);
+        }
+        assert_eq!(timeout.timeout_ms(Some(100), 3_000), 50);
+        // Back off on the timeouts.
+        timeout.update(Duration::from_millis(50), false);
+        assert!(timeout.timeout_ms(None, 3_000) > 50);
+        for _ in 0..10 {
+            timeout.update(Duration::from_millis(5_000), false);
+        }
+        assert_eq!(timeout.timeout_ms(None, 3_000), 5_000);
+    }
+}
@@ -1,6 +1,8 @@
 use super::*;
 use hbb_common::{allow_err, platform::linux::DISTRO};
-use scrap::{is_cursor_embedded, set_map_err, Capturer, Display, Frame, TraitCapturer};
+use scrap::{
+    is_cursor_embedded, set_map_err, CaptureError, Capturer, Display, Frame, TraitCapturer,
+};
 use std::io;
 use std::process::{Command, Output};
 
@@ -73,7 +75,7 @@ impl Clone for CapturerPtr {
 }
 
 impl TraitCapturer for CapturerPtr {
-    fn frame<'a>(&'a mut self, timeout: Duration) -> io::Result<Frame<'a>> {
+    fn frame<'a>(&'a mut self, timeout: Duration) -> Result<Frame<'a>, CaptureError> {
         unsafe { (*self.0).frame(timeout) }
     }
 }
@@ -266,7 +268,9 @@ pub fn clear() {
     }
 }
 
-pub(super) fn get_capturer() -> ResultType<super::video_service::CapturerInfo> {
+// The portal session shares only the screen selected by the user,
+// so all video services get the same capturer whatever display they ask for.
+pub(super) fn get_capturer(display_idx: usize) -> ResultType<super::video_service::CapturerInfo> {
     if is_x11() {
         bail!("Do not call this function if not wayland");
     }
@@ -275,6 +279,13 @@ pub(super) fn get_capturer() -> ResultType<super::video_service::CapturerInfo> {
         let cap_display_info: *const CapDisplayInfo = addr as _;
         unsafe {
             let cap_display_info = &*cap_display_info;
+            if display_idx != cap_display_info.current {
+                log::warn!(
+                    "Wayland only captures display {}, but display {} is requested",
+                    cap_display_info.current,
+                    display_idx
+                );
+            }
             let rect = cap_display_info.rects[cap_display_info.current];
             Ok(super::video_service::CapturerInfo {
                 origin: rect.0,
@@ -285,6 +296,10 @@ pub(super) fn get_capturer() -> ResultType<super::video_service::CapturerInfo> {
                 privacy_mode_id: 0,
                 _capturer_privacy_mode_id: 0,
                 capturer: Box::new(cap_display_info.capturer.clone()),
+                region: None,
+                refresh_rate: None,
+                scale: None,
+                pool_key: None,
             })
         }
     } else {
@@ -602,6 +602,10 @@ impl UI {
         has_vram()
     }
 
+    fn is_nvenc_available(&self) -> bool {
+        is_nvenc_available()
+    }
+
     fn get_langs(&self) -> String {
         get_langs()
     }
@@ -729,6 +733,7 @@ impl sciter::EventHandler for UI {
         fn get_uuid();
         fn has_hwcodec();
         fn has_vram();
+        fn is_nvenc_available();
         fn get_langs();
         fn video_save_directory(bool);
         fn handle_relay_id(String);
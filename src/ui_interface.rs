@@ -959,6 +959,14 @@ pub fn has_vram() -> bool {
     cfg!(feature = "vram")
 }
 
+#[inline]
+pub fn is_nvenc_available() -> bool {
+    #[cfg(feature = "hwcodec")]
+    return scrap::hwcodec::is_nvenc_available();
+    #[allow(unreachable_code)]
+    false
+}
+
 #[cfg(feature = "flutter")]
 #[inline]
 pub fn supported_hwdecodings() -> (bool, bool) {
@@ -1,5 +1,5 @@
 use crate::{
-    common::{get_supported_keyboard_modes, is_keyboard_mode_supported},
+    common::{find_display_by_name, get_supported_keyboard_modes, is_keyboard_mode_supported},
     input::{MOUSE_BUTTON_LEFT, MOUSE_TYPE_DOWN, MOUSE_TYPE_UP, MOUSE_TYPE_WHEEL},
     ui_interface::use_texture_render,
 };
@@ -19,7 +19,7 @@ use uuid::Uuid;
 #[cfg(not(feature = "flutter"))]
 use hbb_common::fs;
 use hbb_common::{
-    allow_err,
+    allow_err, bail,
     config::{Config, LocalConfig, PeerConfig},
     get_version_number, log,
     message_proto::*,
@@ -29,7 +29,7 @@ use hbb_common::{
         sync::mpsc,
         time::{Duration as TokioDuration, Instant},
     },
-    Stream,
+    ResultType, Stream,
 };
 
 use crate::client::io_loop::Remote;
@@ -746,6 +746,21 @@ impl<T: InvokeUiSession> Session<T> {
         }
     }
 
+    /// Switch to the peer display by name, see `find_display_by_name()`.
+    pub fn switch_display_by_name(&self, name: &str) -> ResultType<()> {
+        let names = match self.lc.read().unwrap().peer_info.as_ref() {
+            Some(pi) => pi
+                .displays
+                .iter()
+                .map(|d| d.name.clone())
+                .collect::<Vec<_>>(),
+            None => bail!("No peer info"),
+        };
+        let display = find_display_by_name(&names, name)?;
+        self.switch_display(display as _);
+        Ok(())
+    }
+
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     pub fn enter(&self, keyboard_mode: String) {
         keyboard::client::change_grab_status(GrabState::Run, &keyboard_mode);
@@ -41,6 +41,25 @@ pub fn plug_in_headless() -> ResultType<()> {
     }
 }
 
+/// Plug out the virtual display plugged in by `plug_in_headless()`.
+/// Returns false if there's no such display.
+///
+/// The `amyuni` idd virtual display is not plugged out, we can't get full control of it.
+pub fn plug_out_headless() -> bool {
+    match IDD_IMPL {
+        IDD_IMPL_RUSTDESK => rustdesk_idd::plug_out_headless(),
+        _ => false,
+    }
+}
+
+pub fn is_virtual_display(name: &str) -> bool {
+    match IDD_IMPL {
+        IDD_IMPL_RUSTDESK => rustdesk_idd::is_virtual_display(name),
+        IDD_IMPL_AMYUNI => amyuni_idd::is_my_display(name),
+        _ => false,
+    }
+}
+
 pub fn get_platform_additions() -> serde_json::Map<String, serde_json::Value> {
     let mut map = serde_json::Map::new();
     if !crate::platform::windows::is_self_service_running() {
@@ -77,6 +96,38 @@ pub fn plug_in_monitor(idx: u32, modes: Vec<virtual_display::MonitorMode>) -> Re
     }
 }
 
+/// Plug in a virtual display with the requested mode instead of the default 1920x1080@60,
+/// and wait until the display is actually at that resolution.
+/// The display is plugged out if it doesn't get there within 5 seconds.
+pub fn negotiate_virtual_display_resolution(
+    width: u32,
+    height: u32,
+    refresh_hz: u32,
+) -> ResultType<()> {
+    match IDD_IMPL {
+        IDD_IMPL_RUSTDESK => {
+            rustdesk_idd::plug_in_with_resolution(width, height, refresh_hz).map(|_| ())
+        }
+        _ => bail!(
+            "Virtual display resolution is not supported by {}.",
+            IDD_IMPL
+        ),
+    }
+}
+
+/// Change the resolution of the virtual display without plugging it out and in again,
+/// and wait until the display is actually at that resolution, at most 2 seconds.
+/// There must be only one virtual display plugged in by us.
+pub fn resize_monitor(width: u32, height: u32, refresh_hz: u32) -> ResultType<()> {
+    match IDD_IMPL {
+        IDD_IMPL_RUSTDESK => rustdesk_idd::resize_monitor(width, height, refresh_hz),
+        _ => bail!(
+            "Virtual display resolution is not supported by {}.",
+            IDD_IMPL
+        ),
+    }
+}
+
 pub fn plug_out_monitor(index: i32, force_all: bool, force_one: bool) -> ResultType<()> {
     match IDD_IMPL {
         IDD_IMPL_RUSTDESK => {
@@ -323,6 +374,87 @@ pub mod rustdesk_idd {
         Ok(indices)
     }
 
+    pub fn plug_in_with_resolution(width: u32, height: u32, refresh_hz: u32) -> ResultType<u32> {
+        let mode = virtual_display::MonitorMode {
+            width,
+            height,
+            sync: refresh_hz,
+        };
+        let Some(idx) = plug_in_peer_request(vec![vec![mode]])?.first().copied() else {
+            bail!("No virtual display is available");
+        };
+        let device_name = VIRTUAL_DISPLAY_MANAGER
+            .lock()
+            .unwrap()
+            .peer_index_name
+            .get(&idx)
+            .cloned()
+            .unwrap_or_default();
+        if wait_for_mode(idx, &device_name, mode, 50) {
+            return Ok(idx);
+        }
+        allow_err!(plug_out_peer_request(&[idx]));
+        bail!("Virtual display {idx} did not get to {width}x{height}@{refresh_hz} in 5 seconds");
+    }
+
+    pub fn resize_monitor(width: u32, height: u32, refresh_hz: u32) -> ResultType<()> {
+        let (idx, device_name) = {
+            let lock = VIRTUAL_DISPLAY_MANAGER.lock().unwrap();
+            let mut displays: Vec<_> = lock
+                .headless_index_name
+                .iter()
+                .cloned()
+                .chain(lock.peer_index_name.iter().map(|(k, v)| (*k, v.clone())))
+                .collect();
+            if displays.len() != 1 {
+                bail!("Expected one virtual display, found {}", displays.len());
+            }
+            displays.remove(0)
+        };
+        let mode = virtual_display::MonitorMode {
+            width,
+            height,
+            sync: refresh_hz,
+        };
+        if let Err(e) = virtual_display::update_monitor_modes(idx, &[mode]) {
+            bail!("Update monitor {} modes {:?} failed: {}", idx, mode, e);
+        }
+        if !wait_for_mode(idx, &device_name, mode, 20) {
+            bail!(
+                "Virtual display {idx} did not get to {width}x{height}@{refresh_hz} in 2 seconds"
+            );
+        }
+        Ok(())
+    }
+
+    // Wait until the display is at the mode, checked every 100 ms for `checks` times.
+    // The driver may take a while to apply the mode, set it again every second.
+    fn wait_for_mode(
+        idx: u32,
+        device_name: &str,
+        mode: virtual_display::MonitorMode,
+        checks: usize,
+    ) -> bool {
+        for i in 0..checks {
+            if let Ok(r) = crate::platform::windows::current_resolution(device_name) {
+                if r.width == mode.width as i32 && r.height == mode.height as i32 {
+                    log::info!(
+                        "virtual display {idx} is at {}x{}@{}",
+                        mode.width,
+                        mode.height,
+                        mode.sync
+                    );
+                    return true;
+                }
+            }
+            if i % 10 == 9 {
+                allow_err!(virtual_display::update_monitor_modes(idx, &[mode]));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        false
+    }
+
     pub fn plug_out_peer_request(indices: &[u32]) -> ResultType<()> {
         let mut manager = VIRTUAL_DISPLAY_MANAGER.lock().unwrap();
         for idx in indices.iter() {